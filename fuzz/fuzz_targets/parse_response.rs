@@ -0,0 +1,20 @@
+// Arbitrary bytes into the response header parser. Whatever comes back has to be headers or a
+// Parse error, any other error or a panic is a bug. Run with
+// cargo +nightly fuzz run parse_response
+#![no_main]
+
+use std::panic;
+
+use glitchy_http::error::DownloadError;
+use glitchy_http::headers::parse_response_headers;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // libFuzzer would stop at a panic anyway, catching it first means the input gets printed
+    // with what went wrong
+    match panic::catch_unwind(|| parse_response_headers(data)) {
+        Ok(Ok(_) | Err(DownloadError::Parse(_))) => {}
+        Ok(Err(e)) => panic!("Expected headers or a Parse error, got {e:?} for {data:?}"),
+        Err(_) => panic!("parse_response_headers panicked on {data:?}"),
+    }
+});
@@ -0,0 +1,503 @@
+// The in-memory download for callers that are already running tokio, ie a web service fetching
+// a file for a request without tying up a thread per connection. Chunking, the retry decisions,
+// backoff and stats all come from client.rs, only the socket and the sleeping are async here.
+// Plain HTTP only so far, use_tls gets a Tls error
+use std::{
+    borrow::Cow,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{self, TcpSocket, TcpStream},
+    time,
+};
+
+use crate::client::{
+    body_truncated, check_cancelled, check_config, check_head, check_status, chunk_end,
+    config_hasher, config_manifest, count_attempt, decompress_body, discovery_outcome, empty_stats,
+    head_complete, judge_attempt, range_request, retries_exhausted, shared_attempts,
+    throttle_delay, Attempt, ChunkShared, Response,
+};
+use crate::codec::{chunked_truncated, parse_size_line, MAX_LINE_SIZE};
+use crate::config::DownloadConfig;
+use crate::connection::{
+    bind_failed, connect_failed, filter_family, log_fallback, order_candidates, warn_if_capped,
+    KeepAlive,
+};
+use crate::error::DownloadError;
+use crate::headers::{parse_response_headers, ResponseHeaders};
+use crate::progress::{default_observer, report_fd, ProgressObserver};
+use crate::redirect::RedirectTracker;
+use crate::retry::retry_delay;
+use crate::stats::DownloadStats;
+
+// download_full_data but async, progress gets printed to stdout the same way unless config.quiet
+#[inline]
+pub async fn download_full_data_async(
+    total_size: u64,
+    config: &DownloadConfig,
+) -> Result<(Vec<u8>, DownloadStats), DownloadError> {
+    download_full_data_async_with_progress(
+        total_size,
+        config,
+        default_observer(config)
+            .as_mut()
+            .map(|observer| observer as _),
+    )
+    .await
+}
+
+// download_full_data_with_progress but async. The observer has to be Send so the future is too,
+// otherwise it couldn't go on tokio::spawn
+pub async fn download_full_data_async_with_progress(
+    total_size: u64,
+    config: &DownloadConfig,
+    mut progress: Option<&mut (dyn ProgressObserver + Send)>,
+) -> Result<(Vec<u8>, DownloadStats), DownloadError> {
+    let started = Instant::now();
+    if config.use_tls {
+        return Err(DownloadError::Tls(
+            "The async client only speaks plain HTTP so far".into(),
+        ));
+    }
+    if config.socks5_proxy.is_some() {
+        return Err(DownloadError::Args(
+            "The async client can't go through a SOCKS5 proxy".into(),
+        ));
+    }
+    #[cfg(feature = "quic")]
+    if config.transport == crate::config::Transport::Quic {
+        return Err(DownloadError::Args(
+            "The async client only speaks TCP".into(),
+        ));
+    }
+    check_config(config)?;
+    let config = &*resolve_redirects(config).await?;
+    let manifest = config_manifest(config)?;
+    let attempts = AtomicU32::new(0);
+    let received = AtomicU64::new(0);
+    let headers_pending = AtomicBool::new(true);
+    let chunks = ChunkShared {
+        manifest: manifest.as_ref(),
+        attempts: shared_attempts(config, &attempts),
+        received: &received,
+        headers_pending: &headers_pending,
+    };
+    if total_size == 0 {
+        return Ok((Vec::new(), empty_stats(config)));
+    }
+
+    if let Some(progress) = progress.as_deref_mut() {
+        progress.on_status(&format!("Attempting to download {total_size} bytes..."));
+        progress.on_status(&format!(
+            "Starting download in chunks of up to {} bytes...",
+            config.chunk_size
+        ));
+    }
+    let mut full_data = vec![0u8; total_size.try_into().map_err(DownloadError::from)?];
+    let mut hasher = config_hasher(config);
+    let mut conn = AsyncConnection::default();
+    let mut stats = DownloadStats::default();
+
+    let mut current_pos = 0;
+    while current_pos < total_size {
+        check_cancelled(config)?;
+        let chunk_end = chunk_end(current_pos, total_size, config.chunk_size);
+        let chunk_started = Instant::now();
+        let chunk_data = cancellable(
+            config,
+            download_chunk(
+                &mut conn,
+                current_pos,
+                chunk_end,
+                config,
+                &chunks,
+                &mut stats,
+                &mut progress,
+            ),
+        )
+        .await?;
+        time::sleep(throttle_delay(
+            chunk_data.len() as u64,
+            chunk_started.elapsed(),
+            config,
+        ))
+        .await;
+
+        hasher.update(&chunk_data);
+        let start_idx: usize = current_pos.try_into().map_err(DownloadError::from)?;
+        full_data[start_idx..start_idx + chunk_data.len()].copy_from_slice(&chunk_data);
+        current_pos += chunk_data.len() as u64;
+
+        if let Some(progress) = progress.as_deref_mut() {
+            progress.on_progress(current_pos, total_size);
+        }
+        report_fd(config, current_pos, total_size);
+    }
+
+    stats.finish_hash(hasher);
+    stats.total_duration = started.elapsed();
+    Ok((full_data, stats))
+}
+
+// Runs fut until config.async_cancel_token goes off, then drops it and whatever request it was in
+// the middle of. One that's already cancelled means fut never starts
+async fn cancellable<T>(
+    config: &DownloadConfig,
+    fut: impl Future<Output = Result<T, DownloadError>>,
+) -> Result<T, DownloadError> {
+    let Some(token) = &config.async_cancel_token else {
+        return fut.await;
+    };
+    tokio::select! {
+        biased;
+        () = token.cancelled() => Err(DownloadError::Cancelled),
+        result = fut => result,
+    }
+}
+
+// client::download_chunk with an async send and sleep in between the same judge_attempt calls
+async fn download_chunk(
+    conn: &mut AsyncConnection,
+    start: u64,
+    end: u64,
+    config: &DownloadConfig,
+    chunks: &ChunkShared<'_>,
+    stats: &mut DownloadStats,
+    progress: &mut Option<&mut (dyn ProgressObserver + Send)>,
+) -> Result<Vec<u8>, DownloadError> {
+    let started = Instant::now();
+    stats.chunks_attempted += 1;
+    let max_retries = config.max_retries;
+    for attempt in 1..=max_retries {
+        // Same +1 for the python server as the sync client
+        count_attempt(chunks.attempts, config)?;
+        let response = send_request(conn, start, end.saturating_add(1), config).await;
+        let failure = match judge_attempt(start, end, attempt, config, chunks, response, stats) {
+            Attempt::Done(body) => {
+                stats.record_chunk(started.elapsed());
+                return Ok(body);
+            }
+            Attempt::GiveUp(e) => return Err(e),
+            Attempt::Retry(e) => e,
+        };
+
+        if attempt == max_retries {
+            return Err(retries_exhausted(start, end, attempt, failure));
+        }
+        stats.total_retries += 1;
+        if let Some(progress) = progress.as_deref_mut() {
+            progress.on_retry(&failure);
+        }
+        time::sleep(retry_delay(attempt, &failure, config)).await;
+    }
+    Err(DownloadError::Logic("No attempts allowed".into()))
+}
+
+async fn send_request(
+    conn: &mut AsyncConnection,
+    start: u64,
+    end: u64,
+    config: &DownloadConfig,
+) -> Result<Response, DownloadError> {
+    let request = range_request(start, end, config);
+
+    loop {
+        // A reused keep-alive connection the server already closed gets one more go on a fresh
+        // connection, same as the sync client
+        let reused = conn.is_open();
+        let result = match conn.stream(config).await {
+            Ok(stream) => exchange(stream, &request, config).await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(response) => {
+                if !config.keep_alive || !response.keep_alive {
+                    conn.close();
+                } else {
+                    conn.finished_request(config);
+                }
+                check_status(&response.headers, &response.body)?;
+                return decompress_body(response, config);
+            }
+            Err(DownloadError::Io(_) | DownloadError::Timeout { .. }) if reused => conn.close(),
+            Err(e) => {
+                conn.close();
+                return Err(e);
+            }
+        }
+    }
+}
+
+// client::resolve_redirects without blocking the runtime
+async fn resolve_redirects(
+    config: &DownloadConfig,
+) -> Result<Cow<'_, DownloadConfig>, DownloadError> {
+    if !config.follow_redirects {
+        return Ok(Cow::Borrowed(config));
+    }
+    let mut resolved = config.clone();
+    let mut tracker = RedirectTracker::new(&resolved);
+    while let Some(headers) = discovery_outcome(first_byte_head(&resolved).await)? {
+        if !tracker.follow(&mut resolved, &headers)? {
+            break;
+        }
+    }
+    resolved.follow_redirects = false;
+    Ok(Cow::Owned(resolved))
+}
+
+// Asks for the first byte on a fresh connection and reads just the head of the response
+async fn first_byte_head(config: &DownloadConfig) -> Result<ResponseHeaders, DownloadError> {
+    let mut conn = AsyncConnection::default();
+    let stream = conn.stream(config).await?;
+    within(
+        config.connect_timeout,
+        stream
+            .get_mut()
+            .write_all(range_request(0, 0, config).as_bytes()),
+    )
+    .await?;
+    read_head(stream, config.read_timeout).await
+}
+
+// Writes the request and reads one response back, the write timeout follows connect_timeout like
+// the sync client's socket does
+async fn exchange(
+    stream: &mut BufReader<TcpStream>,
+    request: &str,
+    config: &DownloadConfig,
+) -> Result<Response, DownloadError> {
+    within(
+        config.connect_timeout,
+        stream.get_mut().write_all(request.as_bytes()),
+    )
+    .await?;
+    read_response(stream, config.read_timeout).await
+}
+
+// client::read_response over an async reader. Every read gets read_timeout to itself, which is
+// what the socket timeout gives the sync client
+async fn read_response(
+    reader: &mut BufReader<TcpStream>,
+    read_timeout: Duration,
+) -> Result<Response, DownloadError> {
+    let headers = read_head(reader, read_timeout).await?;
+    let mut keep_alive = headers.keep_alive();
+
+    if headers.is_chunked() {
+        let body = read_chunked_body(reader, read_timeout).await?;
+        return Ok(Response {
+            headers,
+            body,
+            keep_alive,
+            bytes_per_sec: None,
+        });
+    }
+    let mut body = Vec::new();
+    let mut buf = [0u8; 16 * 1024];
+    let mut limited = reader.take(headers.content_length.unwrap_or(u64::MAX));
+    loop {
+        let read = within(read_timeout, limited.read(&mut buf)).await?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..read]);
+    }
+
+    match headers.content_length {
+        Some(len) if (body.len() as u64) < len => return Err(body_truncated(body.len(), len)),
+        Some(_) => {}
+        None => keep_alive = false,
+    }
+    Ok(Response {
+        headers,
+        body,
+        keep_alive,
+        bytes_per_sec: None,
+    })
+}
+
+// codec::read_chunked_body over an async reader
+async fn read_chunked_body(
+    reader: &mut BufReader<TcpStream>,
+    read_timeout: Duration,
+) -> Result<Vec<u8>, DownloadError> {
+    let mut body = Vec::new();
+    loop {
+        let size = parse_size_line(&read_line(reader, read_timeout).await?)?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + usize::try_from(size)?, 0);
+        match within(read_timeout, reader.read_exact(&mut body[start..])).await {
+            Err(DownloadError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(chunked_truncated())
+            }
+            result => result?,
+        };
+        if !read_line(reader, read_timeout).await?.is_empty() {
+            return Err(DownloadError::Parse(
+                "Chunk data is longer than its size line says".into(),
+            ));
+        }
+    }
+    while !read_line(reader, read_timeout).await?.is_empty() {}
+    Ok(body)
+}
+
+async fn read_line(
+    reader: &mut BufReader<TcpStream>,
+    read_timeout: Duration,
+) -> Result<Vec<u8>, DownloadError> {
+    let mut line = Vec::new();
+    let mut limited = reader.take(MAX_LINE_SIZE);
+    within(read_timeout, limited.read_until(b'\n', &mut line)).await?;
+    if !line.ends_with(b"\n") {
+        return Err(if line.len() as u64 == MAX_LINE_SIZE {
+            DownloadError::Parse(
+                format!("Chunked body line is longer than {MAX_LINE_SIZE} bytes").into(),
+            )
+        } else {
+            chunked_truncated()
+        });
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(line)
+}
+
+async fn read_head(
+    reader: &mut BufReader<TcpStream>,
+    read_timeout: Duration,
+) -> Result<ResponseHeaders, DownloadError> {
+    let mut head = Vec::new();
+    while !head_complete(&head) {
+        let read = within(read_timeout, reader.read_until(b'\n', &mut head)).await?;
+        check_head(read, &head)?;
+    }
+    parse_response_headers(&head)
+}
+
+// An IO operation that has to finish within limit, otherwise it's a Timeout
+async fn within<T>(
+    limit: Duration,
+    op: impl Future<Output = io::Result<T>>,
+) -> Result<T, DownloadError> {
+    match time::timeout(limit, op).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(DownloadError::Timeout { timeout: limit }),
+    }
+}
+
+// connection::Connection but over a tokio TcpStream
+#[derive(Debug, Default)]
+struct AsyncConnection {
+    stream: Option<BufReader<TcpStream>>,
+    last_addr: Option<SocketAddr>,
+    keep_alive: KeepAlive,
+}
+
+impl AsyncConnection {
+    fn is_open(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    async fn stream(
+        &mut self,
+        config: &DownloadConfig,
+    ) -> Result<&mut BufReader<TcpStream>, DownloadError> {
+        if self.stream.is_some() && self.keep_alive.idle_too_long(config) {
+            self.close();
+        }
+        if self.stream.is_none() {
+            let (stream, addr) = connect(config, self.last_addr).await?;
+            self.stream = Some(BufReader::new(stream));
+            self.last_addr = Some(addr);
+        }
+        Ok(self.stream.as_mut().expect("stream was just opened"))
+    }
+
+    fn finished_request(&mut self, config: &DownloadConfig) {
+        if self.keep_alive.finished_request(config) {
+            self.close();
+        }
+    }
+
+    fn close(&mut self) {
+        self.stream = None;
+        self.keep_alive = KeepAlive::default();
+    }
+}
+
+// connection::open_socket, tokio's TcpSocket can set the buffer sizes and bind itself
+fn open_socket(addr: SocketAddr, config: &DownloadConfig) -> io::Result<TcpSocket> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    let size = |size: usize| u32::try_from(size).unwrap_or(u32::MAX);
+    if let Some(requested) = config.tcp_recv_buf {
+        socket.set_recv_buffer_size(size(requested))?;
+        warn_if_capped("receive", requested, socket.recv_buffer_size()? as usize);
+    }
+    if let Some(requested) = config.tcp_send_buf {
+        socket.set_send_buffer_size(size(requested))?;
+        warn_if_capped("send", requested, socket.send_buffer_size()? as usize);
+    }
+    if let Some(bind) = config.bind_addr {
+        #[cfg(not(target_os = "windows"))]
+        socket.set_reuseaddr(true)?;
+        socket.bind(bind).map_err(|e| bind_failed(bind, e))?;
+    }
+    Ok(socket)
+}
+
+// connection::connect_any with each address getting connect_timeout, in the same order
+async fn connect(
+    config: &DownloadConfig,
+    preferred: Option<SocketAddr>,
+) -> Result<(TcpStream, SocketAddr), DownloadError> {
+    let mut candidates: Vec<SocketAddr> = match config.proxy {
+        Some(proxy) => vec![proxy],
+        None => net::lookup_host(&config.server_addr)
+            .await
+            .map(Iterator::collect)
+            .unwrap_or_default(),
+    };
+    if candidates.is_empty() {
+        return Err(DownloadError::Network(
+            format!("Could not resolve {}", config.server_addr).into(),
+        ));
+    }
+    filter_family(&mut candidates, config)?;
+    order_candidates(&mut candidates, preferred);
+
+    let mut failures = Vec::new();
+    for addr in candidates {
+        let socket = open_socket(addr, config)?;
+        match time::timeout(config.connect_timeout, socket.connect(addr)).await {
+            Ok(Ok(stream)) => {
+                log_fallback(addr, failures.len());
+                // Same tradeoff as the sync client's, see connection::connect
+                if config.tcp_nodelay {
+                    stream.set_nodelay(true)?;
+                }
+                return Ok((stream, addr));
+            }
+            Ok(Err(e)) => failures.push((addr, e)),
+            Err(_) => failures.push((addr, io::Error::from(io::ErrorKind::TimedOut))),
+        }
+    }
+    Err(connect_failed(config, failures))
+}
@@ -0,0 +1,179 @@
+// Which byte ranges of an output file are known to have been written, so a resume picks up from
+// what actually made it to disk rather than however long the file happens to be. Saved next to
+// the file as JSON, see DownloadConfig::chunk_map
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkMap {
+    pub total_size: u64,
+    // Inclusive ranges, sorted, with no two overlapping or touching
+    pub written: Vec<(u64, u64)>,
+}
+
+impl ChunkMap {
+    #[must_use]
+    pub fn new(total_size: u64) -> Self {
+        Self {
+            total_size,
+            written: Vec::new(),
+        }
+    }
+
+    // start..=end has been written. Merged with any range it overlaps or touches, and cut off at
+    // total_size. A start past its end is an empty range and changes nothing
+    pub fn mark_written(&mut self, start: u64, end: u64) {
+        let end = end.min(self.total_size.saturating_sub(1));
+        if start > end || start >= self.total_size {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        // Everything that overlaps or touches start..=end gets folded into it
+        self.written.retain(|&(s, e)| {
+            let joins = s <= end.saturating_add(1) && start <= e.saturating_add(1);
+            if joins {
+                start = start.min(s);
+                end = end.max(e);
+            }
+            !joins
+        });
+        let at = self.written.partition_point(|&(s, _)| s < start);
+        self.written.insert(at, (start, end));
+    }
+
+    // Every inclusive range of [0, total_size) that isn't written yet, in order
+    #[must_use]
+    pub fn missing_ranges(&self) -> Vec<(u64, u64)> {
+        let mut missing = Vec::new();
+        let mut pos = 0;
+        for &(start, end) in &self.written {
+            if start > pos {
+                missing.push((pos, start - 1));
+            }
+            pos = end + 1;
+        }
+        if pos < self.total_size {
+            missing.push((pos, self.total_size - 1));
+        }
+        missing
+    }
+
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.missing_ranges().is_empty()
+    }
+
+    // Bytes from the start that are all written, where a sequential download carries on from
+    #[must_use]
+    pub fn written_prefix(&self) -> u64 {
+        match self.written.first() {
+            Some(&(0, end)) => end + 1,
+            _ => 0,
+        }
+    }
+
+    // Written to a temporary file next to path and renamed over it, so a crash partway leaves
+    // either the old map or the new one and never half of one
+    pub fn save_to_file(&self, path: &Path) -> Result<(), io::Error> {
+        let tmp = with_suffix(path, ".tmp");
+        let mut file = fs::File::create(&tmp)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    // A map that doesn't parse or has ranges out of order is InvalidData
+    pub fn load_from_file(path: &Path) -> Result<Self, io::Error> {
+        let map: Self = serde_json::from_slice(&fs::read(path)?)?;
+        let sorted = map.written.windows(2).all(|pair| pair[0].1 < pair[1].0);
+        let in_range = map
+            .written
+            .iter()
+            .all(|&(start, end)| start <= end && end < map.total_size);
+        if !sorted || !in_range {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Chunk map {} has bad ranges in it", path.display()),
+            ));
+        }
+        Ok(map)
+    }
+}
+
+// Where the map for the output file at path goes, <path>.chunkmap
+#[must_use]
+pub fn chunk_map_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".chunkmap")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_merge_as_they_come_in() {
+        let mut map = ChunkMap::new(100);
+        assert_eq!(map.missing_ranges(), vec![(0, 99)]);
+        map.mark_written(10, 19);
+        map.mark_written(40, 49);
+        assert_eq!(map.written, vec![(10, 19), (40, 49)]);
+        assert_eq!(map.missing_ranges(), vec![(0, 9), (20, 39), (50, 99)]);
+        assert_eq!(map.written_prefix(), 0);
+
+        // Touching and overlapping both join up
+        map.mark_written(20, 29);
+        map.mark_written(25, 42);
+        assert_eq!(map.written, vec![(10, 49)]);
+        map.mark_written(0, 9);
+        assert_eq!(map.written_prefix(), 50);
+
+        // Backwards does nothing, past the end gets cut off
+        map.mark_written(70, 60);
+        map.mark_written(50, 500);
+        assert_eq!(map.written, vec![(0, 99)]);
+        assert!(map.is_complete());
+        assert!(ChunkMap::new(0).is_complete());
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let path = std::env::temp_dir().join(format!(
+            "glitchy-http-chunkmap-{}.chunkmap",
+            std::process::id()
+        ));
+        let mut map = ChunkMap::new(1000);
+        map.mark_written(0, 99);
+        map.mark_written(500, 599);
+        map.save_to_file(&path).unwrap();
+        assert!(!with_suffix(&path, ".tmp").exists());
+        assert_eq!(ChunkMap::load_from_file(&path).unwrap(), map);
+
+        fs::write(&path, r#"{"total_size":10,"written":[[5,6],[0,1]]}"#).unwrap();
+        let unsorted = ChunkMap::load_from_file(&path);
+        fs::write(&path, "not json").unwrap();
+        let garbled = ChunkMap::load_from_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(unsorted.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(garbled.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn map_goes_next_to_the_file() {
+        assert_eq!(
+            chunk_map_path(Path::new("out/data.bin")),
+            Path::new("out/data.bin.chunkmap")
+        );
+    }
+}
@@ -1,171 +1,3127 @@
 use std::{
-    io::{self, BufReader, Read, Write},
-    net::{SocketAddr, TcpStream},
-    str, thread,
-    time::Duration,
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::error::DownloadError;
-const CHUNK_SIZE: u64 = 32 * 1024; // 32 KiB chunk size to not truncate
-const MAX_RETRIES: u32 = 10; // Max retries per chunk
-const RETRY_DELAY: Duration = Duration::from_millis(500);
-const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
-const READ_TIMEOUT: Duration = Duration::from_secs(10);
-const SERVER_ADDR: &str = "127.0.0.1:8080";
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::{debug, info, warn};
 
-// For now keep this function signature if we ever need to give Errors
+use crate::chunk_map::{chunk_map_path, ChunkMap};
+use crate::codec::{byteranges_boundary, decode_content, parse_byteranges, read_chunked_body};
+use crate::config::{validate_header_name, validate_path, validate_user_agent, DownloadConfig};
+use crate::connection::Connection;
+use crate::debug::header_dump;
+use crate::error::{in_file, DownloadError, MAX_ERROR_BODY};
+use crate::etag::{if_none_match, load_cached, store_cached, CachedCopy};
+use crate::headers::{parse_response_headers, parse_retry_after, ContentRange, ResponseHeaders};
+use crate::manifest::{load_manifest, verify_chunk, ChunkManifest};
+use crate::progress::{default_observer, report_fd, ProgressObserver};
+use crate::redirect::RedirectTracker;
+use crate::request::HttpRequest;
+use crate::retry::retry_delay;
+use crate::sha::{DownloadWriter, IncrementalHasher};
+use crate::stats::{DownloadStats, RateLimitedReader, ThrottledReader};
+
+// Holds the whole file in memory, prefer download_to_file for anything big.
+// Returns the data along with stats on how it went. The hash (config.hash_algorithm) in there is
+// worked out as the chunks arrive so there's no second pass over the buffer. Progress gets
+// printed to stdout unless config.quiet
 #[inline]
-pub fn download_full_data(total_size: u64) -> Result<Vec<u8>, DownloadError> {
+pub fn download_full_data(
+    total_size: u64,
+    config: &DownloadConfig,
+) -> Result<(Vec<u8>, DownloadStats), DownloadError> {
+    download_full_data_with_progress(
+        total_size,
+        config,
+        default_observer(config)
+            .as_mut()
+            .map(|observer| observer as _),
+    )
+}
+
+// download_full_data but progress goes to the observer instead. None means no output at all
+// other than retry warnings through log
+pub fn download_full_data_with_progress(
+    total_size: u64,
+    config: &DownloadConfig,
+    mut progress: Option<&mut dyn ProgressObserver>,
+) -> Result<(Vec<u8>, DownloadStats), DownloadError> {
+    let started = Instant::now();
     if total_size == 0 {
-        return Ok(Vec::new());
+        return Ok((Vec::new(), empty_stats(config)));
     }
 
-    println!("Attempting to download {total_size} bytes...");
-    // Create buffer of the correct size for efficiency
-    let mut full_data = vec![0u8; total_size as usize];
-    let mut current_pos: u64 = 0;
+    // With etag_cache_dir the first request just asks whether the cached copy is still good,
+    // redirects get followed first so it's asking about the same URL the download would use
+    let resolved;
+    let mut new_etag = None;
+    let config = match &config.etag_cache_dir {
+        Some(dir) => {
+            check_config(config)?;
+            resolved = resolve_redirects(config)?;
+            match revalidate(dir, total_size, &resolved)? {
+                CacheCheck::Unchanged(cached) => {
+                    info!("Using cached copy (ETag match)");
+                    if let Some(progress) = progress.as_deref_mut() {
+                        progress.on_status("Using cached copy (ETag match)");
+                    }
+                    let mut hasher = config_hasher(&resolved);
+                    hasher.update(&cached.data);
+                    let stats = DownloadStats {
+                        total_duration: started.elapsed(),
+                        ..DownloadStats::hashed(hasher)
+                    };
+                    return Ok((cached.data, stats));
+                }
+                CacheCheck::Changed { etag } => new_etag = etag,
+            }
+            &*resolved
+        }
+        None => config,
+    };
 
-    println!("Starting download in chunks of up to {CHUNK_SIZE} bytes...");
+    if let Some(progress) = progress.as_deref_mut() {
+        progress.on_status(&format!("Attempting to download {total_size} bytes..."));
+    }
+    // Create buffer of the correct size for efficiency. On 32 bit a size past 4 GiB doesn't fit in
+    // memory anyway so that's an error rather than a silently truncated buffer
+    let capacity: usize = total_size.try_into().map_err(DownloadError::from)?;
+    // Chunks come in order so each one just goes on the end, getting hashed on the way in
+    let mut out = DownloadWriter::new(Vec::with_capacity(capacity), config_hasher(config));
 
-    // Create a single TCP connection that we'll try to reuse
-    while current_pos < total_size {
-        let chunk_start = current_pos;
-        let mut chunk_end = current_pos.saturating_add(CHUNK_SIZE).saturating_sub(1);
-        if chunk_end >= total_size {
-            chunk_end = total_size.saturating_sub(1);
+    // Only kept for the check at the end, which release builds skip
+    let mut written = Vec::new();
+    let mut stats = download_chunks(
+        0,
+        total_size,
+        config,
+        progress,
+        |chunk_start, chunk_data| {
+            if cfg!(debug_assertions) && !chunk_data.is_empty() {
+                written.push((chunk_start, chunk_start + chunk_data.len() as u64 - 1));
+            }
+            // Defensive programming that the chunk goes where it belongs and not past the end
+            debug_assert_eq!(
+                chunk_start,
+                out.get_ref().len() as u64,
+                "Chunk arrived out of order"
+            );
+            debug_assert!(
+                chunk_start + chunk_data.len() as u64 <= total_size,
+                "Attempting to write chunk beyond buffer bounds"
+            );
+            out.write_all(chunk_data)?;
+            Ok(())
+        },
+    )?;
+    let (full_data, hasher) = out.into_parts();
+    stats.finish_hash(hasher);
+
+    if cfg!(debug_assertions) {
+        verify_buffer_completeness(&full_data, &written, total_size)?;
+    }
+    // The download worked either way, next time just won't be able to skip it
+    if let (Some(dir), Some(etag)) = (&config.etag_cache_dir, new_etag) {
+        if let Err(e) = store_cached(dir, config, &etag, &full_data) {
+            warn!("Couldn't save the download to {}: {e}", dir.display());
         }
-        let chunk_end = chunk_end;
+    }
+    stats.total_duration = started.elapsed();
+    Ok((full_data, stats))
+}
 
-        // A bit of defensive programming here. Catch bugs early.
-        // Could instead make a Logic Variant for DownloadError so clients could give better
-        // diagonistics if things fail but ideally those never happen.
-        debug_assert!(chunk_start <= chunk_end, "Chunk start is after end");
-        let chunk_data = download_chunk(chunk_start, chunk_end)?;
-        let expected_len = (chunk_end - chunk_start + 1) as usize;
+// How many downloaded chunks a ChunkStream holds on to before the download waits for the caller
+const STREAM_AHEAD: usize = 2;
 
-        // This implementation here would need to change if the server was a block_box
-        // that changed its threshold for truncating every time versus being a constant
-        debug_assert!(
-            chunk_data.len() == expected_len,
-            "Downloaded chunk doesn't match the expected size"
-        );
+// Each chunk of the download as it arrives, in order, for feeding into a decompressor or the like
+// without holding the whole file. The download runs on its own thread through the same chunk
+// loop as download_to_file, so retries, pipelining and prefetch all happen in there (concurrency
+// doesn't) and what comes out is only ever the next chunk or the error that ended it. Collecting
+// it gives the same bytes download_full_data would. Dropping it early stops the download after
+// the chunk in flight
+pub fn download_streaming(total_size: u64, config: &DownloadConfig) -> ChunkStream {
+    let (chunks, receiver) = mpsc::sync_channel(STREAM_AHEAD);
+    let config = config.clone();
+    let worker = thread::spawn(move || {
+        let started = Instant::now();
+        // Nowhere to write to, the chunks go down the channel instead. It's still the hash of
+        // exactly what was sent
+        let mut hashed = DownloadWriter::new(io::sink(), config_hasher(&config));
+        let mut stats = download_chunks(0, total_size, &config, None, |_, chunk_data| {
+            hashed.write_all(chunk_data)?;
+            chunks
+                .send(chunk_data.to_vec())
+                .map_err(|_| DownloadError::Cancelled)
+        })?;
+        stats.finish_hash(hashed.into_parts().1);
+        stats.total_duration = started.elapsed();
+        Ok(stats)
+    });
+    ChunkStream {
+        receiver,
+        worker: Some(worker),
+        stats: None,
+    }
+}
 
-        // Copy the downloaded chunk into the correct position in the main buffer
-        let start_idx = chunk_start as usize;
+pub struct ChunkStream {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    // Until it's been joined, after the last chunk or the error
+    worker: Option<thread::JoinHandle<Result<DownloadStats, DownloadError>>>,
+    stats: Option<DownloadStats>,
+}
 
-        // Defensive programming that we're not writing beyond buffer bounds
-        debug_assert!(
-            start_idx + chunk_data.len() <= full_data.len(),
-            "Attempting to write chunk beyond buffer bounds. end_idx={}, buffer_len={}",
-            start_idx + chunk_data.len(),
-            full_data.len()
-        );
+impl ChunkStream {
+    // The whole download's stats, hash included, once every chunk has come out
+    #[must_use]
+    pub fn stats(&self) -> Option<&DownloadStats> {
+        self.stats.as_ref()
+    }
+}
 
-        // Now we know the copy will be valid
-        full_data[start_idx..start_idx + chunk_data.len()].copy_from_slice(&chunk_data);
+impl Iterator for ChunkStream {
+    type Item = Result<Vec<u8>, DownloadError>;
 
-        current_pos += chunk_data.len() as u64;
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Ok(chunk) = self.receiver.recv() {
+            return Some(Ok(chunk));
+        }
+        // The download's over one way or the other
+        let result = self.worker.take()?.join().unwrap_or_else(|_| {
+            Err(DownloadError::Logic(
+                "The streaming download's thread panicked".into(),
+            ))
+        });
+        match result {
+            Ok(stats) => {
+                self.stats = Some(stats);
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
 
-        // Progress indicator
-        let percentage = (current_pos as f64 / total_size as f64) * 100.0;
-        print!(
-            "\rDownloaded: {:.2}% ({}/{}) bytes",
-            percentage, current_pos, total_size
-        );
-        io::stdout().flush()?; // Ensure progress is displayed immediately
+// What the server said about the copy in etag_cache_dir
+enum CacheCheck {
+    Unchanged(CachedCopy),
+    // Or there wasn't a copy to ask about. etag is what the server has for it now, if it sent one
+    Changed { etag: Option<String> },
+}
+
+// Asks for the first byte, with If-None-Match when there's a cached copy of the right size. Only
+// a 304 to that means the copy's still good, anything else (a failure included, the download can
+// deal with that) means downloading it and keeping whatever ETag came back
+fn revalidate(
+    dir: &Path,
+    total_size: u64,
+    config: &DownloadConfig,
+) -> Result<CacheCheck, DownloadError> {
+    let cached = load_cached(dir, config, total_size);
+    let mut conditional = config.clone();
+    if let Some(cached) = &cached {
+        conditional
+            .extra_headers
+            .push(("If-None-Match".into(), if_none_match(&cached.etag)));
+    }
+    let headers = discovery_outcome(request_headers(
+        &range_request(0, 0, &conditional),
+        &conditional,
+    ))?;
+    Ok(match (headers, cached) {
+        (Some(headers), Some(cached)) if headers.status_code == 304 => {
+            CacheCheck::Unchanged(cached)
+        }
+        (headers, _) => CacheCheck::Changed {
+            etag: headers
+                .filter(|headers| matches!(headers.status_code, 200 | 206))
+                .and_then(|headers| headers.get("etag").map(str::to_owned)),
+        },
+    })
+}
+
+// write_all, except a writer that stops taking bytes isn't an error here. Gives back how many
+// went in so verify_write_result can say how far it got
+fn write_counted<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<usize> {
+    let mut written = 0;
+    while written < data.len() {
+        match writer.write(&data[written..]) {
+            Ok(0) => break,
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
     }
+    Ok(written)
+}
 
-    println!("\nDownload complete.");
-    Ok(full_data)
+// After writing a chunk at position in the file, a PartialWrite if fewer than expected_len of
+// its bytes went in. Both ends are counted from the start of the file
+pub fn verify_write_result(
+    expected_len: usize,
+    written_len: usize,
+    position: u64,
+) -> Result<(), DownloadError> {
+    if written_len == expected_len {
+        return Ok(());
+    }
+    Err(DownloadError::PartialWrite {
+        written: position + written_len as u64,
+        expected: position + expected_len as u64,
+    })
 }
 
-// This does some retrying in case downloading fails
-fn download_chunk(start: u64, end: u64) -> Result<Vec<u8>, DownloadError> {
-    let expected_len = (end.saturating_sub(start) + 1) as usize;
-    if expected_len == 0 {
-        // Shouldn't happen but handle defensively
-        return Ok(Vec::new());
-    }
-
-    // Debug printing
-    // println!("Requesting chunk: bytes={}-{} (expecting {} bytes)", start, end, expected_len);
-
-    for attempt in 1..=MAX_RETRIES {
-        // The +1 is because the buggy python server doesn't
-        // actually respect the HTTP Range header
-        // correctly I think, I might be wrong though
-        let request_end = end.saturating_add(1);
-        match send_request(start, request_end) {
-            Ok(body) => {
-                if body.len() == expected_len {
-                    // Debug print
-                    // println!("Successfully received chunk{}-{}", start, end);
-                    return Ok(body);
+// Checks written_ranges (inclusive, in any order) cover every byte of [0, total_size) exactly
+// once and nothing past it, and that buf is long enough to hold them. The first stretch that
+// isn't, going from the start, comes back as a RangeMismatch whether nothing wrote it or more
+// than one range did. A start past its end is an empty range and doesn't count
+pub fn verify_buffer_completeness(
+    buf: &[u8],
+    written_ranges: &[(u64, u64)],
+    total_size: u64,
+) -> Result<(), DownloadError> {
+    let mismatch = |gap_start, gap_end| Err(DownloadError::RangeMismatch { gap_start, gap_end });
+    if (buf.len() as u64) < total_size {
+        return mismatch(buf.len() as u64, total_size - 1);
+    }
+    let mut ranges: Vec<_> = written_ranges
+        .iter()
+        .filter(|(start, end)| start <= end)
+        .collect();
+    ranges.sort_unstable();
+
+    // Everything before pos has been covered once
+    let mut pos = 0;
+    for &&(start, end) in &ranges {
+        if start > pos {
+            return mismatch(pos, start - 1);
+        }
+        if start < pos {
+            return mismatch(start, end.min(pos - 1));
+        }
+        if end >= total_size {
+            return mismatch(start.max(total_size), end);
+        }
+        pos = end + 1;
+    }
+    if pos < total_size {
+        return mismatch(pos, total_size - 1);
+    }
+    Ok(())
+}
+
+// What a zero byte download gives back, nothing to time or count
+pub(crate) fn empty_stats(config: &DownloadConfig) -> DownloadStats {
+    DownloadStats::hashed(config_hasher(config))
+}
+
+// Hashes with config.hash_algorithm, or doesn't at all if compute_hash is off
+pub(crate) fn config_hasher(config: &DownloadConfig) -> IncrementalHasher {
+    if config.compute_hash {
+        IncrementalHasher::new(config.hash_algorithm)
+    } else {
+        IncrementalHasher::off()
+    }
+}
+
+// Same as download_full_data but each chunk goes straight to disk so we only ever hold one chunk
+// in memory. If something fails halfway the file is left with every chunk before the failure
+// written correctly, chunks are sequential so it's always a valid prefix of the real data.
+// That means if the file is already there and smaller than total_size, it's treated as an earlier
+// attempt and the download carries on from where it stopped. Anything bigger than total_size
+// can't be ours so it gets overwritten.
+// The hash in the returned stats covers the whole file, including whatever was already there,
+// while the counters only cover what this call downloaded
+pub fn download_to_file(
+    total_size: u64,
+    path: &Path,
+    config: &DownloadConfig,
+) -> Result<DownloadStats, DownloadError> {
+    download_to_file_with_progress(
+        total_size,
+        path,
+        config,
+        default_observer(config)
+            .as_mut()
+            .map(|observer| observer as _),
+    )
+}
+
+// download_to_file but progress goes to the observer instead, None for silence. When resuming
+// the observer only sees the part that's left ie (0..remaining, remaining)
+pub fn download_to_file_with_progress(
+    total_size: u64,
+    path: &Path,
+    config: &DownloadConfig,
+    mut progress: Option<&mut dyn ProgressObserver>,
+) -> Result<DownloadStats, DownloadError> {
+    let started = Instant::now();
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .append(config.append)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(in_file(path))?;
+    let mut resume_from = file.metadata().map_err(in_file(path))?.len();
+    if config.append && resume_from >= total_size {
+        return Err(DownloadError::Args(format!(
+            "{} is already {resume_from} bytes, there's nothing to append to make it {total_size}",
+            path.display()
+        )));
+    }
+    if resume_from > total_size {
+        file.set_len(0).map_err(in_file(path))?;
+        resume_from = 0;
+    }
+    // With a chunk map the file's length isn't trusted, only what the map says was written (a
+    // crash can leave the length ahead of the data). No map means an earlier run without one, and
+    // then it's the length like always
+    let map_path = chunk_map_path(path);
+    let mut chunk_map = None;
+    if config.chunk_map {
+        if let Some(written) = load_chunk_map(&map_path, total_size).map(|map| map.written_prefix())
+        {
+            if written < resume_from {
+                file.set_len(written).map_err(in_file(path))?;
+                resume_from = written;
+            }
+        }
+        let mut map = ChunkMap::new(total_size);
+        if resume_from > 0 {
+            map.mark_written(0, resume_from - 1);
+        }
+        chunk_map = Some(map);
+    }
+
+    // The returned hash covers the whole file so whatever's already there goes in first
+    let mut hasher = config_hasher(config);
+    if config.compute_hash {
+        io::copy(&mut (&mut file).take(resume_from), &mut hasher).map_err(in_file(path))?;
+    }
+    if resume_from == total_size {
+        remove_chunk_map(&map_path, config)?;
+        return Ok(DownloadStats {
+            total_duration: started.elapsed(),
+            ..DownloadStats::hashed(hasher)
+        });
+    }
+
+    if let Some(progress) = progress.as_deref_mut() {
+        progress.on_status(&if resume_from > 0 {
+            format!(
+                "{} {} from byte {resume_from}, {} of {total_size} bytes left...",
+                if config.append {
+                    "Appending to"
                 } else {
-                    // Received 200/206 but server truncated the body so it doesn't match the
-                    // expeced length
-
-                    eprintln!(
-                            "Warning: Received truncated chunk ({} bytes) for range {}-{} (expected {}). Retrying (attempt {}/{})",
-                            body.len(), start, end, expected_len, attempt, MAX_RETRIES
-                        );
-                    // Fall through to retry delay
-                }
+                    "Resuming"
+                },
+                path.display(),
+                total_size - resume_from
+            )
+        } else {
+            format!(
+                "Attempting to download {total_size} bytes to {}...",
+                path.display()
+            )
+        });
+    }
+    let mut written: u64 = resume_from;
+
+    // Chunks arrive in order so it's one seek up front and then just appending. Seeking a
+    // BufWriter flushes it, so seeking per chunk would throw the buffering away.
+    // Writing a 1 GiB file in 32 KiB chunks straight to the File is ~32k write calls, through the
+    // default 256 KiB buffer it's ~4k, which shows up most on slow disks and network filesystems
+    file.seek(SeekFrom::Start(resume_from))
+        .map_err(in_file(path))?;
+    // And everything that goes in gets hashed on its way to the buffer
+    let mut file = DownloadWriter::new(
+        BufWriter::with_capacity(config.write_buffer_size, file),
+        hasher,
+    );
+    let result = download_chunks(
+        resume_from,
+        total_size,
+        config,
+        progress,
+        |chunk_start, chunk_data| {
+            let end = chunk_start + chunk_data.len() as u64;
+            if config.max_total_bytes.is_some_and(|limit| end > limit) {
+                return Err(DownloadError::Logic(
+                    "Response exceeds expected size".into(),
+                ));
             }
-            Err(e) => {
-                // Handle the network or parsing error
-                eprintln!(
-                    "Error downloading chunk {}-{}: {}. Retrying (attempt {}/{})",
-                    start, end, e, attempt, MAX_RETRIES
-                );
-                // Fall through to retry delay
+            let written_len = write_counted(&mut file, chunk_data).map_err(in_file(path))?;
+            verify_write_result(chunk_data.len(), written_len, chunk_start)?;
+            written = end;
+            // The map can only say what's actually in the file, so the buffer goes first. That
+            // costs the buffering, which is why the map is opt in
+            if let Some(map) = chunk_map.as_mut() {
+                file.flush().map_err(in_file(path))?;
+                map.mark_written(chunk_start, end - 1);
+                map.save_to_file(&map_path).map_err(in_file(&map_path))?;
             }
+            Ok(())
+        },
+    );
+
+    // If a chunk gave up the error should still say how far we got. Dropping the BufWriter on
+    // the way out writes whatever it was holding so a rerun resumes from there
+    let mut stats = result.map_err(|e| DownloadError::PartialDownload {
+        written,
+        total: total_size,
+        cause: Box::new(e),
+    })?;
+    // Whatever is still sitting in the buffer has to hit the file before the stats (and the hash
+    // in them) get handed back, and a failure here is a plain Io error
+    file.flush().map_err(in_file(path))?;
+    stats.finish_hash(file.into_parts().1);
+    remove_chunk_map(&map_path, config)?;
+    stats.total_duration = started.elapsed();
+    Ok(stats)
+}
+
+// The map at map_path if it's there and for a file of total_size. One that can't be read is
+// ignored with a warning, the download falls back to trusting the file's length
+fn load_chunk_map(map_path: &Path, total_size: u64) -> Option<ChunkMap> {
+    match ChunkMap::load_from_file(map_path) {
+        Ok(map) if map.total_size == total_size => Some(map),
+        Ok(map) => {
+            warn!(
+                "Ignoring {}, it's for {} bytes not {total_size}",
+                map_path.display(),
+                map.total_size
+            );
+            None
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("Ignoring {}: {e}", map_path.display());
+            None
         }
+    }
+}
 
-        // Wait for a bit before retrying for this chunk
-        thread::sleep(RETRY_DELAY);
+// A finished file doesn't need its map any more
+fn remove_chunk_map(map_path: &Path, config: &DownloadConfig) -> Result<(), DownloadError> {
+    if !config.chunk_map {
+        return Ok(());
     }
+    match std::fs::remove_file(map_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(in_file(map_path)(e)),
+        _ => Ok(()),
+    }
+}
 
-    // If loop finishes all times then all the retries failed
-    Err(DownloadError::Network(format!(
-        "Failed to download chunk {start}-{end} after {MAX_RETRIES} retries"
-    )))
+// download_to_file but the file is memory mapped at its full size and each chunk is copied
+// straight into the mapping, so nothing the size of the file lives on the heap and the OS only
+// has to find pages for the parts that have arrived. Resumes the same way download_to_file does.
+// If a chunk gives up the mapping is flushed and the file cut back to what was written, so it's
+// still a valid prefix either function can resume from
+#[cfg(feature = "mmap")]
+#[inline]
+pub fn download_to_mmap(
+    total_size: u64,
+    path: &Path,
+    config: &DownloadConfig,
+) -> Result<DownloadStats, DownloadError> {
+    download_to_mmap_with_progress(
+        total_size,
+        path,
+        config,
+        default_observer(config)
+            .as_mut()
+            .map(|observer| observer as _),
+    )
 }
 
-fn send_request(start: u64, end: u64) -> Result<Vec<u8>, DownloadError> {
-    let server_addr: SocketAddr = SERVER_ADDR.parse().expect("SERVER_ADDR is valid");
-    let mut stream = TcpStream::connect_timeout(&server_addr, CONNECT_TIMEOUT)?;
+// download_to_mmap but progress goes to the observer instead, None for silence
+#[cfg(feature = "mmap")]
+pub fn download_to_mmap_with_progress(
+    total_size: u64,
+    path: &Path,
+    config: &DownloadConfig,
+    mut progress: Option<&mut dyn ProgressObserver>,
+) -> Result<DownloadStats, DownloadError> {
+    let started = Instant::now();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(in_file(path))?;
+    let mut resume_from = file.metadata().map_err(in_file(path))?.len();
+    if resume_from > total_size {
+        resume_from = 0;
+    }
+    // Mapping nothing is an error on some platforms, and there's nothing to write anyway
+    if total_size == 0 {
+        file.set_len(0).map_err(in_file(path))?;
+        return Ok(empty_stats(config));
+    }
+    // Anything past usize can't be mapped in one go, ie a >4 GiB file on 32 bit
+    let map_len: usize = total_size.try_into().map_err(DownloadError::from)?;
+    file.set_len(total_size).map_err(in_file(path))?;
+    // SAFETY: the mapping is only sound while nobody else changes the file underneath it. We
+    // just opened it for writing and nothing else in here touches it until the mapping is gone
+    let mut map = unsafe {
+        memmap2::MmapOptions::new()
+            .len(map_len)
+            .map_mut(&file)
+            .map_err(in_file(path))?
+    };
 
-    // Format and send HTTP request
-    let request = format!(
-        "GET / HTTP/1.1\r\n\
-         Host: {SERVER_ADDR}\r\n\
-         Range: bytes={start}-{end}\r\n\
-         Connection: close\r\n\
-         \r\n"
-    );
-    stream.set_read_timeout(Some(READ_TIMEOUT))?;
-    stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+    let mut hasher = config_hasher(config);
+    hasher.update(&map[..resume_from as usize]);
+    if resume_from == total_size {
+        return Ok(DownloadStats {
+            total_duration: started.elapsed(),
+            ..DownloadStats::hashed(hasher)
+        });
+    }
 
-    stream.write_all(request.as_bytes())?;
+    if let Some(progress) = progress.as_deref_mut() {
+        progress.on_status(&format!(
+            "Attempting to download {} of {total_size} bytes into a mapping of {}...",
+            total_size - resume_from,
+            path.display()
+        ));
+    }
+    let mut written = resume_from;
+    // Writing to the slice moves it along, so chunks (which come in order) land one after another
+    let mut out = DownloadWriter::new(&mut map[resume_from as usize..], hasher);
+    let result = download_chunks(
+        resume_from,
+        total_size,
+        config,
+        progress,
+        |chunk_start, chunk_data| {
+            out.write_all(chunk_data).map_err(in_file(path))?;
+            written = chunk_start + chunk_data.len() as u64;
+            Ok(())
+        },
+    );
+    let hasher = out.into_parts().1;
 
-    let mut reader = BufReader::new(stream);
-    let mut response = Vec::new();
-    reader.read_to_end(&mut response)?;
+    // Flushed and unmapped whichever way it went, the file can only be shrunk once the mapping
+    // is gone
+    let flushed = map.flush();
+    drop(map);
+    let mut stats = match result {
+        Ok(mut stats) => {
+            stats.finish_hash(hasher);
+            stats
+        }
+        Err(e) => {
+            file.set_len(written).map_err(in_file(path))?;
+            return Err(DownloadError::PartialDownload {
+                written,
+                total: total_size,
+                cause: Box::new(e),
+            });
+        }
+    };
+    flushed.map_err(in_file(path))?;
+    stats.total_duration = started.elapsed();
+    Ok(stats)
+}
 
-    const DELIMITER: &[u8] = b"\r\n\r\n";
+// Fetches just the ranges in plan into the file at path, from plan's server and path in chunks of
+// plan's chunk_size, the rest of the settings come from config. The file is set to total_size
+// first and every range gets written where it belongs, so the ranges can come in any order or
+// leave gaps. A range that already has a non-zero byte in the file is taken as done by an earlier
+// run and skipped, which means one that got cut off halfway is skipped too.
+// There's no PartialDownload on failure since what's done isn't a prefix, running the plan again
+// picks up whatever's missing. The hash covers the whole file, the counters only what this call
+// downloaded
+pub fn download_plan(
+    plan: &DownloadPlan,
+    path: &Path,
+    config: &DownloadConfig,
+) -> Result<DownloadStats, DownloadError> {
+    download_plan_with_progress(
+        plan,
+        path,
+        config,
+        default_observer(config)
+            .as_mut()
+            .map(|observer| observer as _),
+    )
+}
 
-    // Find the end of headers (double CRLF), body is afterwards from it
-    match response
-        .windows(DELIMITER.len())
-        .position(|w| w == b"\r\n\r\n")
+// download_plan but progress goes to the observer instead, None for silence. It only counts the
+// ranges that weren't skipped
+pub fn download_plan_with_progress(
+    plan: &DownloadPlan,
+    path: &Path,
+    config: &DownloadConfig,
+    mut progress: Option<&mut dyn ProgressObserver>,
+) -> Result<DownloadStats, DownloadError> {
+    let started = Instant::now();
+    let config = DownloadConfig {
+        server_addr: plan.server.clone(),
+        path: plan.path.clone(),
+        chunk_size: plan.chunk_size,
+        ..config.clone()
+    };
+    check_config(&config)?;
+    if let Some((start, end)) = plan
+        .ranges
+        .iter()
+        .find(|&&(start, end)| start > end || end >= plan.total_size)
     {
-        Some(pos) => {
-            let body = pos + DELIMITER.len();
-            Ok(response[body..].to_vec())
+        return Err(DownloadError::Args(format!(
+            "Invalid range {start}-{end} in a plan for {} bytes",
+            plan.total_size
+        )));
+    }
+    let config = &*resolve_redirects(&config)?;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(in_file(path))?;
+    file.set_len(plan.total_size).map_err(in_file(path))?;
+    let mut todo = Vec::with_capacity(plan.ranges.len());
+    for &(start, end) in &plan.ranges {
+        if !range_written(&mut file, start, end).map_err(in_file(path))? {
+            todo.push((start, end));
+        }
+    }
+    let remaining: u64 = todo.iter().map(|(start, end)| end - start + 1).sum();
+    if let Some(progress) = progress.as_deref_mut() {
+        progress.on_status(&format!(
+            "Attempting to download {} of {} planned ranges, {remaining} bytes to {}...",
+            todo.len(),
+            plan.ranges.len(),
+            path.display()
+        ));
+    }
+
+    let manifest = config_manifest(config)?;
+    let attempts = AtomicU32::new(0);
+    let received = AtomicU64::new(0);
+    let headers_pending = AtomicBool::new(true);
+    let chunks = ChunkShared {
+        manifest: manifest.as_ref(),
+        attempts: shared_attempts(config, &attempts),
+        received: &received,
+        headers_pending: &headers_pending,
+    };
+    let mut conn = Connection::new();
+    let mut stats = DownloadStats::default();
+    let mut done = 0;
+    for (start, end) in todo {
+        // A range bigger than a chunk goes in chunks, and one that comes back short carries on
+        // from where it stopped like download_chunks does
+        let mut pos = start;
+        while pos <= end {
+            check_cancelled(config)?;
+            let chunk_end = chunk_end(pos, end + 1, config.chunk_size);
+            let chunk_started = Instant::now();
+            let (chunk_data, bytes_per_sec) = download_chunk(
+                &mut conn,
+                pos,
+                chunk_end,
+                config,
+                &chunks,
+                &mut stats,
+                &mut |e| {
+                    if let Some(progress) = progress.as_deref_mut() {
+                        progress.on_retry(e);
+                    }
+                },
+            )?;
+            if let (Some(progress), Some(speed)) = (progress.as_deref_mut(), bytes_per_sec) {
+                progress.on_throughput(speed);
+            }
+            thread::sleep(throttle_delay(
+                chunk_data.len() as u64,
+                chunk_started.elapsed(),
+                config,
+            ));
+
+            file.seek(SeekFrom::Start(pos)).map_err(in_file(path))?;
+            let written_len = write_counted(&mut file, &chunk_data).map_err(in_file(path))?;
+            verify_write_result(chunk_data.len(), written_len, pos)?;
+            pos += chunk_data.len() as u64;
+            done += chunk_data.len() as u64;
+            if let Some(progress) = progress.as_deref_mut() {
+                progress.on_progress(done, remaining);
+            }
+            report_fd(config, done, remaining);
+        }
+    }
+
+    let mut hasher = config_hasher(config);
+    if config.compute_hash {
+        file.seek(SeekFrom::Start(0)).map_err(in_file(path))?;
+        io::copy(&mut file, &mut hasher).map_err(in_file(path))?;
+    }
+    stats.finish_hash(hasher);
+    stats.total_duration = started.elapsed();
+    Ok(stats)
+}
+
+// Whether anything in start..=end of file isn't zero, ie an earlier download_plan wrote it
+fn range_written(file: &mut File, start: u64, end: u64) -> io::Result<bool> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut range = (&mut *file).take(end - start + 1);
+    let mut buf = [0u8; 8192];
+    loop {
+        match range.read(&mut buf)? {
+            0 => return Ok(false),
+            read if buf[..read].iter().any(|&b| b != 0) => return Ok(true),
+            _ => {}
+        }
+    }
+}
+
+// Splits the file into config.concurrency equal regions and downloads each one on its own thread
+// and connection. Each thread still goes chunk by chunk with the normal retry logic. If any
+// thread gives up the rest stop at their next chunk and the first error is returned.
+// Chunks finish out of order so the hash is worked out once everything is assembled, the stats
+// are every thread's added together
+#[inline]
+pub fn parallel_download_full_data(
+    total_size: u64,
+    config: &DownloadConfig,
+) -> Result<(Vec<u8>, DownloadStats), DownloadError> {
+    parallel_download_full_data_with_progress(
+        total_size,
+        config,
+        default_observer(config)
+            .as_mut()
+            .map(|observer| observer as _),
+    )
+}
+
+// parallel_download_full_data but progress goes to the observer, None for silence. The threads
+// take turns with it so it sees the combined total, and has to be Send to get passed around
+pub fn parallel_download_full_data_with_progress(
+    total_size: u64,
+    config: &DownloadConfig,
+    progress: Option<&mut (dyn ProgressObserver + Send)>,
+) -> Result<(Vec<u8>, DownloadStats), DownloadError> {
+    let started = Instant::now();
+    if config.concurrency == 0 {
+        return Err(DownloadError::Args("Concurrency must be at least 1".into()));
+    }
+    check_config(config)?;
+    if total_size == 0 {
+        return Ok((Vec::new(), empty_stats(config)));
+    }
+    let config = &*resolve_redirects(config)?;
+
+    let mut silent = |_: u64, _: u64| {};
+    let progress: &mut (dyn ProgressObserver + Send) = match progress {
+        Some(progress) => progress,
+        None => &mut silent,
+    };
+    let region_size = total_size.div_ceil(config.concurrency as u64);
+    progress.on_status(&format!(
+        "Attempting to download {total_size} bytes over {} connections...",
+        config.concurrency
+    ));
+
+    let manifest = config_manifest(config)?;
+    let attempts = AtomicU32::new(0);
+    let received = AtomicU64::new(0);
+    let headers_pending = AtomicBool::new(true);
+    let mut full_data = vec![0u8; total_size.try_into().map_err(DownloadError::from)?];
+    let failed = AtomicBool::new(false);
+    let downloaded = AtomicU64::new(0);
+    let progress = Mutex::new(progress);
+
+    let results: Vec<Result<DownloadStats, DownloadError>> = thread::scope(|scope| {
+        let handles: Vec<_> = full_data
+            .chunks_mut(region_size as usize)
+            .enumerate()
+            .map(|(i, region)| {
+                let region_start = i as u64 * region_size;
+                let shared = SharedProgress {
+                    chunks: ChunkShared {
+                        manifest: manifest.as_ref(),
+                        attempts: shared_attempts(config, &attempts),
+                        received: &received,
+                        headers_pending: &headers_pending,
+                    },
+                    failed: &failed,
+                    downloaded: &downloaded,
+                    progress: &progress,
+                    total_size,
+                };
+                scope.spawn(move || {
+                    let result = download_region(region_start, region, config, &shared);
+                    if result.is_err() {
+                        // Tell everyone else to stop
+                        shared.failed.store(true, Ordering::Relaxed);
+                    }
+                    result
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join().unwrap_or_else(|_| {
+                    failed.store(true, Ordering::Relaxed);
+                    Err(DownloadError::Logic("Download thread panicked".into()))
+                })
+            })
+            .collect()
+    });
+
+    // Only the thread that actually failed returns an error, the cancelled ones return Ok
+    let mut stats = DownloadStats::default();
+    for result in results {
+        stats.merge(&result?);
+    }
+
+    let mut hasher = config_hasher(config);
+    hasher.update(&full_data);
+    stats.finish_hash(hasher);
+    stats.total_duration = started.elapsed();
+    Ok((full_data, stats))
+}
+
+// State every parallel download thread shares
+struct SharedProgress<'a, 'p> {
+    chunks: ChunkShared<'a>,
+    // Set by whichever thread fails first, everyone else stops at their next chunk
+    failed: &'a AtomicBool,
+    downloaded: &'a AtomicU64,
+    progress: &'a Mutex<&'p mut (dyn ProgressObserver + Send)>,
+    total_size: u64,
+}
+
+// One thread's worth of parallel_download_full_data. region is the slice of the final buffer
+// starting at region_start. Returns the stats for just this region, without a hash
+fn download_region(
+    region_start: u64,
+    region: &mut [u8],
+    config: &DownloadConfig,
+    shared: &SharedProgress<'_, '_>,
+) -> Result<DownloadStats, DownloadError> {
+    let region_end = region_start + region.len() as u64;
+    let mut conn = Connection::new();
+    let mut stats = DownloadStats::default();
+    let mut current_pos = region_start;
+
+    while current_pos < region_end {
+        if shared.failed.load(Ordering::Relaxed) {
+            return Ok(stats);
+        }
+        check_cancelled(config)?;
+
+        let chunk_end = chunk_end(current_pos, region_end, config.chunk_size);
+        let chunk_started = Instant::now();
+        // One connection's speed says little about everyone's together, so the observer is left
+        // to work that out from the progress
+        let (chunk_data, _) = download_chunk(
+            &mut conn,
+            current_pos,
+            chunk_end,
+            config,
+            &shared.chunks,
+            &mut stats,
+            &mut |e| {
+                shared
+                    .progress
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .on_retry(e);
+            },
+        )?;
+
+        // The limit is for the whole download so each thread gets its share of it
+        thread::sleep(throttle_delay(
+            chunk_data.len() as u64 * config.concurrency as u64,
+            chunk_started.elapsed(),
+            config,
+        ));
+
+        let offset = (current_pos - region_start) as usize;
+        region[offset..offset + chunk_data.len()].copy_from_slice(&chunk_data);
+        current_pos += chunk_data.len() as u64;
+
+        let len = chunk_data.len() as u64;
+        let so_far = shared.downloaded.fetch_add(len, Ordering::Relaxed) + len;
+        // Holding the lock while printing stops two threads' lines getting mixed up
+        let mut progress = shared
+            .progress
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        progress.on_progress(so_far, shared.total_size);
+        report_fd(config, so_far, shared.total_size);
+    }
+    Ok(stats)
+}
+
+// The chunk loop shared by all the download functions, fetches start_pos..total_size. on_chunk
+// receives the offset each chunk starts at, in order, and decides what to do with the data.
+// Hashing is up to on_chunk too, by writing through a DownloadWriter, so the stats come back
+// without a hash. Progress is reported relative to start_pos so a resumed download counts up
+// from 0. Leaves total_duration for the caller to fill in
+fn download_chunks<F>(
+    start_pos: u64,
+    total_size: u64,
+    config: &DownloadConfig,
+    mut progress: Option<&mut dyn ProgressObserver>,
+    mut on_chunk: F,
+) -> Result<DownloadStats, DownloadError>
+where
+    F: FnMut(u64, &[u8]) -> Result<(), DownloadError>,
+{
+    check_config(config)?;
+    let config = &*resolve_redirects(config)?;
+    let manifest = config_manifest(config)?;
+    let attempts = AtomicU32::new(0);
+    let received = AtomicU64::new(0);
+    let headers_pending = AtomicBool::new(true);
+    let chunks = ChunkShared {
+        manifest: manifest.as_ref(),
+        attempts: shared_attempts(config, &attempts),
+        received: &received,
+        headers_pending: &headers_pending,
+    };
+
+    let mut current_pos: u64 = start_pos;
+
+    let chunk_size = config.chunk_size;
+    if let Some(progress) = progress.as_deref_mut() {
+        progress.on_status(&format!(
+            "Starting download in chunks of up to {chunk_size} bytes..."
+        ));
+    }
+
+    // Create a single TCP connection that we'll try to reuse
+    let mut conn = Connection::new();
+    let mut stats = DownloadStats::default();
+    let mut pipelining = config.pipeline_depth > 1 && config.keep_alive;
+
+    thread::scope(|scope| {
+        // Pipelining already keeps the connection busy between chunks and does it better, so
+        // prefetching is only for when that's off
+        let chunks_ref = &chunks;
+        let mut prefetcher = (config.prefetch && !pipelining).then(|| {
+            let (jobs, job_queue) = mpsc::channel();
+            let (done, results) = mpsc::channel();
+            Prefetcher {
+                jobs,
+                results,
+                pending: None,
+                worker: scope.spawn(move || prefetch_worker(&job_queue, &done, config, chunks_ref)),
+            }
+        });
+
+        while current_pos < total_size {
+            check_cancelled(config)?;
+
+            // A burst of chunks at once, whatever doesn't come back from it goes through
+            // download_chunk below like normal
+            let ranges = next_ranges(current_pos, total_size, config);
+            if pipelining && ranges.len() > 1 {
+                let burst_started = Instant::now();
+                let (bodies, server_pipelines) =
+                    pipeline_chunks(&mut conn, &ranges, config, &chunks, &mut stats)?;
+                if !server_pipelines {
+                    warn!(
+                        "Server doesn't seem to handle pipelined requests, sending one at a time"
+                    );
+                    pipelining = false;
+                }
+                if !bodies.is_empty() {
+                    let bytes = bodies.iter().map(|body| body.len() as u64).sum();
+                    thread::sleep(throttle_delay(bytes, burst_started.elapsed(), config));
+                    for body in bodies {
+                        on_chunk(current_pos, &body)?;
+                        current_pos += body.len() as u64;
+                    }
+                    if let Some(progress) = progress.as_deref_mut() {
+                        progress.on_progress(current_pos - start_pos, total_size - start_pos);
+                    }
+                    report_fd(config, current_pos - start_pos, total_size - start_pos);
+                    continue;
+                }
+            }
+
+            let chunk_start = current_pos;
+            let chunk_end = chunk_end(chunk_start, total_size, chunk_size);
+
+            // A bit of defensive programming here. Catch bugs early.
+            // Could instead make a Logic Variant for DownloadError so clients could give better
+            // diagonistics if things fail but ideally those never happen.
+            debug_assert!(chunk_start <= chunk_end, "Chunk start is after end");
+            let mut chunk_started = Instant::now();
+            // Whatever the prefetch thread made of this chunk if it was asked for it. One it
+            // couldn't get goes through download_chunk with its retries like any other
+            let prefetched = match prefetcher.as_mut().and_then(Prefetcher::take) {
+                Some((Attempt::Done(body), bytes_per_sec, sent)) => {
+                    chunk_started = sent;
+                    Some((body, bytes_per_sec))
+                }
+                Some((Attempt::GiveUp(e), ..)) => return Err(e),
+                Some((Attempt::Retry(e), ..)) => {
+                    if let Some(progress) = progress.as_deref_mut() {
+                        progress.on_retry(&e);
+                    }
+                    None
+                }
+                None => None,
+            };
+            let (chunk_data, bytes_per_sec) = match prefetched {
+                Some(prefetched) => prefetched,
+                None => download_chunk(
+                    &mut conn,
+                    chunk_start,
+                    chunk_end,
+                    config,
+                    &chunks,
+                    &mut stats,
+                    &mut |e| {
+                        if let Some(progress) = progress.as_deref_mut() {
+                            progress.on_retry(e);
+                        }
+                    },
+                )?,
+            };
+            // The odd chunk that didn't make it in a burst would otherwise be all the observer
+            // heard about, and stand in for the speed of every burst after it
+            if let (Some(progress), Some(speed), false) =
+                (progress.as_deref_mut(), bytes_per_sec, pipelining)
+            {
+                progress.on_throughput(speed);
+            }
+
+            // Can come back short if the server's Content-Range said it sent less, never longer
+            debug_assert!(
+                chunk_data.len() as u64 <= chunk_end - chunk_start + 1,
+                "Downloaded chunk is bigger than requested"
+            );
+
+            thread::sleep(throttle_delay(
+                chunk_data.len() as u64,
+                chunk_started.elapsed(),
+                config,
+            ));
+
+            // Whatever is left of this chunk becomes the start of the next one
+            let next = chunk_start + chunk_data.len() as u64;
+            // Which is on its way while this one gets handed over
+            if let Some(prefetcher) = prefetcher.as_mut().filter(|_| next < total_size) {
+                prefetcher.send(next, self::chunk_end(next, total_size, chunk_size));
+            }
+
+            on_chunk(chunk_start, &chunk_data)?;
+            current_pos = next;
+
+            if let Some(progress) = progress.as_deref_mut() {
+                progress.on_progress(current_pos - start_pos, total_size - start_pos);
+            }
+            report_fd(config, current_pos - start_pos, total_size - start_pos);
+        }
+
+        // Hanging up on the jobs is what tells the thread to stop
+        if let Some(Prefetcher { jobs, worker, .. }) = prefetcher {
+            drop(jobs);
+            let worker_stats = worker
+                .join()
+                .map_err(|_| DownloadError::Logic("Prefetch thread panicked".into()))?;
+            stats.merge(&worker_stats);
+        }
+        Ok(())
+    })?;
+
+    Ok(stats)
+}
+
+// download_chunks' end of the prefetch thread, see prefetch_worker
+struct Prefetcher<'scope> {
+    jobs: mpsc::Sender<(u64, u64)>,
+    results: mpsc::Receiver<(Attempt, Option<f64>)>,
+    // When the range it's working on was sent, if there is one
+    pending: Option<Instant>,
+    worker: thread::ScopedJoinHandle<'scope, DownloadStats>,
+}
+
+impl Prefetcher<'_> {
+    // A thread that's gone away just means nothing gets prefetched
+    fn send(&mut self, start: u64, end: u64) {
+        if self.jobs.send((start, end)).is_ok() {
+            self.pending = Some(Instant::now());
+        }
+    }
+
+    // Waits for the range that was sent, with its speed and when it was sent
+    fn take(&mut self) -> Option<(Attempt, Option<f64>, Instant)> {
+        let sent = self.pending.take()?;
+        let (attempt, bytes_per_sec) = self.results.recv().ok()?;
+        Some((attempt, bytes_per_sec, sent))
+    }
+}
+
+// Makes one attempt at every range that comes in on jobs, on a connection of its own, and sends
+// back what came of it. A failure isn't retried here, the chunk counts as attempted once it
+// arrives and anything else as a retry since download_chunk asks for it again. Hands back its
+// share of the stats once jobs hangs up
+fn prefetch_worker(
+    jobs: &mpsc::Receiver<(u64, u64)>,
+    results: &mpsc::Sender<(Attempt, Option<f64>)>,
+    config: &DownloadConfig,
+    chunks: &ChunkShared<'_>,
+) -> DownloadStats {
+    let mut conn = Connection::new();
+    let mut stats = DownloadStats::default();
+    for (start, end) in jobs {
+        let started = Instant::now();
+        let (attempt, bytes_per_sec) = match count_attempt(chunks.attempts, config) {
+            Ok(()) => {
+                // Same +1 as download_chunk
+                let response =
+                    send_request(&mut conn, start, end.saturating_add(1), config, chunks);
+                let bytes_per_sec = response.as_ref().ok().and_then(|r| r.bytes_per_sec);
+                let attempt = judge_attempt(start, end, 1, config, chunks, response, &mut stats);
+                (attempt, bytes_per_sec)
+            }
+            Err(e) => (Attempt::GiveUp(e), None),
+        };
+        match attempt {
+            Attempt::Done(_) => {
+                stats.chunks_attempted += 1;
+                stats.record_chunk(started.elapsed());
+            }
+            Attempt::Retry(_) => stats.total_retries += 1,
+            Attempt::GiveUp(_) => {}
+        }
+        if results.send((attempt, bytes_per_sec)).is_err() {
+            break;
+        }
+    }
+    stats
+}
+
+// The next pipeline_depth chunks from pos, as long as each one arrives in full
+fn next_ranges(pos: u64, total_size: u64, config: &DownloadConfig) -> Vec<(u64, u64)> {
+    let mut ranges = Vec::with_capacity(config.pipeline_depth);
+    let mut start = pos;
+    while ranges.len() < config.pipeline_depth && start < total_size {
+        let end = chunk_end(start, total_size, config.chunk_size);
+        ranges.push((start, end));
+        start = end + 1;
+    }
+    ranges
+}
+
+// Sends the request for every range in one write then reads the responses back in order.
+// Returns the chunks that came back whole, stopping at the first that didn't (or came back
+// short) since the rest no longer line up, and the connection gets closed to throw away whatever
+// they were. The flag is false if a response was garbled or for the wrong range, or the server
+// closed the connection with responses still to send, which is what a server that can't
+// pipeline does. Every chunk that made it counts as attempted, every request
+// that didn't as a retry since download_chunk will ask for it again
+fn pipeline_chunks(
+    conn: &mut Connection,
+    ranges: &[(u64, u64)],
+    config: &DownloadConfig,
+    chunks: &ChunkShared<'_>,
+    stats: &mut DownloadStats,
+) -> Result<(Vec<Vec<u8>>, bool), DownloadError> {
+    let mut requests = String::new();
+    let mut sent = 0;
+    // Any past what the connection has left would get cut off when it's closed for
+    // keepalive_max_requests, and look like the server not pipelining
+    let ranges = &ranges[..ranges.len().min(conn.requests_left(config) as usize)];
+    for &(start, end) in ranges {
+        if let Err(e) = count_attempt(chunks.attempts, config) {
+            if sent == 0 {
+                return Err(e);
+            }
+            break;
+        }
+        // Same +1 as download_chunk
+        requests.push_str(&range_request(start, end.saturating_add(1), config));
+        sent += 1;
+    }
+
+    let started = Instant::now();
+    let mut bodies = Vec::new();
+    let mut server_pipelines = true;
+    let written = conn
+        .stream(config)
+        .and_then(|stream| write_request(stream.get_mut(), &requests, config));
+    if written.is_ok() {
+        for &(start, end) in &ranges[..sent] {
+            let Ok(stream) = conn.stream(config) else {
+                break;
+            };
+            let stream = &mut RateLimitedReader::new(stream, read_limit(config));
+            // Already sent, this is only to print it
+            let trace = HeaderTrace::new(start, config, chunks);
+            let request = trace
+                .map(|_| range_request(start, end.saturating_add(1), config))
+                .unwrap_or_default();
+            let response = match read_head_traced(stream, &request, trace) {
+                Ok(headers) if headers.content_range.is_some_and(|r| r.start != start) => {
+                    server_pipelines = false;
+                    break;
+                }
+                Ok(headers) => read_body(stream, headers)
+                    .and_then(|response| finish_response(conn, response, config)),
+                Err(DownloadError::Parse(_)) => {
+                    server_pipelines = false;
+                    break;
+                }
+                Err(e) => Err(e),
+            }
+            .map_err(|e| e.with_timeout(config.read_timeout));
+
+            let body = match judge_attempt(start, end, 1, config, chunks, response, stats) {
+                Attempt::Done(body) => body,
+                Attempt::GiveUp(e) => {
+                    conn.close();
+                    return Err(e);
+                }
+                Attempt::Retry(_) => break,
+            };
+            stats.chunks_attempted += 1;
+            stats.record_chunk(started.elapsed());
+            let whole = body.len() as u64 == end - start + 1;
+            bodies.push(body);
+            if !whole {
+                break;
+            }
+            // The server hung up with responses still to come, so it's going to do that every
+            // time
+            if !conn.is_open() {
+                server_pipelines = bodies.len() == sent;
+                break;
+            }
         }
-        None => Err(DownloadError::Parse(
-            "Chunk has no end of headers therefore no body".to_owned(),
+    }
+
+    if bodies.len() < sent {
+        conn.close();
+        stats.total_retries += (sent - bodies.len()) as u32;
+    }
+    Ok((bodies, server_pipelines))
+}
+
+// Checked before every chunk, so a cancel takes effect once the chunk in flight is done
+pub(crate) fn check_cancelled(config: &DownloadConfig) -> Result<(), DownloadError> {
+    match &config.cancel_token {
+        Some(token) if token.load(Ordering::Relaxed) => Err(DownloadError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+// Whatever every chunk of one download shares, across threads for a parallel one
+pub(crate) struct ChunkShared<'a> {
+    pub(crate) manifest: Option<&'a ChunkManifest>,
+    // Requests made so far, for max_total_attempts
+    pub(crate) attempts: &'a AtomicU32,
+    // Body bytes received so far, for max_total_bytes
+    pub(crate) received: &'a AtomicU64,
+    // True until the first chunk's headers have been printed for debug_headers
+    pub(crate) headers_pending: &'a AtomicBool,
+}
+
+// Which chunk a response is for, for DownloadConfig::debug_headers. None of these without it
+#[derive(Clone, Copy)]
+struct HeaderTrace<'a> {
+    chunk: u64,
+    first: &'a AtomicBool,
+}
+
+impl<'a> HeaderTrace<'a> {
+    // Counted from 1 by where start falls
+    fn new(start: u64, config: &DownloadConfig, chunks: &ChunkShared<'a>) -> Option<Self> {
+        config.debug_headers.then(|| HeaderTrace {
+            chunk: start / config.chunk_size + 1,
+            first: chunks.headers_pending,
+        })
+    }
+}
+
+// config.attempt_counter if it has one, otherwise local which is fresh for each download
+pub(crate) fn shared_attempts<'a>(
+    config: &'a DownloadConfig,
+    local: &'a AtomicU32,
+) -> &'a AtomicU32 {
+    config.attempt_counter.as_deref().unwrap_or(local)
+}
+
+// Counts a request against max_total_attempts, or fails if there's none left
+pub(crate) fn count_attempt(
+    attempts: &AtomicU32,
+    config: &DownloadConfig,
+) -> Result<(), DownloadError> {
+    let Some(limit) = config.max_total_attempts else {
+        return Ok(());
+    };
+    if attempts.fetch_add(1, Ordering::Relaxed) >= limit {
+        return Err(DownloadError::Network(
+            format!("Global attempt limit reached, {limit} requests made").into(),
+        ));
+    }
+    Ok(())
+}
+
+// Counts body bytes against max_total_bytes, or fails once they've gone past it
+pub(crate) fn count_received(
+    received: &AtomicU64,
+    bytes: u64,
+    config: &DownloadConfig,
+) -> Result<(), DownloadError> {
+    let total = received.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    match config.max_total_bytes {
+        Some(limit) if total > limit => Err(DownloadError::Logic(
+            "Response exceeds expected size".into(),
         )),
+        _ => Ok(()),
+    }
+}
+
+// config.chunk_manifest read in, once per download rather than once per chunk
+pub(crate) fn config_manifest(
+    config: &DownloadConfig,
+) -> Result<Option<ChunkManifest>, DownloadError> {
+    config
+        .chunk_manifest
+        .as_deref()
+        .map(load_manifest)
+        .transpose()
+}
+
+// Settings every download refuses before it starts rather than misbehaving halfway through
+pub(crate) fn check_config(config: &DownloadConfig) -> Result<(), DownloadError> {
+    validate_path(&config.path)?;
+    validate_extra_headers(config)?;
+    validate_user_agent(&config.user_agent)?;
+    if config.chunk_size == 0 {
+        return Err(DownloadError::Args(
+            "Chunk size must be at least 1 byte".into(),
+        ));
+    }
+    if config.bandwidth_limit_bytes_per_sec == Some(0) {
+        return Err(DownloadError::Args(
+            "Bandwidth limit must be at least 1 byte per second".into(),
+        ));
+    }
+    if config.max_retries == 0 {
+        return Err(DownloadError::Args("Max retries must be at least 1".into()));
+    }
+    if config.pipeline_depth == 0 {
+        return Err(DownloadError::Args(
+            "Pipeline depth must be at least 1".into(),
+        ));
+    }
+    if config.keepalive_max_requests == 0 {
+        return Err(DownloadError::Args(
+            "Keep-alive max requests must be at least 1".into(),
+        ));
+    }
+    #[cfg(feature = "quic")]
+    if config.transport == crate::config::Transport::Quic && config.proxy.is_some() {
+        return Err(DownloadError::Args(
+            "QUIC can't go through an HTTP proxy".into(),
+        ));
+    }
+    #[cfg(feature = "quic")]
+    if config.transport == crate::config::Transport::Quic && config.socks5_proxy.is_some() {
+        return Err(DownloadError::Args(
+            "QUIC can't go through a SOCKS5 proxy".into(),
+        ));
+    }
+    if config.proxy.is_some() && config.socks5_proxy.is_some() {
+        return Err(DownloadError::Args(
+            "Can't go through an HTTP proxy and a SOCKS5 proxy at once".into(),
+        ));
+    }
+    Ok(())
+}
+
+// bandwidth_limit_bytes_per_sec for a RateLimitedReader, where 0 is no limit
+fn read_limit(config: &DownloadConfig) -> u64 {
+    config.bandwidth_limit_bytes_per_sec.unwrap_or(0)
+}
+
+// How much longer to wait after bytes took elapsed to arrive, so the download averages out at
+// bandwidth_limit_bytes_per_sec. Zero without a limit or if it was slow enough already, which is
+// usually the case since every response is read through a RateLimitedReader now. What's left for
+// this is splitting the limit between parallel threads (each connection's reader gets all of it)
+// and the async client, which doesn't read through one
+pub(crate) fn throttle_delay(bytes: u64, elapsed: Duration, config: &DownloadConfig) -> Duration {
+    match config.bandwidth_limit_bytes_per_sec {
+        Some(limit) if limit > 0 => {
+            Duration::from_secs_f64(bytes as f64 / limit as f64).saturating_sub(elapsed)
+        }
+        _ => Duration::ZERO,
+    }
+}
+
+// What plan_download works out, along with where it's meant to come from so it can be saved and
+// run again later (or somewhere else) through download_plan. ranges are inclusive like the Range
+// header, anything that makes them up doesn't have to cut the file the way split_ranges would.
+// Serializable with the serde feature
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DownloadPlan {
+    pub ranges: Vec<(u64, u64)>,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    // host:port like DownloadConfig::server_addr
+    pub server: String,
+    pub path: String,
+    pub created_at: SystemTime,
+}
+
+// Every (start, end) the download loop asks for, ends inclusive like the Range header (the request
+// itself asks for one more byte, see download_chunk). Doesn't touch the network. This is exactly
+// what happens as long as the server sends every chunk in full, one that sends less via
+// Content-Range shifts everything after it
+pub fn plan_download(total_size: u64, config: &DownloadConfig) -> DownloadPlan {
+    DownloadPlan {
+        ranges: split_ranges(total_size, config.chunk_size),
+        total_size,
+        chunk_size: config.chunk_size,
+        server: config.server_addr.clone(),
+        path: config.path.clone(),
+        created_at: SystemTime::now(),
+    }
+}
+
+// [0, total_size) cut into inclusive (start, end) ranges of chunk_size bytes, the last one
+// whatever's left. A chunk_size of 0 gives nothing since the download refuses it anyway
+#[must_use]
+pub fn split_ranges(total_size: u64, chunk_size: u64) -> Vec<(u64, u64)> {
+    if chunk_size == 0 {
+        return Vec::new();
+    }
+    let mut ranges = Vec::with_capacity(total_size.div_ceil(chunk_size) as usize);
+    let mut pos = 0;
+    while pos < total_size {
+        let end = chunk_end(pos, total_size, chunk_size);
+        ranges.push((pos, end));
+        pos = end + 1;
+    }
+    ranges
+}
+
+// Last byte of the chunk starting at start, stopping at whichever comes first of chunk_size bytes
+// or limit (exclusive). start has to be below limit
+pub(crate) fn chunk_end(start: u64, limit: u64, chunk_size: u64) -> u64 {
+    start
+        .saturating_add(chunk_size)
+        .saturating_sub(1)
+        .min(limit - 1)
+}
+
+// Works out how big the file is so it doesn't have to be passed in. Tries in order:
+// 1. HEAD and read Content-Length
+// 2. GET with Range: bytes=0-0 and read the total out of Content-Range
+// 3. Plain GET and read Content-Length, hanging up before the body arrives. This is the only
+//    one the python server supports since it has no HEAD and never sends Content-Range
+pub fn discover_total_size(config: &DownloadConfig) -> Result<u64, DownloadError> {
+    validate_path(&config.path)?;
+    validate_extra_headers(config)?;
+    validate_user_agent(&config.user_agent)?;
+    let config = &*resolve_redirects(config)?;
+    let path = request_target(config);
+    let request = |method, range: Option<(u64, u64)>| {
+        let mut request = HttpRequest::new(method, &path, &config.server_addr);
+        add_user_agent(&mut request, config);
+        if let Some((start, end)) = range {
+            request.range(start, end);
+        }
+        add_auth(&mut request, config);
+        request.header("Connection", "close");
+        add_extra_headers(&mut request, config);
+        request.to_string()
+    };
+
+    let head = request("HEAD", None);
+    if let Some(size) = discovery_headers(&head, config)?.and_then(|h| size_from_head_response(&h))
+    {
+        return Ok(size);
+    }
+
+    let ranged = request("GET", Some((0, 0)));
+    if let Some(size) =
+        discovery_headers(&ranged, config)?.and_then(|h| size_from_range_response(&h))
+    {
+        return Ok(size);
+    }
+
+    let full = request("GET", None);
+    if let Some(size) = discovery_headers(&full, config)?.and_then(|h| size_from_head_response(&h))
+    {
+        return Ok(size);
+    }
+
+    Err(DownloadError::Parse(
+        "Could not discover the total size, server sent neither Content-Length nor Content-Range"
+            .to_owned()
+            .into(),
+    ))
+}
+
+// A health check before a long download. Asks for the first byte (the python server has no HEAD)
+// and times it from sending the request to the response headers arriving, so connecting and the
+// TLS handshake aren't in it. Anything but a 200 or 206 is the usual ServerError
+pub fn test_connection(config: &DownloadConfig) -> Result<Duration, DownloadError> {
+    validate_path(&config.path)?;
+    validate_extra_headers(config)?;
+    validate_user_agent(&config.user_agent)?;
+    let config = &*resolve_redirects(config)?;
+    let mut conn = Connection::new();
+    let stream = conn.stream(config)?;
+    let started = Instant::now();
+    write_request(stream.get_mut(), &range_request(0, 0, config), config)?;
+    let headers = read_head(stream).map_err(|e| e.with_timeout(config.read_timeout))?;
+    let rtt = started.elapsed();
+    check_status(&headers, &[])?;
+    Ok(rtt)
+}
+
+// With follow_redirects on, asks for the first byte and follows redirects until something else
+// comes back, returning a config pointed at wherever that was. follow_redirects is off in it so
+// the download functions it gets passed to don't do it all again. Anything other than a redirect
+// (including the request failing) is left for the download itself to deal with
+pub fn resolve_redirects(
+    config: &DownloadConfig,
+) -> Result<Cow<'_, DownloadConfig>, DownloadError> {
+    if !config.follow_redirects {
+        return Ok(Cow::Borrowed(config));
+    }
+    let mut resolved = config.clone();
+    let mut tracker = RedirectTracker::new(&resolved);
+    while let Some(headers) = discovery_headers(&range_request(0, 0, &resolved), &resolved)? {
+        if !tracker.follow(&mut resolved, &headers)? {
+            break;
+        }
+    }
+    resolved.follow_redirects = false;
+    Ok(Cow::Owned(resolved))
+}
+
+// request_headers for discover_total_size. Most failures just mean try the next way (ie no HEAD
+// support) but bad arguments or credentials will fail every way so those come straight back
+fn discovery_headers(
+    request: &str,
+    config: &DownloadConfig,
+) -> Result<Option<ResponseHeaders>, DownloadError> {
+    discovery_outcome(request_headers(request, config))
+}
+
+pub(crate) fn discovery_outcome(
+    result: Result<ResponseHeaders, DownloadError>,
+) -> Result<Option<ResponseHeaders>, DownloadError> {
+    match result {
+        Ok(headers) if headers.status_code == 401 => Err(status_error(&headers, &[])),
+        Ok(headers) => Ok(Some(headers)),
+        Err(e @ DownloadError::Args(_)) => Err(e),
+        Err(_) => Ok(None),
+    }
+}
+
+// A successful HEAD (or unranged GET) has the full size as its Content-Length
+fn size_from_head_response(headers: &ResponseHeaders) -> Option<u64> {
+    (headers.status_code == 200)
+        .then_some(headers.content_length)
+        .flatten()
+}
+
+// A range response has the full size after the / in Content-Range. If the server ignored the
+// range and sent a 200 then Content-Length is the full size instead
+fn size_from_range_response(headers: &ResponseHeaders) -> Option<u64> {
+    match headers.status_code {
+        206 => headers.content_range.and_then(|r| r.total),
+        200 => headers.content_length,
+        _ => None,
+    }
+}
+
+// Sends request on a fresh connection and returns just the response headers, the connection
+// gets dropped without reading the body
+fn request_headers(
+    request: &str,
+    config: &DownloadConfig,
+) -> Result<ResponseHeaders, DownloadError> {
+    let mut conn = Connection::new();
+    let stream = conn.stream(config)?;
+    write_request(stream.get_mut(), request, config)?;
+    read_head(stream).map_err(|e| e.with_timeout(config.read_timeout))
+}
+
+// The socket's write timeout is connect_timeout
+fn write_request<W: Write>(
+    stream: &mut W,
+    request: &str,
+    config: &DownloadConfig,
+) -> Result<(), DownloadError> {
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| DownloadError::from(e).with_timeout(config.connect_timeout))
+}
+
+// This does some retrying in case downloading fails, on_retry hears about every failed attempt
+// that's going to be retried. Every attempt gets counted in stats, and against
+// max_total_attempts which stops it straight away once it runs out. Comes back with how fast the
+// attempt that worked arrived, see Response::bytes_per_sec
+fn download_chunk(
+    conn: &mut Connection,
+    start: u64,
+    end: u64,
+    config: &DownloadConfig,
+    chunks: &ChunkShared<'_>,
+    stats: &mut DownloadStats,
+    on_retry: &mut dyn FnMut(&DownloadError),
+) -> Result<(Vec<u8>, Option<f64>), DownloadError> {
+    let expected_len = (end.saturating_sub(start) + 1) as usize;
+    if expected_len == 0 {
+        // Shouldn't happen but handle defensively
+        return Ok((Vec::new(), None));
+    }
+
+    debug!("Requesting chunk: bytes={start}-{end} (expecting {expected_len} bytes)");
+
+    // The +1 is because the buggy python server doesn't
+    // actually respect the HTTP Range header
+    // correctly I think, I might be wrong though
+    let request_end = end.saturating_add(1);
+    retry_chunk(start, end, config, chunks, stats, on_retry, || {
+        send_request(conn, start, request_end, config, chunks)
+    })
+}
+
+// download_chunk's retry loop, with send making each request. Up to max_retries of them with
+// retry_delay between, or until judge_attempt is happy with one or says to give up
+fn retry_chunk(
+    start: u64,
+    end: u64,
+    config: &DownloadConfig,
+    chunks: &ChunkShared<'_>,
+    stats: &mut DownloadStats,
+    on_retry: &mut dyn FnMut(&DownloadError),
+    mut send: impl FnMut() -> Result<Response, DownloadError>,
+) -> Result<(Vec<u8>, Option<f64>), DownloadError> {
+    let started = Instant::now();
+    stats.chunks_attempted += 1;
+    let max_retries = config.max_retries;
+    for attempt in 1..=max_retries {
+        count_attempt(chunks.attempts, config)?;
+        let response = send();
+        let bytes_per_sec = response.as_ref().ok().and_then(|r| r.bytes_per_sec);
+        let failure = match judge_attempt(start, end, attempt, config, chunks, response, stats) {
+            Attempt::Done(body) => {
+                stats.record_chunk(started.elapsed());
+                return Ok((body, bytes_per_sec));
+            }
+            Attempt::GiveUp(e) => return Err(e),
+            Attempt::Retry(e) => e,
+        };
+
+        // Wait for a bit before retrying for this chunk, no point waiting after the last one
+        if attempt == max_retries {
+            return Err(retries_exhausted(start, end, attempt, failure));
+        }
+        stats.total_retries += 1;
+        on_retry(&failure);
+        thread::sleep(retry_delay(attempt, &failure, config));
+    }
+
+    // Only with max_retries 0, which check_config turns away
+    Err(DownloadError::Logic("No attempts allowed".into()))
+}
+
+// Several ranges in one request (Range: bytes=0-99,200-299), for a few scattered parts of a file
+// without a request each. Ranges are inclusive like plan_download's. Hands back (start, data) for
+// each range in the order they were asked for, cut out of whatever the server sent: a
+// multipart/byteranges body, one range covering them all, or the whole file from a server that
+// ignores Range. Retried like a chunk, there's no +1 for the python server since it sends the
+// whole file anyway
+pub fn fetch_multiple_ranges(
+    ranges: &[(u64, u64)],
+    config: &DownloadConfig,
+) -> Result<Vec<(u64, Vec<u8>)>, DownloadError> {
+    check_config(config)?;
+    let (Some(first), Some(last)) = (ranges.first(), ranges.last()) else {
+        return Err(DownloadError::Args("No ranges to fetch".into()));
+    };
+    if let Some((start, end)) = ranges.iter().find(|(start, end)| start > end) {
+        return Err(DownloadError::Args(format!(
+            "Invalid range {start}-{end}, the start is past the end"
+        )));
+    }
+    let config = &*resolve_redirects(config)?;
+    let spec = ranges
+        .iter()
+        .map(|(start, end)| format!("{start}-{end}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    let request = ranges_request(&spec, config);
+    let attempts = AtomicU32::new(0);
+    let attempts = shared_attempts(config, &attempts);
+
+    let mut conn = Connection::new();
+    let max_retries = config.max_retries;
+    for attempt in 1..=max_retries {
+        count_attempt(attempts, config)?;
+        let result = exchange(&mut conn, &request, config, None)
+            .and_then(|response| cut_ranges(ranges, response));
+        let failure = match result {
+            Ok(parts) => return Ok(parts),
+            Err(e @ (DownloadError::Args(_) | DownloadError::Auth(_))) => return Err(e),
+            Err(
+                e @ DownloadError::ServerError {
+                    status: 400..=499, ..
+                },
+            ) => return Err(e),
+            Err(e) => e,
+        };
+        if attempt == max_retries {
+            return Err(retries_exhausted(first.0, last.1, attempt, failure));
+        }
+        warn!(
+            "Error fetching ranges {spec}: {failure}. Retrying (attempt {attempt}/{max_retries})"
+        );
+        thread::sleep(retry_delay(attempt, &failure, config));
+    }
+    Err(DownloadError::Logic("No attempts allowed".into()))
+}
+
+// fetch_multiple_ranges' answer split back up into the ranges that were asked for
+fn cut_ranges(
+    ranges: &[(u64, u64)],
+    response: Response,
+) -> Result<Vec<(u64, Vec<u8>)>, DownloadError> {
+    let headers = &response.headers;
+    let parts = match headers.get("content-type").and_then(byteranges_boundary) {
+        Some(boundary) if headers.status_code == 206 => {
+            parse_byteranges(&response.body, &boundary)?
+                .into_iter()
+                .map(|(range, data)| (range.start, data))
+                .collect()
+        }
+        _ => match headers.content_range {
+            Some(range) if headers.status_code == 206 => vec![(range.start, response.body)],
+            _ => vec![(0, response.body)],
+        },
+    };
+
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            parts
+                .iter()
+                .find_map(|(part_start, data)| {
+                    let from = usize::try_from(start.checked_sub(*part_start)?).ok()?;
+                    let to = usize::try_from(end - part_start).ok()?;
+                    data.get(from..=to).map(|bytes| (start, bytes.to_vec()))
+                })
+                .ok_or_else(|| {
+                    DownloadError::Parse(format!("Server didn't send bytes {start}-{end}").into())
+                })
+        })
+        .collect()
+}
+
+// What one attempt at a chunk came to
+pub(crate) enum Attempt {
+    Done(Vec<u8>),
+    // Worth another go after the backoff
+    Retry(DownloadError),
+    // Retrying won't help
+    GiveUp(DownloadError),
+}
+
+// Decides what to do with one attempt at start..=end, counting it in stats. Kept apart from the
+// sending and sleeping so the async client makes exactly the same calls. A body that's the right
+// length still gets retried if the manifest has a different hash for it
+pub(crate) fn judge_attempt(
+    start: u64,
+    end: u64,
+    attempt: u32,
+    config: &DownloadConfig,
+    chunks: &ChunkShared<'_>,
+    response: Result<Response, DownloadError>,
+    stats: &mut DownloadStats,
+) -> Attempt {
+    let expected_len = (end.saturating_sub(start) + 1) as usize;
+    let max_retries = config.max_retries;
+    let done = |body: Vec<u8>| match chunks
+        .manifest
+        .map_or(Ok(()), |m| verify_chunk(start, &body, m))
+    {
+        Ok(()) => Attempt::Done(body),
+        Err(e) => {
+            warn!(
+                "Error downloading chunk {}-{}: {}. Retrying (attempt {}/{})",
+                start, end, e, attempt, max_retries
+            );
+            Attempt::Retry(e)
+        }
+    };
+    match &response {
+        Ok(response) => {
+            let len = response.body.len() as u64;
+            stats.bytes_transferred += len;
+            // Past the limit is the server misbehaving, asking again won't help
+            if let Err(e) = count_received(chunks.received, len, config) {
+                return Attempt::GiveUp(e);
+            }
+        }
+        Err(
+            DownloadError::Io(_)
+            | DownloadError::Timeout { .. }
+            | DownloadError::ConnectionRefused { .. }
+            | DownloadError::Network(_),
+        ) => {
+            stats.connection_errors += 1;
+        }
+        Err(_) => {}
+    }
+
+    match response {
+        // A server that sends Content-Range says exactly which bytes it sent, so trust that
+        // over the body length. It might have sent less than we asked for which is fine, the
+        // caller just asks for the rest next time
+        Ok(Response {
+            headers:
+                ResponseHeaders {
+                    content_range: Some(range),
+                    ..
+                },
+            body,
+            ..
+        }) => match body_for_range(start, end, range, body) {
+            Ok(body) => done(body),
+            Err(e) => {
+                warn!(
+                    "Error downloading chunk {}-{}: {}. Retrying (attempt {}/{})",
+                    start, end, e, attempt, max_retries
+                );
+                Attempt::Retry(e)
+            }
+        },
+        Ok(Response { mut body, .. }) => {
+            // Without a Content-Range all there is to go on is the length. The Range that went
+            // out had the extra byte on the end, so one more than the chunk is a server that
+            // honoured it and gets cut off the same as in body_for_range. Any longer means it
+            // ignored the Range
+            if let Err(e) =
+                validate_range_response((start, end.saturating_add(1)), None, body.len())
+            {
+                warn!(
+                    "Error downloading chunk {}-{}: {}. Retrying (attempt {}/{})",
+                    start, end, e, attempt, max_retries
+                );
+                return Attempt::Retry(e);
+            }
+            body.truncate(expected_len);
+            if body.len() == expected_len {
+                debug!("Successfully received chunk {start}-{end}");
+                done(body)
+            } else {
+                // Received 200/206 but server truncated the body so it doesn't match the
+                // expeced length
+
+                warn!(
+                    "Received truncated chunk ({} bytes) for range {start}-{end} (expected \
+                     {expected_len}). Retrying (attempt {attempt}/{max_retries})",
+                    body.len()
+                );
+                // Fall through to retry delay
+                Attempt::Retry(DownloadError::Parse(
+                    format!(
+                        "Truncated chunk: got {} bytes, expected {expected_len}",
+                        body.len()
+                    )
+                    .into(),
+                ))
+            }
+        }
+        // Retrying won't fix a bad config, bad credentials or a 4xx so bail out straight away
+        Err(e @ (DownloadError::Args(_) | DownloadError::Auth(_))) => Attempt::GiveUp(e),
+        Err(
+            e @ DownloadError::ServerError {
+                status: 400..=499, ..
+            },
+        ) => Attempt::GiveUp(e),
+        Err(e) => {
+            // Handle the network or parsing error
+            warn!(
+                "Error downloading chunk {}-{}: {}. Retrying (attempt {}/{})",
+                start, end, e, attempt, max_retries
+            );
+            // Fall through to retry delay
+            Attempt::Retry(e)
+        }
+    }
+}
+
+pub(crate) fn retries_exhausted(
+    start: u64,
+    end: u64,
+    attempts: u32,
+    last_error: DownloadError,
+) -> DownloadError {
+    warn!("Giving up on chunk {start}-{end} after {attempts} attempts");
+    DownloadError::RetriesExhausted {
+        attempts,
+        last_error: Box::new(last_error),
+    }
+}
+
+// Works out which part of body is the chunk start..=end using the Content-Range the server sent.
+// The result can be shorter than asked for if the server sent less. It gets cut down if the
+// server sent more, which a correct server does since we ask for one byte past end
+fn body_for_range(
+    start: u64,
+    end: u64,
+    range: ContentRange,
+    mut body: Vec<u8>,
+) -> Result<Vec<u8>, DownloadError> {
+    // The Range that went out had the extra byte on the end
+    validate_range_response((start, end.saturating_add(1)), Some(range), body.len())?;
+    let received_end = range.end.min(end);
+    body.truncate((received_end - range.start + 1) as usize);
+    Ok(body)
+}
+
+// Checks a response to Range: bytes=<requested.0>-<requested.1> is really that range, so a server
+// sending the wrong part of the file is a Parse error rather than the wrong bytes in the output.
+// It has to start where we asked, can stop short of the end (the caller asks for the rest), never
+// past it, and the body has to be as long as its Content-Range says. Without a Content-Range only
+// the body can be checked, it can't be longer than what was asked for
+pub fn validate_range_response(
+    requested: (u64, u64),
+    response_range: Option<ContentRange>,
+    body_len: usize,
+) -> Result<(), DownloadError> {
+    let (start, end) = requested;
+    let mismatch = |detail: String| {
+        Err(DownloadError::Parse(
+            format!("Range mismatch: {detail}").into(),
+        ))
+    };
+    let Some(range) = response_range else {
+        let asked = end.saturating_sub(start) + 1;
+        if body_len as u64 > asked {
+            return mismatch(format!("asked for {asked} bytes but body has {body_len}"));
+        }
+        return Ok(());
+    };
+    if range.start != start || range.end > end {
+        return mismatch(format!(
+            "asked for bytes {start}-{end} but server sent {}-{}",
+            range.start, range.end
+        ));
+    }
+    if body_len as u64 != range.byte_len() {
+        return mismatch(format!(
+            "Content-Range says {} bytes but body has {body_len}",
+            range.byte_len()
+        ));
+    }
+    Ok(())
+}
+
+// The GET for bytes start-end, end goes in as is so the caller deals with the python server
+pub(crate) fn range_request(start: u64, end: u64, config: &DownloadConfig) -> String {
+    ranges_request(&format!("{start}-{end}"), config)
+}
+
+// The GET for a Range: bytes= of ranges, ie "0-99" or "0-99,200-299"
+fn ranges_request(ranges: &str, config: &DownloadConfig) -> String {
+    let path = request_target(config);
+    let mut request = HttpRequest::new("GET", &path, &config.server_addr);
+    add_user_agent(&mut request, config);
+    request.header("Range", &format!("bytes={ranges}"));
+    add_auth(&mut request, config);
+    if config.accept_encoding {
+        request.header("Accept-Encoding", "gzip, deflate");
+    }
+    let connection = if config.keep_alive {
+        "keep-alive"
+    } else {
+        "close"
+    };
+    request.header("Connection", connection);
+    add_extra_headers(&mut request, config);
+    request.to_string()
+}
+
+// What goes between GET and HTTP/1.1. A proxy needs the whole URL to know where to send it,
+// except through a CONNECT tunnel where the server itself is on the other end
+fn request_target(config: &DownloadConfig) -> String {
+    if config.proxy.is_some() && !config.use_tls {
+        format!("http://{}{}", config.server_addr, config.path)
+    } else {
+        config.path.clone()
+    }
+}
+
+fn send_request(
+    conn: &mut Connection,
+    start: u64,
+    end: u64,
+    config: &DownloadConfig,
+    chunks: &ChunkShared<'_>,
+) -> Result<Response, DownloadError> {
+    let trace = HeaderTrace::new(start, config, chunks);
+    exchange(conn, &range_request(start, end, config), config, trace)
+}
+
+// Sends request on conn and reads the response, status checked and body decompressed
+fn exchange(
+    conn: &mut Connection,
+    request: &str,
+    config: &DownloadConfig,
+    trace: Option<HeaderTrace<'_>>,
+) -> Result<Response, DownloadError> {
+    loop {
+        // Servers are allowed to close idle keep-alive connections whenever they like, so if a
+        // reused connection dies that's not a real failure, just try again on a fresh one
+        let reused = conn.is_open();
+        let result = conn.stream(config).and_then(|stream| {
+            write_request(stream.get_mut(), request, config)?;
+            // The limit holds the reads back as they happen, see throttle_delay for what it's on
+            // top of
+            let mut reader = RateLimitedReader::new(stream, read_limit(config));
+            read_head_traced(&mut reader, request, trace)
+                .and_then(|headers| read_body_timed(&mut reader, headers))
+                .map_err(|e| e.with_timeout(config.read_timeout))
+        });
+
+        match result {
+            Ok(response) => return finish_response(conn, response, config),
+            Err(DownloadError::Io(_) | DownloadError::Timeout { .. }) if reused => conn.close(),
+            Err(e) => {
+                // No idea what state the stream is in now so don't reuse it
+                conn.close();
+                return Err(e);
+            }
+        }
+    }
+}
+
+// Everything after a response has been read off conn, closing conn if it can't be reused
+fn finish_response(
+    conn: &mut Connection,
+    response: Response,
+    config: &DownloadConfig,
+) -> Result<Response, DownloadError> {
+    if !config.keep_alive || !response.keep_alive {
+        conn.close();
+    } else {
+        conn.finished_request(config);
+    }
+    check_status(&response.headers, &response.body)?;
+    decompress_body(response, config)
+}
+
+// Undoes Content-Encoding if we asked for it. Without accept_encoding a compressed body is left
+// alone like any other, it's the server's problem if it ignored us
+pub(crate) fn decompress_body(
+    mut response: Response,
+    config: &DownloadConfig,
+) -> Result<Response, DownloadError> {
+    if config.accept_encoding {
+        if let Some(encoding) = response.headers.get("content-encoding") {
+            response.body = decode_content(encoding, response.body)?;
+        }
+    }
+    Ok(response)
+}
+
+// A single response off the wire, status hasn't been checked yet
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub(crate) headers: ResponseHeaders,
+    pub(crate) body: Vec<u8>,
+    // Whether the connection can be used for another request afterwards
+    pub(crate) keep_alive: bool,
+    // How fast the body came off the connection, once exchange has timed it. None when it wasn't,
+    // ie pipelined responses or the async client's, or it was too quick to time
+    pub(crate) bytes_per_sec: Option<f64>,
+}
+
+// read_body with the speed filled in. Timed from once the head has been read, so the wait for
+// the server to answer and the head itself don't count, only the body
+fn read_body_timed<R: BufRead>(
+    reader: &mut R,
+    headers: ResponseHeaders,
+) -> Result<Response, DownloadError> {
+    let mut reader = ThrottledReader::new(reader);
+    let mut response = read_body(&mut reader, headers)?;
+    response.bytes_per_sec = Some(reader.bytes_per_second()).filter(|&speed| speed > 0.0);
+    Ok(response)
+}
+
+// Anything bigger than this isn't a sane header section
+pub(crate) const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// Reads exactly one response off the reader. A chunked body gets decoded as it's read, otherwise
+// if the server told us the Content-Length we read just that many bytes. Either way the
+// connection can be reused after, without either we read until the server closes it. exchange
+// does the same thing through read_head_traced
+#[cfg(test)]
+fn read_response<R: BufRead>(reader: &mut R) -> Result<Response, DownloadError> {
+    let headers = read_head(reader)?;
+    read_body(reader, headers)
+}
+
+// The rest of read_response once the head's been read
+fn read_body<R: BufRead>(
+    reader: &mut R,
+    headers: ResponseHeaders,
+) -> Result<Response, DownloadError> {
+    let mut keep_alive = headers.keep_alive();
+
+    if headers.is_chunked() {
+        let body = read_chunked_body(reader)?;
+        return Ok(Response {
+            headers,
+            body,
+            keep_alive,
+            bytes_per_sec: None,
+        });
+    }
+    let mut body = Vec::new();
+    match headers.content_length {
+        Some(len) => {
+            reader.take(len).read_to_end(&mut body)?;
+            // Connection got cut off partway (the python server loves doing this), we know
+            // straight away the chunk is no good
+            if (body.len() as u64) < len {
+                return Err(body_truncated(body.len(), len));
+            }
+        }
+        None => {
+            reader.read_to_end(&mut body)?;
+            keep_alive = false;
+        }
+    }
+
+    Ok(Response {
+        headers,
+        body,
+        keep_alive,
+        bytes_per_sec: None,
+    })
+}
+
+pub(crate) fn body_truncated(got: usize, expected: u64) -> DownloadError {
+    DownloadError::Parse(
+        format!("Response body truncated: got {got} bytes, expected {expected}").into(),
+    )
+}
+
+// Reads just the status line and headers, leaving the reader at the start of the body
+fn read_head<R: BufRead>(reader: &mut R) -> Result<ResponseHeaders, DownloadError> {
+    parse_response_headers(&read_raw_head(reader)?)
+}
+
+// The head as it came off the wire, blank line at the end included
+fn read_raw_head<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, DownloadError> {
+    // Read line by line until the end of headers (double CRLF), body is afterwards from it
+    let mut head = Vec::new();
+    while !head_complete(&head) {
+        let read = reader.read_until(b'\n', &mut head)?;
+        check_head(read, &head)?;
+    }
+    Ok(head)
+}
+
+// read_head, printing to stderr what came back if there's a trace. The first chunk gets its
+// request and the whole head, straight off the wire so a head that won't parse still shows up.
+// Every other chunk gets one line
+fn read_head_traced<R: BufRead>(
+    reader: &mut R,
+    request: &str,
+    trace: Option<HeaderTrace<'_>>,
+) -> Result<ResponseHeaders, DownloadError> {
+    let Some(HeaderTrace { chunk, first }) = trace else {
+        return read_head(reader);
+    };
+    let head = read_raw_head(reader)?;
+    if first.swap(false, Ordering::Relaxed) {
+        eprint!("{}", header_dump(chunk, request, &head));
+        return parse_response_headers(&head);
+    }
+    let headers = parse_response_headers(&head)?;
+    let bytes = match headers.content_length {
+        Some(len) => len.to_string(),
+        None => "?".to_owned(),
+    };
+    eprintln!("[chunk {chunk}: {} {bytes}]", headers.status_code);
+    Ok(headers)
+}
+
+pub(crate) fn head_complete(head: &[u8]) -> bool {
+    head.ends_with(b"\r\n\r\n")
+}
+
+// After each line of the head, read is how many bytes that line was
+pub(crate) fn check_head(read: usize, head: &[u8]) -> Result<(), DownloadError> {
+    if read == 0 {
+        return Err(DownloadError::Parse(
+            "Chunk has no end of headers therefore no body"
+                .to_owned()
+                .into(),
+        ));
+    }
+    if head.len() > MAX_HEAD_SIZE {
+        return Err(DownloadError::Parse(
+            format!("Response headers are larger than {MAX_HEAD_SIZE} bytes").into(),
+        ));
+    }
+    Ok(())
+}
+
+// Straight after Host, unless it's been set to nothing
+fn add_user_agent(request: &mut HttpRequest<'_>, config: &DownloadConfig) {
+    if !config.user_agent.is_empty() {
+        request.header("User-Agent", &config.user_agent);
+    }
+}
+
+// The Authorization header every request gets if there are credentials
+fn add_auth(request: &mut HttpRequest<'_>, config: &DownloadConfig) {
+    if let Some(auth) = basic_auth(config) {
+        request.header("Authorization", &auth);
+    }
+}
+
+fn basic_auth(config: &DownloadConfig) -> Option<String> {
+    let (user, password) = config.auth.as_ref()?;
+    Some(format!(
+        "Basic {}",
+        BASE64_STANDARD.encode(format!("{user}:{password}"))
+    ))
+}
+
+// DownloadConfig::extra_headers, after everything else. HttpRequest::header drops any CR or LF
+// in a value so none of them can start a header of its own
+fn add_extra_headers(request: &mut HttpRequest<'_>, config: &DownloadConfig) {
+    for (name, value) in &config.extra_headers {
+        request.header(name, value);
+    }
+}
+
+fn validate_extra_headers(config: &DownloadConfig) -> Result<(), DownloadError> {
+    config
+        .extra_headers
+        .iter()
+        .try_for_each(|(name, _)| validate_header_name(name))
+}
+
+// Anything that isn't 200 or 206 is an error
+// body is whatever came with the response, it ends up in a ServerError
+pub(crate) fn check_status(headers: &ResponseHeaders, body: &[u8]) -> Result<(), DownloadError> {
+    match headers.status_code {
+        200 | 206 => Ok(()),
+        _ => Err(status_error(headers, body)),
+    }
+}
+
+fn status_error(headers: &ResponseHeaders, body: &[u8]) -> DownloadError {
+    match headers.status_code {
+        // Never says what the credentials were, only that they didn't work
+        401 => DownloadError::Auth(format!(
+            "Server returned 401 {}, credentials are missing or wrong",
+            headers.reason
+        )),
+        // Retried like a 5xx rather than given up on like every other 4xx
+        429 => DownloadError::RateLimited {
+            retry_after: headers
+                .get("retry-after")
+                .and_then(|value| parse_retry_after(value, SystemTime::now())),
+        },
+        status @ 400..=599 => DownloadError::ServerError {
+            status,
+            body: body[..body.len().min(MAX_ERROR_BODY)].to_vec(),
+        },
+        code => DownloadError::HttpStatus {
+            code,
+            reason: headers.reason.clone(),
+        },
+    }
+}
+
+// Split a raw response into its status and body. Kept separate from send_request so it can be
+// tested without a server
+#[cfg(test)]
+fn parse_response(mut response: &[u8]) -> Result<Vec<u8>, DownloadError> {
+    let response = read_response(&mut response)?;
+    check_status(&response.headers, &response.body)?;
+    Ok(response.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::config::DEFAULT_SERVER_ADDR;
+    use crate::retry::backoff_delay;
+
+    fn expect_status(raw: &[u8], expected_code: u16, expected_reason: &str) {
+        match parse_response(raw) {
+            Err(DownloadError::HttpStatus { code, reason }) => {
+                assert_eq!(code, expected_code);
+                assert_eq!(reason, expected_reason);
+            }
+            other => panic!("Expected HttpStatus error, got {other:?}"),
+        }
+    }
+
+    fn expect_server_error(raw: &[u8], expected_status: u16, expected_body: &[u8]) {
+        match parse_response(raw) {
+            Err(DownloadError::ServerError { status, body }) => {
+                assert_eq!(status, expected_status);
+                assert_eq!(body, expected_body);
+            }
+            other => panic!("Expected ServerError, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn status_not_found() {
+        expect_server_error(b"HTTP/1.1 404 Not Found\r\n\r\n", 404, b"");
+    }
+
+    #[test]
+    fn status_unauthorized() {
+        match parse_response(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n") {
+            Err(DownloadError::Auth(msg)) => assert!(msg.contains("401 Unauthorized"), "{msg}"),
+            other => panic!("Expected Auth error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn chunked_body() {
+        let raw = b"HTTP/1.1 206 Partial Content\r\nTransfer-Encoding: chunked\r\n\
+                    Content-Length: 100\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        let mut reader = &raw[..];
+        let response = read_response(&mut reader).unwrap();
+        assert_eq!(response.body, b"abcde");
+        // Chunked says where the body ends, so the connection is good for another request
+        assert!(response.keep_alive);
+    }
+
+    #[test]
+    fn status_too_many_requests() {
+        match parse_response(b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 7\r\n\r\n") {
+            Err(DownloadError::RateLimited { retry_after }) => {
+                assert_eq!(retry_after, Some(Duration::from_secs(7)));
+            }
+            other => panic!("Expected RateLimited, got {other:?}"),
+        }
+        // Without a usable Retry-After it's just the normal backoff
+        assert!(matches!(
+            parse_response(b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: later\r\n\r\n"),
+            Err(DownloadError::RateLimited { retry_after: None })
+        ));
+    }
+
+    #[test]
+    fn basic_auth_header() {
+        assert_eq!(basic_auth(&DownloadConfig::default()), None);
+        // The example from RFC 7617
+        let config = DownloadConfig {
+            auth: Some(("Aladdin".into(), "open sesame".into())),
+            ..Default::default()
+        };
+        assert_eq!(
+            basic_auth(&config).as_deref(),
+            Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==")
+        );
+    }
+
+    #[test]
+    fn request_line_uses_the_path() {
+        let config = DownloadConfig {
+            path: "/files/data.bin?v=2".into(),
+            ..Default::default()
+        };
+        let request = range_request(0, 99, &config);
+        assert!(
+            request.starts_with("GET /files/data.bin?v=2 HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n"),
+            "{request}"
+        );
+        let bad = DownloadConfig {
+            path: "/../secret".into(),
+            ..Default::default()
+        };
+        assert!(matches!(check_config(&bad), Err(DownloadError::Args(_))));
+    }
+
+    #[test]
+    fn user_agent_comes_after_host() {
+        let request = range_request(0, 99, &DownloadConfig::default());
+        assert!(
+            request.starts_with(
+                "GET / HTTP/1.1\r\nHost: 127.0.0.1:8080\r\nUser-Agent: http-client/0.1.0 (Rust)\r\n"
+            ),
+            "{request}"
+        );
+        let none = DownloadConfig {
+            user_agent: String::new(),
+            ..Default::default()
+        };
+        assert!(!range_request(0, 99, &none).contains("User-Agent"));
+        let bad = DownloadConfig {
+            user_agent: "curl/8.0\r\nX-Injected: yes".into(),
+            ..Default::default()
+        };
+        assert!(matches!(check_config(&bad), Err(DownloadError::Args(_))));
+    }
+
+    #[test]
+    fn extra_headers_go_in_every_request() {
+        use std::io::BufReader;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Answers the one request with 5 bytes and hands back exactly what it was sent
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut raw = Vec::new();
+            while !raw.ends_with(b"\r\n\r\n") {
+                reader.read_until(b'\n', &mut raw).unwrap();
+            }
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-4/5\r\nContent-Length: 5\r\n\r\nhello")
+                .unwrap();
+            raw
+        });
+        let config = DownloadConfig {
+            server_addr: addr.to_string(),
+            quiet: true,
+            extra_headers: vec![
+                ("X-Auth-Token".into(), "abc123".into()),
+                ("Authorization".into(), "Bearer x\r\nX-Injected: yes".into()),
+            ],
+            ..Default::default()
+        };
+        let (data, _) = download_full_data(5, &config).unwrap();
+        assert_eq!(data, b"hello");
+
+        let raw = String::from_utf8(server.join().unwrap()).unwrap();
+        // After the standard headers, with the CRLF taken out so nothing gets injected
+        assert!(
+            raw.ends_with(
+                "Connection: keep-alive\r\nX-Auth-Token: abc123\r\n\
+                 Authorization: Bearer xX-Injected: yes\r\n\r\n"
+            ),
+            "{raw}"
+        );
+
+        let bad = DownloadConfig {
+            extra_headers: vec![("Bad Name".into(), "x".into())],
+            ..Default::default()
+        };
+        assert!(matches!(check_config(&bad), Err(DownloadError::Args(_))));
+        assert!(matches!(
+            discover_total_size(&bad),
+            Err(DownloadError::Args(_))
+        ));
+    }
+
+    #[test]
+    fn proxied_request_line_has_the_whole_url() {
+        let config = DownloadConfig {
+            server_addr: "files.example.com:80".into(),
+            path: "/data.bin".into(),
+            proxy: Some("127.0.0.1:3128".parse().unwrap()),
+            ..Default::default()
+        };
+        let request = range_request(0, 99, &config);
+        assert!(
+            request.starts_with(
+                "GET http://files.example.com:80/data.bin HTTP/1.1\r\nHost: files.example.com:80\r\n"
+            ),
+            "{request}"
+        );
+        // Through a CONNECT tunnel it's talking to the server itself
+        let tunnelled = DownloadConfig {
+            use_tls: true,
+            ..config
+        };
+        assert!(range_request(0, 99, &tunnelled).starts_with("GET /data.bin HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn status_server_error() {
+        expect_server_error(
+            b"HTTP/1.0 500 Internal Server Error\r\nContent-Length: 22\r\n\r\n\
+              Internal Server Error\n",
+            500,
+            b"Internal Server Error\n",
+        );
+        // Only the start of a long one is kept
+        let mut raw = b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 10000\r\n\r\n".to_vec();
+        raw.extend(std::iter::repeat_n(b'x', 10_000));
+        expect_server_error(&raw, 503, &[b'x'; MAX_ERROR_BODY]);
+    }
+
+    #[test]
+    fn status_redirect() {
+        expect_status(
+            b"HTTP/1.1 301 Moved Permanently\r\nLocation: /new\r\n\r\n",
+            301,
+            "Moved Permanently",
+        );
+    }
+
+    #[test]
+    fn partial_content_body() {
+        let body =
+            parse_response(b"HTTP/1.0 206 Partial Content\r\nContent-Length: 5\r\n\r\nhello")
+                .unwrap();
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn keep_alive_reads_exactly_content_length() {
+        // Two responses back to back on the same connection
+        let raw = b"HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\n\r\nabc\
+                    HTTP/1.1 206 Partial Content\r\ncontent-length: 2\r\n\r\nde";
+        let mut reader = &raw[..];
+        let first = read_response(&mut reader).unwrap();
+        assert_eq!(first.body, b"abc");
+        assert!(first.keep_alive);
+        let second = read_response(&mut reader).unwrap();
+        assert_eq!(second.body, b"de");
+        assert!(reader.is_empty());
+    }
+
+    // Hands out each piece after its delay, a read never crosses into the next piece
+    struct Scripted(Vec<(Duration, Vec<u8>)>);
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((delay, piece)) = self.0.first_mut() else {
+                return Ok(0);
+            };
+            thread::sleep(std::mem::take(delay));
+            let n = buf.len().min(piece.len());
+            buf[..n].copy_from_slice(&piece[..n]);
+            piece.drain(..n);
+            if piece.is_empty() {
+                self.0.remove(0);
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn speed_is_just_the_body() {
+        // The ~3 KB head comes straight away, then the 2000 byte body takes 200ms
+        let head = format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Length: 2000\r\nX-Padding: {}\r\n\r\n",
+            "x".repeat(3000)
+        );
+        let mut reader = io::BufReader::new(Scripted(vec![
+            (Duration::ZERO, head.into_bytes()),
+            (Duration::from_millis(200), vec![b'b'; 2000]),
+        ]));
+        let headers = read_head(&mut reader).unwrap();
+        let response = read_body_timed(&mut reader, headers).unwrap();
+        assert_eq!(response.body.len(), 2000);
+        // 2000 bytes in at least 200ms is at most 10000/s however late the sleep wakes up,
+        // counting the head too would be more than twice that
+        let speed = response.bytes_per_sec.unwrap();
+        assert!(speed <= 10_000.0, "{speed}");
+    }
+
+    #[test]
+    fn connection_close_disables_reuse() {
+        let mut close = &b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 1\r\n\r\na"[..];
+        assert!(!read_response(&mut close).unwrap().keep_alive);
+
+        // HTTP/1.0 without an explicit keep-alive isn't persistent
+        let mut old = &b"HTTP/1.0 200 OK\r\nContent-Length: 1\r\n\r\na"[..];
+        assert!(!read_response(&mut old).unwrap().keep_alive);
+
+        // No Content-Length means reading until the server hangs up
+        let mut unframed = &b"HTTP/1.1 200 OK\r\n\r\nabc"[..];
+        let response = read_response(&mut unframed).unwrap();
+        assert_eq!(response.body, b"abc");
+        assert!(!response.keep_alive);
+    }
+
+    #[test]
+    fn garbled_pipeline_turns_it_off() {
+        use std::net::TcpListener;
+
+        // Gets the first response right and then loses track
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).unwrap();
+            socket
+                .write_all(
+                    b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-4/10\r\n\
+                      Content-Length: 5\r\n\r\nhelloGARBAGE\r\n\r\n",
+                )
+                .unwrap();
+        });
+
+        let config = DownloadConfig {
+            server_addr: addr.to_string(),
+            chunk_size: 5,
+            pipeline_depth: 2,
+            ..Default::default()
+        };
+        let attempts = AtomicU32::new(0);
+        let received = AtomicU64::new(0);
+        let headers_pending = AtomicBool::new(true);
+        let chunks = ChunkShared {
+            manifest: None,
+            attempts: &attempts,
+            received: &received,
+            headers_pending: &headers_pending,
+        };
+        let mut conn = Connection::new();
+        let mut stats = DownloadStats::default();
+        let ranges = next_ranges(0, 10, &config);
+        assert_eq!(ranges, [(0, 4), (5, 9)]);
+        let (bodies, server_pipelines) =
+            pipeline_chunks(&mut conn, &ranges, &config, &chunks, &mut stats).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(bodies, [b"hello"]);
+        assert!(!server_pipelines);
+        assert!(!conn.is_open());
+        assert_eq!((stats.chunks_attempted, stats.total_retries), (1, 1));
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        let mut truncated = &b"HTTP/1.1 206 Partial Content\r\nContent-Length: 10\r\n\r\nabc"[..];
+        match read_response(&mut truncated) {
+            Err(DownloadError::Parse(msg)) => {
+                assert_eq!(
+                    msg.to_string(),
+                    "Response body truncated: got 3 bytes, expected 10"
+                );
+            }
+            other => panic!("Expected Parse error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn size_from_head() {
+        let ok = parse_response_headers(b"HTTP/1.1 200 OK\r\nContent-Length: 646863\r\n\r\n");
+        assert_eq!(size_from_head_response(&ok.unwrap()), Some(646863));
+
+        // What the python server sends back for HEAD
+        let unsupported = parse_response_headers(
+            b"HTTP/1.0 501 Unsupported method ('HEAD')\r\nContent-Length: 497\r\n\r\n",
+        );
+        assert_eq!(size_from_head_response(&unsupported.unwrap()), None);
+    }
+
+    #[test]
+    fn size_from_range_fallback() {
+        let ranged = parse_response_headers(
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-0/646863\r\n\
+              Content-Length: 1\r\n\r\n",
+        );
+        assert_eq!(size_from_range_response(&ranged.unwrap()), Some(646863));
+
+        // Range ignored, whole thing sent back
+        let ignored = parse_response_headers(b"HTTP/1.1 200 OK\r\nContent-Length: 42\r\n\r\n");
+        assert_eq!(size_from_range_response(&ignored.unwrap()), Some(42));
+
+        // The python server: a 206 with no Content-Range is no use
+        let python =
+            parse_response_headers(b"HTTP/1.0 206 Partial Content\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(size_from_range_response(&python.unwrap()), None);
+    }
+
+    #[test]
+    fn throttle_delay_sleeps_off_the_difference() {
+        let limited = DownloadConfig {
+            bandwidth_limit_bytes_per_sec: Some(1000),
+            ..Default::default()
+        };
+        // 500 bytes at 1000/s is half a second, 100ms of it already gone
+        assert_eq!(
+            throttle_delay(500, Duration::from_millis(100), &limited),
+            Duration::from_millis(400)
+        );
+        // Already slower than the limit
+        assert_eq!(
+            throttle_delay(500, Duration::from_secs(1), &limited),
+            Duration::ZERO
+        );
+        assert_eq!(
+            throttle_delay(500, Duration::ZERO, &DownloadConfig::default()),
+            Duration::ZERO
+        );
+
+        let zero = DownloadConfig {
+            bandwidth_limit_bytes_per_sec: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(check_config(&zero), Err(DownloadError::Args(_))));
+    }
+
+    fn retry_config(max_retries: u32, base_ms: u64) -> DownloadConfig {
+        DownloadConfig {
+            max_retries,
+            retry_base_delay: Duration::from_millis(base_ms),
+            retry_max_delay: Duration::from_secs(1),
+            retry_jitter: false,
+            ..Default::default()
+        }
+    }
+
+    // retry_chunk for bytes 0-4 where every request fails with an IO error, except the
+    // succeed_on'th which gets the whole chunk. sent counts the requests
+    fn retry_once_succeeding(
+        config: &DownloadConfig,
+        succeed_on: Option<u32>,
+        sent: &mut u32,
+    ) -> Result<(Vec<u8>, Option<f64>), DownloadError> {
+        let attempts = AtomicU32::new(0);
+        let received = AtomicU64::new(0);
+        let headers_pending = AtomicBool::new(true);
+        let chunks = ChunkShared {
+            manifest: None,
+            attempts: &attempts,
+            received: &received,
+            headers_pending: &headers_pending,
+        };
+        let mut stats = DownloadStats::default();
+        retry_chunk(0, 4, config, &chunks, &mut stats, &mut |_| {}, || {
+            *sent += 1;
+            if Some(*sent) != succeed_on {
+                return Err(io::Error::other("connection reset").into());
+            }
+            let headers = parse_response_headers(
+                b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-4/5\r\n\
+                  Content-Length: 5\r\n\r\n",
+            )?;
+            Ok(Response {
+                headers,
+                body: b"hello".to_vec(),
+                keep_alive: true,
+                bytes_per_sec: None,
+            })
+        })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+        #[test]
+        fn retries_stop_at_max_retries(max_retries in 1u32..6, base_ms in 0u64..3) {
+            let config = retry_config(max_retries, base_ms);
+            let mut sent = 0;
+            let started = Instant::now();
+            let result = retry_once_succeeding(&config, None, &mut sent);
+            let elapsed = started.elapsed();
+
+            prop_assert!(
+                matches!(
+                    result,
+                    Err(DownloadError::RetriesExhausted { attempts, .. }) if attempts == max_retries
+                ),
+                "{:?}", result
+            );
+            prop_assert_eq!(sent, max_retries);
+            // Waits between attempts, never after the last
+            let waited: Duration =
+                (1..max_retries).map(|attempt| backoff_delay(attempt, &config)).sum();
+            prop_assert!(elapsed >= waited, "{:?} < {:?}", elapsed, waited);
+            prop_assert!(elapsed < waited + Duration::from_millis(500), "{:?}", elapsed);
+        }
+
+        #[test]
+        fn a_success_stops_the_retries(max_retries in 1u32..6, succeed_on in 1u32..6) {
+            let succeed_on = succeed_on.min(max_retries);
+            let config = retry_config(max_retries, 0);
+            let mut sent = 0;
+            let (body, _) = retry_once_succeeding(&config, Some(succeed_on), &mut sent).unwrap();
+            prop_assert_eq!(body, b"hello".to_vec());
+            prop_assert_eq!(sent, succeed_on);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn split_ranges_cover_everything_once(total_size in 0u64..1_000_000, chunk_size in 1u64..100_000) {
+            let ranges = split_ranges(total_size, chunk_size);
+            prop_assert_eq!(ranges.len() as u64, total_size.div_ceil(chunk_size));
+            let mut next = 0;
+            for &(start, end) in &ranges {
+                // Each one starts right after the last, so no gaps and no overlaps
+                prop_assert_eq!(start, next);
+                prop_assert!(start <= end);
+                prop_assert!(end - start < chunk_size);
+                next = end + 1;
+            }
+            prop_assert_eq!(next, total_size);
+        }
+
+        #[test]
+        fn split_ranges_only_the_last_is_short(total_size in 1u64..u64::MAX, chunk_size in 1u64..u64::MAX) {
+            let ranges = split_ranges(total_size.min(chunk_size.saturating_mul(64)), chunk_size);
+            let (last, full) = ranges.split_last().unwrap();
+            prop_assert!(full.iter().all(|&(start, end)| end - start + 1 == chunk_size));
+            prop_assert!(last.1 - last.0 < chunk_size);
+        }
+    }
+
+    #[test]
+    fn download_plan() {
+        let config = |chunk_size| DownloadConfig {
+            chunk_size,
+            ..Default::default()
+        };
+        let ranges = |total_size, chunk_size| plan_download(total_size, &config(chunk_size)).ranges;
+        assert_eq!(ranges(10, 4), vec![(0, 3), (4, 7), (8, 9)]);
+        assert_eq!(ranges(8, 4), vec![(0, 3), (4, 7)]);
+        assert_eq!(ranges(3, 4), vec![(0, 2)]);
+        assert!(ranges(0, 4).is_empty());
+        assert!(ranges(10, 0).is_empty());
+
+        // Says where it's from too
+        let plan = plan_download(10, &config(4));
+        assert_eq!(
+            (plan.total_size, plan.chunk_size, &*plan.server, &*plan.path),
+            (10, 4, DEFAULT_SERVER_ADDR, "/")
+        );
+
+        // Covers every byte exactly once with nothing bigger than a chunk
+        let plan = plan_download(694_259, &DownloadConfig::default()).ranges;
+        assert_eq!(plan.len(), 22);
+        assert_eq!(plan.first(), Some(&(0, 32767)));
+        assert_eq!(plan.last().map(|&(_, end)| end), Some(694_258));
+        for pair in plan.windows(2) {
+            assert_eq!(pair[0].1 + 1, pair[1].0);
+        }
+        assert!(plan
+            .iter()
+            .all(|&(start, end)| end - start < DownloadConfig::default().chunk_size));
+
+        // No overflow right at the top of the range
+        assert_eq!(ranges(u64::MAX, u64::MAX), vec![(0, u64::MAX - 1)]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn plan_round_trips_through_json() {
+        let config = DownloadConfig {
+            chunk_size: 4,
+            ..Default::default()
+        };
+        let plan = plan_download(10, &config);
+        let json = serde_json::to_string(&plan).unwrap();
+        assert!(json.contains(r#""ranges":[[0,3],[4,7],[8,9]]"#), "{json}");
+        assert_eq!(serde_json::from_str::<DownloadPlan>(&json).unwrap(), plan);
+    }
+
+    #[test]
+    fn short_writes_are_caught() {
+        // Takes 3 bytes and then nothing, like a full disk that doesn't say so
+        struct Full(usize);
+        impl Write for Full {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let n = buf.len().min(3 - self.0);
+                self.0 += n;
+                Ok(n)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let written = write_counted(&mut Full(0), b"hello").unwrap();
+        assert_eq!(written, 3);
+        assert!(matches!(
+            verify_write_result(5, written, 100),
+            Err(DownloadError::PartialWrite {
+                written: 103,
+                expected: 105
+            })
+        ));
+        assert_eq!(write_counted(&mut Vec::new(), b"hello").unwrap(), 5);
+        assert!(verify_write_result(5, 5, 100).is_ok());
+    }
+
+    #[test]
+    fn buffer_completeness() {
+        let buf = [1u8; 10];
+        let check = |ranges: &[(u64, u64)]| match verify_buffer_completeness(&buf, ranges, 10) {
+            Ok(()) => None,
+            Err(DownloadError::RangeMismatch { gap_start, gap_end }) => Some((gap_start, gap_end)),
+            Err(e) => panic!("{e:?}"),
+        };
+        assert_eq!(check(&[(0, 3), (4, 7), (8, 9)]), None);
+        assert_eq!(check(&[(8, 9), (0, 3), (4, 7)]), None);
+        assert_eq!(check(&[(0, 9), (5, 4)]), None);
+        // Gaps at the start, in the middle and at the end
+        assert_eq!(check(&[(2, 9)]), Some((0, 1)));
+        assert_eq!(check(&[(0, 3), (6, 9)]), Some((4, 5)));
+        assert_eq!(check(&[(0, 7)]), Some((8, 9)));
+        assert_eq!(check(&[]), Some((0, 9)));
+        // Overlaps and running off the end
+        assert_eq!(check(&[(0, 5), (4, 9)]), Some((4, 5)));
+        assert_eq!(check(&[(0, 9), (0, 9)]), Some((0, 9)));
+        assert_eq!(check(&[(0, 11)]), Some((10, 11)));
+        // A buffer too short for the file
+        assert!(matches!(
+            verify_buffer_completeness(&buf[..8], &[(0, 9)], 10),
+            Err(DownloadError::RangeMismatch {
+                gap_start: 8,
+                gap_end: 9
+            })
+        ));
+        assert!(verify_buffer_completeness(&[], &[], 0).is_ok());
+    }
+
+    #[test]
+    fn content_range_decides_chunk_length() {
+        let range = |start, end| ContentRange {
+            start,
+            end,
+            total: Some(1000),
+        };
+
+        // Got exactly what was asked for
+        let body = body_for_range(10, 14, range(10, 14), b"abcde".to_vec()).unwrap();
+        assert_eq!(body, b"abcde");
+
+        // Server sent less, keep all of it
+        let body = body_for_range(10, 19, range(10, 12), b"abc".to_vec()).unwrap();
+        assert_eq!(body, b"abc");
+
+        // A correct server sends the extra byte we ask for, drop it
+        let body = body_for_range(10, 12, range(10, 13), b"abcd".to_vec()).unwrap();
+        assert_eq!(body, b"abc");
+
+        assert!(matches!(
+            body_for_range(10, 19, range(11, 13), b"abc".to_vec()),
+            Err(DownloadError::Parse(_))
+        ));
+        assert!(matches!(
+            body_for_range(10, 19, range(10, 13), b"abc".to_vec()),
+            Err(DownloadError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn range_response_has_to_match_the_request() {
+        let range = |start, end| {
+            Some(ContentRange {
+                start,
+                end,
+                total: None,
+            })
+        };
+        let message = |result: Result<(), DownloadError>| match result {
+            Err(DownloadError::Parse(msg)) => msg.to_string(),
+            other => panic!("Expected a Parse error, got {other:?}"),
+        };
+
+        assert!(validate_range_response((100, 199), range(100, 199), 100).is_ok());
+        // Short is fine, the rest gets asked for again
+        assert!(validate_range_response((100, 199), range(100, 149), 50).is_ok());
+        assert_eq!(
+            message(validate_range_response((100, 199), range(0, 99), 100)),
+            "Range mismatch: asked for bytes 100-199 but server sent 0-99"
+        );
+        assert_eq!(
+            message(validate_range_response((100, 199), range(100, 299), 200)),
+            "Range mismatch: asked for bytes 100-199 but server sent 100-299"
+        );
+        assert_eq!(
+            message(validate_range_response((100, 199), range(100, 199), 99)),
+            "Range mismatch: Content-Range says 100 bytes but body has 99"
+        );
+
+        assert!(validate_range_response((100, 199), None, 100).is_ok());
+        assert!(validate_range_response((100, 199), None, 10).is_ok());
+        assert_eq!(
+            message(validate_range_response((100, 199), None, 1000)),
+            "Range mismatch: asked for 100 bytes but body has 1000"
+        );
+    }
+
+    #[test]
+    fn extra_byte_without_a_content_range_gets_cut_off() {
+        let attempts = AtomicU32::new(0);
+        let received = AtomicU64::new(0);
+        let headers_pending = AtomicBool::new(false);
+        let chunks = ChunkShared {
+            manifest: None,
+            attempts: &attempts,
+            received: &received,
+            headers_pending: &headers_pending,
+        };
+        let config = DownloadConfig::default();
+        let mut stats = DownloadStats::default();
+        let response = |body: &[u8]| {
+            Ok(Response {
+                headers: parse_response_headers(b"HTTP/1.1 206 Partial Content\r\n\r\n")?,
+                body: body.to_vec(),
+                keep_alive: true,
+                bytes_per_sec: None,
+            })
+        };
+
+        // Bytes 0-4 go out as Range: bytes=0-5, so a server that honours it sends 6
+        match judge_attempt(0, 4, 1, &config, &chunks, response(b"hello!"), &mut stats) {
+            Attempt::Done(body) => assert_eq!(body, b"hello"),
+            Attempt::Retry(e) | Attempt::GiveUp(e) => panic!("Expected the chunk, got {e:?}"),
+        }
+        match judge_attempt(0, 4, 1, &config, &chunks, response(b"hello!!"), &mut stats) {
+            Attempt::Retry(DownloadError::Parse(msg)) => assert_eq!(
+                msg.to_string(),
+                "Range mismatch: asked for 6 bytes but body has 7"
+            ),
+            Attempt::Done(_) => panic!("Expected a Range mismatch, got the chunk"),
+            Attempt::Retry(e) | Attempt::GiveUp(e) => {
+                panic!("Expected a Range mismatch, got {e:?}")
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_status_line() {
+        assert!(matches!(
+            parse_response(b"HTTP/1.1 2x6 Nope\r\n\r\n"),
+            Err(DownloadError::Parse(_))
+        ));
+        assert!(matches!(
+            parse_response(b"SPDY/3 200 OK\r\n\r\n"),
+            Err(DownloadError::Parse(_))
+        ));
     }
 }
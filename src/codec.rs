@@ -0,0 +1,467 @@
+use std::io::{BufRead, Read};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+use crate::error::DownloadError;
+use crate::headers::ContentRange;
+
+// Longest chunk size or trailer line we'll put up with, a real one is a few bytes
+pub(crate) const MAX_LINE_SIZE: u64 = 8 * 1024;
+
+// Undoes Transfer-Encoding: chunked (RFC 9112 section 7.1). Every chunk is its size in hex on a
+// line of its own (possibly with ;extensions after it), that many bytes of data, then CRLF. A
+// chunk of size 0 ends the body, followed by optional trailer lines and a blank line.
+// raw starts straight after the response headers, anything after the blank line is ignored
+pub fn decode_chunked_body(mut raw: &[u8]) -> Result<Vec<u8>, DownloadError> {
+    read_chunked_body(&mut raw)
+}
+
+// decode_chunked_body straight off a connection, stops right after the final blank line so the
+// connection can carry on with the next response
+pub(crate) fn read_chunked_body<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, DownloadError> {
+    let mut body = Vec::new();
+    loop {
+        let size = parse_size_line(&read_line(reader)?)?;
+        if size == 0 {
+            break;
+        }
+        let read = reader.take(size).read_to_end(&mut body)?;
+        if (read as u64) < size {
+            return Err(chunked_truncated());
+        }
+        if !read_line(reader)?.is_empty() {
+            return Err(DownloadError::Parse(
+                "Chunk data is longer than its size line says".into(),
+            ));
+        }
+    }
+    // Trailers, nothing we need from them
+    while !read_line(reader)?.is_empty() {}
+    Ok(body)
+}
+
+// One CRLF terminated line without the CRLF. A bare LF is accepted too
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, DownloadError> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_SIZE).read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\n") {
+        return Err(if line.len() as u64 == MAX_LINE_SIZE {
+            DownloadError::Parse(
+                format!("Chunked body line is longer than {MAX_LINE_SIZE} bytes").into(),
+            )
+        } else {
+            chunked_truncated()
+        });
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(line)
+}
+
+// "1a3f" or "1a3f;name=value", the extensions don't mean anything to us
+pub(crate) fn parse_size_line(line: &[u8]) -> Result<u64, DownloadError> {
+    let invalid = || {
+        DownloadError::Parse(
+            format!(
+                "Invalid chunk size line: {:?}",
+                String::from_utf8_lossy(line)
+            )
+            .into(),
+        )
+    };
+    let line = std::str::from_utf8(line).map_err(|_| invalid())?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    u64::from_str_radix(size, 16).map_err(|_| invalid())
+}
+
+pub(crate) fn chunked_truncated() -> DownloadError {
+    DownloadError::Parse("Chunked body ended before the final chunk".into())
+}
+
+// Undoes Content-Encoding, which unlike Transfer-Encoding is about the data itself. gzip and
+// deflate are all we ask for. deflate is meant to be zlib wrapped but plenty of servers send it
+// raw, so that gets tried when the zlib header's wrong. Several codings are undone last first
+pub fn decode_content(encoding: &str, body: Vec<u8>) -> Result<Vec<u8>, DownloadError> {
+    let mut body = body;
+    for coding in encoding.rsplit(',').map(str::trim) {
+        body = match coding.to_ascii_lowercase().as_str() {
+            "" | "identity" => body,
+            "gzip" | "x-gzip" => decompress(GzDecoder::new(body.as_slice()), coding)?,
+            "deflate" => match decompress(ZlibDecoder::new(body.as_slice()), coding) {
+                Ok(decoded) => decoded,
+                Err(_) => decompress(DeflateDecoder::new(body.as_slice()), coding)?,
+            },
+            other => {
+                return Err(DownloadError::Parse(
+                    format!("Unsupported Content-Encoding: {other}").into(),
+                ))
+            }
+        };
+    }
+    Ok(body)
+}
+
+fn decompress(mut decoder: impl Read, coding: &str) -> Result<Vec<u8>, DownloadError> {
+    let mut decoded = Vec::new();
+    decoder.read_to_end(&mut decoded).map_err(|e| {
+        DownloadError::Parse(format!("Could not decompress {coding} body: {e}").into())
+    })?;
+    Ok(decoded)
+}
+
+// The boundary out of a Content-Type of multipart/byteranges; boundary=THIS_SEPARATES, quoted
+// or not. None for any other Content-Type
+#[must_use]
+pub fn byteranges_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';').map(str::trim);
+    if !params.next()?.eq_ignore_ascii_case("multipart/byteranges") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_owned())
+            .filter(|boundary| !boundary.is_empty())
+    })
+}
+
+// Splits a multipart/byteranges body (RFC 9110 section 14.6) into each part's Content-Range and
+// data, see ContentRangeIterator for the format. Keeps the Content-Range as is so a total of *
+// is fine here
+pub fn parse_byteranges(
+    body: &[u8],
+    boundary: &str,
+) -> Result<Vec<(ContentRange, Vec<u8>)>, DownloadError> {
+    let mut parts = ContentRangeIterator::new(body, boundary);
+    let mut out = Vec::new();
+    while let Some(part) = parts.next_part() {
+        let (range, data) = part?;
+        out.push((range, data.to_vec()));
+    }
+    Ok(out)
+}
+
+// Goes through a multipart/byteranges body one part at a time giving (start, end, total, data),
+// without copying any of it. Every part is --boundary on a line of its own, the part's headers
+// (Content-Range and usually Content-Type, which is the whole file's type so it's skipped), a
+// blank line and then exactly as many bytes as its Content-Range covers. The boundary is matched
+// ignoring case. An empty part, no Content-Range and nothing before the next boundary, covers no
+// bytes so it gets skipped. Whatever comes before the first boundary and after the closing
+// --boundary-- is ignored. After an error there's nothing more
+#[derive(Debug, Clone)]
+pub struct ContentRangeIterator<'a> {
+    rest: &'a [u8],
+    boundary: String,
+    // Past the preamble
+    started: bool,
+    // Read the closing boundary or hit an error
+    finished: bool,
+}
+
+impl<'a> ContentRangeIterator<'a> {
+    // boundary is what byteranges_boundary got out of the Content-Type
+    #[must_use]
+    pub fn new(body: &'a [u8], boundary: &str) -> Self {
+        Self {
+            rest: body,
+            boundary: boundary.to_owned(),
+            started: false,
+            finished: false,
+        }
+    }
+
+    // The next part with its Content-Range as it was, None after the last one
+    pub(crate) fn next_part(&mut self) -> Option<Result<(ContentRange, &'a [u8]), DownloadError>> {
+        if self.finished {
+            return None;
+        }
+        let part = self.read_part();
+        if !matches!(part, Ok(Some(_))) {
+            self.finished = true;
+        }
+        part.transpose()
+    }
+
+    fn read_part(&mut self) -> Result<Option<(ContentRange, &'a [u8])>, DownloadError> {
+        // Preamble, usually nothing
+        while !self.started {
+            let line = take_line(&mut self.rest).ok_or_else(|| malformed("No boundary line"))?;
+            self.started = self.boundary_line(line) == Some(false);
+        }
+        loop {
+            let mut range = None;
+            loop {
+                let line =
+                    take_line(&mut self.rest).ok_or_else(|| malformed("Part headers cut off"))?;
+                if line.is_empty() {
+                    break;
+                }
+                let line = std::str::from_utf8(line)?;
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.trim().eq_ignore_ascii_case("content-range") {
+                        range = ContentRange::parse(value)?;
+                    }
+                }
+            }
+            let len = match range {
+                Some(range) => usize::try_from(range.byte_len())?,
+                None => 0,
+            };
+            if self.rest.len() < len {
+                return Err(malformed("Part data cut off"));
+            }
+            let (data, after) = self.rest.split_at(len);
+            self.rest = after;
+
+            // The CRLF after the data belongs to the next boundary line
+            if take_line(&mut self.rest).is_none_or(|line| !line.is_empty()) {
+                return Err(malformed(if range.is_some() {
+                    "Part data is longer than its Content-Range says"
+                } else {
+                    "Part without a Content-Range"
+                }));
+            }
+            let line = take_line(&mut self.rest).ok_or_else(|| malformed("No closing boundary"))?;
+            let closing = self
+                .boundary_line(line)
+                .ok_or_else(|| malformed("Expected a boundary after the part data"))?;
+            self.finished = closing;
+            match range {
+                Some(range) => return Ok(Some((range, data))),
+                None if closing => return Ok(None),
+                None => {}
+            }
+        }
+    }
+
+    // Some(false) for --boundary, Some(true) for the closing --boundary--, None for anything else
+    fn boundary_line(&self, line: &[u8]) -> Option<bool> {
+        let rest = line.trim_ascii_end().strip_prefix(b"--")?;
+        let after = rest.get(self.boundary.len()..)?;
+        if !rest[..self.boundary.len()].eq_ignore_ascii_case(self.boundary.as_bytes()) {
+            return None;
+        }
+        match after {
+            b"" => Some(false),
+            b"--" => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Iterator for ContentRangeIterator<'a> {
+    type Item = Result<(u64, u64, u64, &'a [u8]), DownloadError>;
+
+    // A part whose Content-Range total is * is an error here, there's no total to give back
+    fn next(&mut self) -> Option<Self::Item> {
+        let part = self.next_part()?.and_then(|(range, data)| {
+            let total = range
+                .total
+                .ok_or_else(|| malformed("Part's Content-Range has no total"))?;
+            Ok((range.start, range.end, total, data))
+        });
+        if part.is_err() {
+            self.finished = true;
+        }
+        Some(part)
+    }
+}
+
+fn malformed(why: &str) -> DownloadError {
+    DownloadError::Parse(format!("Malformed multipart/byteranges body: {why}").into())
+}
+
+// Next line of rest without its CRLF (or bare LF), None once there's no complete line left
+fn take_line<'a>(rest: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let end = rest.iter().position(|&b| b == b'\n')?;
+    let line = &rest[..end];
+    *rest = &rest[end + 1..];
+    Some(line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder, ZlibEncoder},
+        Compression,
+    };
+
+    use super::*;
+
+    #[test]
+    fn decodes_chunks() {
+        let raw = b"4\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\n\r\n";
+        assert_eq!(
+            decode_chunked_body(raw).unwrap(),
+            b"Wikipedia in\r\n\r\nchunks."
+        );
+        // Extensions, trailers and whatever comes after are all skipped
+        let raw = b"3;name=value\r\nabc\r\n0\r\nExpires: never\r\n\r\nHTTP/1.1 200 OK";
+        assert_eq!(decode_chunked_body(raw).unwrap(), b"abc");
+        assert_eq!(decode_chunked_body(b"0\r\n\r\n").unwrap(), b"");
+        // Bare LF instead of CRLF and upper case hex
+        assert_eq!(
+            decode_chunked_body(b"A\n0123456789\n0\n\n").unwrap(),
+            b"0123456789"
+        );
+    }
+
+    #[test]
+    fn leaves_the_next_response_alone() {
+        let mut reader = &b"2\r\nhi\r\n0\r\n\r\nnext"[..];
+        assert_eq!(read_chunked_body(&mut reader).unwrap(), b"hi");
+        assert_eq!(reader, b"next");
+    }
+
+    #[test]
+    fn rejects_broken_bodies() {
+        for raw in [
+            &b""[..],
+            b"5\r\nabc",
+            b"5\r\nabc\r\n",
+            b"3\r\nabcdef\r\n0\r\n\r\n",
+            b"zz\r\nabc\r\n0\r\n\r\n",
+            b"\r\nabc\r\n0\r\n\r\n",
+            b"3\r\nabc\r\n",
+            b"3\r\nabc\r\n0\r\n",
+            b"ffffffffffffffffff\r\n",
+        ] {
+            assert!(
+                matches!(decode_chunked_body(raw), Err(DownloadError::Parse(_))),
+                "{:?}",
+                String::from_utf8_lossy(raw)
+            );
+        }
+    }
+
+    #[test]
+    fn boundary_from_content_type() {
+        assert_eq!(
+            byteranges_boundary("multipart/byteranges; boundary=3d6b6a416f9b5").as_deref(),
+            Some("3d6b6a416f9b5")
+        );
+        assert_eq!(
+            byteranges_boundary("Multipart/ByteRanges;charset=x; BOUNDARY=\"a b\"").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(byteranges_boundary("text/plain; boundary=x"), None);
+        assert_eq!(byteranges_boundary("multipart/byteranges"), None);
+    }
+
+    #[test]
+    fn splits_byteranges() {
+        // The example from RFC 9110, with a bare LF line in there for good measure
+        let body = b"\r\n--THIS_STRING_SEPARATES\r\n\
+            Content-Type: application/pdf\r\n\
+            Content-Range: bytes 500-504/8000\r\n\r\n\
+            hello\r\n\
+            --THIS_STRING_SEPARATES\n\
+            Content-Range: bytes 7000-7001/8000\r\n\r\n\
+            \r\n\r\n\
+            --THIS_STRING_SEPARATES--\r\nepilogue";
+        let parts = parse_byteranges(body, "THIS_STRING_SEPARATES").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!((parts[0].0.start, parts[0].0.end), (500, 504));
+        assert_eq!(parts[0].1, b"hello");
+        assert_eq!((parts[1].0.start, parts[1].0.end), (7000, 7001));
+        assert_eq!(parts[1].1, b"\r\n");
+
+        for bad in [
+            &b""[..],
+            b"--B\r\nContent-Range: bytes 0-4/10\r\n\r\nhel",
+            b"--B\r\nContent-Range: bytes 0-4/10\r\n\r\nhello world\r\n--B--\r\n",
+            b"--B\r\nContent-Type: text/plain\r\n\r\nhello\r\n--B--\r\n",
+            b"--B\r\nContent-Range: bytes 0-4/10\r\n\r\nhello\r\n--C--\r\n",
+            b"--B\r\nContent-Range: bytes 0-4/10\r\n\r\nhello\r\n",
+        ] {
+            assert!(
+                matches!(parse_byteranges(bad, "B"), Err(DownloadError::Parse(_))),
+                "{:?}",
+                String::from_utf8_lossy(bad)
+            );
+        }
+    }
+
+    #[test]
+    fn iterates_byteranges() {
+        let body = b"--B\r\n\
+            Content-Type: application/octet-stream\r\n\
+            Content-Range: bytes 0-4/20\r\n\r\n\
+            hello\r\n\
+            --b\r\n\
+            Content-Range: bytes 15-19/20\r\n\r\n\
+            world\r\n\
+            --B--\r\n";
+        let parts = ContentRangeIterator::new(body, "B")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            parts,
+            [(0, 4, 20, &b"hello"[..]), (15, 19, 20, &b"world"[..])]
+        );
+
+        // A last part with nothing in it covers no bytes
+        let body = b"--B\r\n\
+            Content-Range: bytes 0-4/20\r\n\r\n\
+            hello\r\n\
+            --B\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\
+            \r\n\
+            --B--\r\n";
+        let parts = ContentRangeIterator::new(body, "b")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(parts, [(0, 4, 20, &b"hello"[..])]);
+        assert_eq!(parse_byteranges(body, "B").unwrap().len(), 1);
+
+        // Without a total there's nothing to put in the triple, and it stops there
+        let mut parts = ContentRangeIterator::new(
+            b"--B\r\nContent-Range: bytes 0-4/*\r\n\r\nhello\r\n--B\r\n\
+              Content-Range: bytes 5-9/*\r\n\r\nworld\r\n--B--\r\n",
+            "B",
+        );
+        assert!(matches!(parts.next(), Some(Err(DownloadError::Parse(_)))));
+        assert!(parts.next().is_none());
+    }
+
+    #[test]
+    fn decodes_content() {
+        let data = b"the same thing over and over, the same thing over and over".to_vec();
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&data).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&data).unwrap();
+        let zlib = zlib.finish().unwrap();
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(&data).unwrap();
+        let raw = raw.finish().unwrap();
+
+        assert_eq!(decode_content("gzip", gzip.clone()).unwrap(), data);
+        assert_eq!(decode_content("X-GZIP", gzip).unwrap(), data);
+        assert_eq!(decode_content("deflate", zlib.clone()).unwrap(), data);
+        assert_eq!(decode_content("deflate", raw).unwrap(), data);
+        assert_eq!(decode_content("identity", data.clone()).unwrap(), data);
+
+        // deflate then gzip over the top
+        let mut both = GzEncoder::new(Vec::new(), Compression::default());
+        both.write_all(&zlib).unwrap();
+        let both = both.finish().unwrap();
+        assert_eq!(decode_content("deflate, gzip", both).unwrap(), data);
+
+        for (encoding, body) in [("gzip", data.clone()), ("br", data.clone())] {
+            assert!(
+                matches!(decode_content(encoding, body), Err(DownloadError::Parse(_))),
+                "{encoding}"
+            );
+        }
+    }
+}
@@ -0,0 +1,152 @@
+// A download spec in TOML for the binary's --config, so a batch job doesn't have to spell every
+// flag out each time. Everything's optional, whatever's left out stays at the flag's default and
+// any flag given on the command line wins over the file:
+//
+// [server]
+// address = "127.0.0.1:8080"
+// path = "/files/data.bin"
+// use_tls = false
+//
+// [download]
+// chunk_size = 65536
+// max_retries = 20
+// output = "data.bin"
+//
+// [verification]
+// algorithm = "sha256"
+// expected_hash = "986f52d9..."
+//
+// Values are checked the same way the flags are, that happens when the binary applies them. Keys
+// it doesn't know are an error rather than quietly ignored, a typo shouldn't mean a default
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::error::DownloadError;
+use crate::sha::HashAlgorithm;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSection,
+    pub download: DownloadSection,
+    pub verification: VerificationSection,
+}
+
+// Where to download from, --server, --path, --tls and --proxy
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    // host:port like --server
+    pub address: Option<String>,
+    pub path: Option<String>,
+    pub use_tls: Option<bool>,
+    pub proxy: Option<SocketAddr>,
+}
+
+// How to download it, named after the flags. The *_ms ones are milliseconds like the flags take
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadSection {
+    pub chunk_size: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub attempts: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+    pub connect_timeout_ms: Option<u64>,
+    pub read_timeout_ms: Option<u64>,
+    pub concurrency: Option<usize>,
+    pub pipeline: Option<usize>,
+    pub compressed: Option<bool>,
+    pub follow_redirects: Option<bool>,
+    pub output: Option<PathBuf>,
+}
+
+// What the download gets checked against, --hash-algo and the expected hash positional
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerificationSection {
+    pub algorithm: Option<HashAlgorithm>,
+    pub expected_hash: Option<String>,
+}
+
+// A file that isn't there is the Io error from reading it
+pub fn load_config(path: &Path) -> Result<Config, DownloadError> {
+    parse_config(&fs::read_to_string(path)?)
+}
+
+pub fn parse_config(text: &str) -> Result<Config, DownloadError> {
+    toml::from_str(text)
+        .map_err(|e| DownloadError::Parse(format!("Invalid config file: {e}").into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_section() {
+        let config = parse_config(
+            r#"
+            [server]
+            address = "files.example.com:443"
+            path = "/files/data.bin"
+            use_tls = true
+
+            [download]
+            chunk_size = 65536
+            max_retries = 20
+            read_timeout_ms = 5000
+
+            [verification]
+            algorithm = "SHA-512"
+            expected_hash = "abc123"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                server: ServerSection {
+                    address: Some("files.example.com:443".into()),
+                    path: Some("/files/data.bin".into()),
+                    use_tls: Some(true),
+                    proxy: None,
+                },
+                download: DownloadSection {
+                    chunk_size: Some(65536),
+                    max_retries: Some(20),
+                    read_timeout_ms: Some(5000),
+                    ..Default::default()
+                },
+                verification: VerificationSection {
+                    algorithm: Some(HashAlgorithm::Sha512),
+                    expected_hash: Some("abc123".into()),
+                },
+            }
+        );
+        assert_eq!(parse_config("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn bad_config_files() {
+        for text in [
+            "[server]\nadress = \"127.0.0.1:8080\"",
+            "[download]\nchunk_size = \"big\"",
+            "[verification]\nalgorithm = \"md5\"",
+            "[server",
+        ] {
+            assert!(
+                matches!(parse_config(text), Err(DownloadError::Parse(_))),
+                "{text}"
+            );
+        }
+        assert!(matches!(
+            load_config(Path::new("/nonexistent/glitchy-http.toml")),
+            Err(DownloadError::Io(_))
+        ));
+    }
+}
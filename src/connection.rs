@@ -0,0 +1,907 @@
+#[cfg(feature = "tls")]
+use std::sync::{Arc, OnceLock};
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use log::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+#[cfg(feature = "tls")]
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    StreamOwned,
+};
+
+use crate::client::{head_complete, MAX_HEAD_SIZE};
+#[cfg(feature = "quic")]
+use crate::config::Transport;
+use crate::config::{AddrFamily, DownloadConfig};
+use crate::error::{ConnectError, DownloadError};
+use crate::headers::parse_response_headers;
+#[cfg(feature = "quic")]
+use crate::quic::{QuicSession, QuicStream};
+use crate::socks5;
+
+// A single persistent connection that gets reused across chunk requests. It's lazily opened on
+// first use and dropped whenever something goes wrong, the next request just opens a fresh one
+#[derive(Debug, Default)]
+pub struct Connection {
+    stream: Option<BufReader<Stream>>,
+    // Whichever address worked last time goes first next time, so a broken IPv6 route only costs
+    // one connect timeout rather than one per reconnect
+    last_addr: Option<SocketAddr>,
+    keep_alive: KeepAlive,
+    // With Transport::Quic, the QUIC connection streams get opened on. Outlives close() so a
+    // fresh stream doesn't mean a fresh handshake
+    #[cfg(feature = "quic")]
+    quic: Option<QuicSession>,
+}
+
+impl Connection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Whether the next request would go over an already open connection
+    pub fn is_open(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    // Hands out the open stream, connecting first if there isn't one or it's been idle too long
+    pub fn stream(
+        &mut self,
+        config: &DownloadConfig,
+    ) -> Result<&mut BufReader<Stream>, DownloadError> {
+        if self.stream.is_some() && self.keep_alive.idle_too_long(config) {
+            self.close();
+        }
+        if self.stream.is_none() {
+            #[cfg(feature = "quic")]
+            if config.transport == Transport::Quic {
+                let stream = self.quic_stream(config)?;
+                self.stream = Some(BufReader::new(Stream::Quic(Box::new(stream))));
+                return Ok(self.stream.as_mut().expect("stream was just opened"));
+            }
+            let (stream, addr) = connect(config, self.last_addr)?;
+            self.stream = Some(BufReader::new(stream));
+            self.last_addr = Some(addr);
+        }
+        Ok(self.stream.as_mut().expect("stream was just opened"))
+    }
+
+    // A new stream on the QUIC connection that's already up, only connecting again if the server
+    // closed it or it went idle for too long
+    #[cfg(feature = "quic")]
+    fn quic_stream(&mut self, config: &DownloadConfig) -> Result<QuicStream, DownloadError> {
+        let session = match self.quic.take() {
+            Some(session) if session.is_alive() => session,
+            _ => {
+                let (session, addr) = QuicSession::connect(config, self.last_addr)?;
+                self.last_addr = Some(addr);
+                session
+            }
+        };
+        let stream = session.open_stream(config);
+        self.quic = Some(session);
+        stream
+    }
+
+    // A response came back in full and the stream's good for another, unless that was the last
+    // one keepalive_max_requests allows
+    pub fn finished_request(&mut self, config: &DownloadConfig) {
+        if self.keep_alive.finished_request(config) {
+            self.close();
+        }
+    }
+
+    // Requests the next stream can take before it gets closed for keepalive_max_requests
+    pub fn requests_left(&self, config: &DownloadConfig) -> u32 {
+        self.keep_alive.requests_left(config)
+    }
+
+    pub fn close(&mut self) {
+        self.stream = None;
+        self.keep_alive = KeepAlive::default();
+    }
+}
+
+// How much a kept alive connection has been used, restarted with every new connection. Shared
+// with the async client's connection
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct KeepAlive {
+    requests: u32,
+    last_used: Option<Instant>,
+}
+
+impl KeepAlive {
+    // True once every request the connection's allowed is used up, ie time to close it
+    pub(crate) fn finished_request(&mut self, config: &DownloadConfig) -> bool {
+        self.requests += 1;
+        self.last_used = Some(Instant::now());
+        self.requests >= config.keepalive_max_requests
+    }
+
+    pub(crate) fn idle_too_long(&self, config: &DownloadConfig) -> bool {
+        self.last_used
+            .is_some_and(|at| at.elapsed() > config.keepalive_idle_timeout)
+    }
+
+    pub(crate) fn requests_left(&self, config: &DownloadConfig) -> u32 {
+        config.keepalive_max_requests.saturating_sub(self.requests)
+    }
+}
+
+// Plain TCP or TLS on top of it, either way it's just bytes in and out for the caller
+#[derive(Debug)]
+pub enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+    #[cfg(feature = "quic")]
+    Quic(Box<QuicStream>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => s.read(buf),
+            #[cfg(feature = "quic")]
+            Stream::Quic(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => s.write(buf),
+            #[cfg(feature = "quic")]
+            Stream::Quic(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => s.flush(),
+            #[cfg(feature = "quic")]
+            Stream::Quic(s) => s.flush(),
+        }
+    }
+}
+
+fn connect(
+    config: &DownloadConfig,
+    preferred: Option<SocketAddr>,
+) -> Result<(Stream, SocketAddr), DownloadError> {
+    let (mut stream, addr) = connect_any(&candidates(config, preferred)?, config)?;
+    stream.set_read_timeout(Some(config.read_timeout))?;
+    stream.set_write_timeout(Some(config.connect_timeout))?;
+    // Nagle's algorithm holds a small write back until the last one's been ACKed, and with the
+    // server delaying its ACK a request can sit there ~40ms, which adds up with 4-8 KiB chunks.
+    // Turning it off sends every write straight away for lower latency per request, at the cost
+    // of more small packets and a bit more CPU, so it's opt in
+    if config.tcp_nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if config.socks5_proxy.is_some() {
+        socks5::connect(&mut stream, config)?;
+    }
+    if !config.use_tls {
+        return Ok((Stream::Plain(stream), addr));
+    }
+    if config.proxy.is_some() {
+        open_tunnel(&mut stream, config)?;
+    }
+    tls_handshake(stream, config)
+        .map(|stream| (stream, addr))
+        .map_err(|e| e.with_timeout(config.read_timeout))
+}
+
+// Every address worth trying, in the order to try them. Hostnames need resolving, an IP just
+// comes straight back out. A dual stack host gives back both families. With a proxy it's the
+// proxy that has to find the server
+pub(crate) fn candidates(
+    config: &DownloadConfig,
+    preferred: Option<SocketAddr>,
+) -> Result<Vec<SocketAddr>, DownloadError> {
+    let mut candidates: Vec<SocketAddr> = match config.proxy.or(config.socks5_proxy) {
+        Some(proxy) => vec![proxy],
+        None => config
+            .server_addr
+            .to_socket_addrs()
+            .map(Iterator::collect)
+            .unwrap_or_default(),
+    };
+    if candidates.is_empty() {
+        return Err(DownloadError::Network(
+            format!("Could not resolve {}", config.server_addr).into(),
+        ));
+    }
+    filter_family(&mut candidates, config)?;
+    order_candidates(&mut candidates, preferred);
+    Ok(candidates)
+}
+
+// Asks the proxy for a raw connection to server_addr, once it says 200 everything after is
+// between us and the server. The response is read a byte at a time since anything past its
+// blank line would already be the server's side of the TLS handshake
+fn open_tunnel(stream: &mut TcpStream, config: &DownloadConfig) -> Result<(), DownloadError> {
+    let target = &config.server_addr;
+    stream
+        .write_all(format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").as_bytes())
+        .map_err(|e| DownloadError::from(e).with_timeout(config.connect_timeout))?;
+
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head_complete(&head) {
+        let read = stream
+            .read(&mut byte)
+            .map_err(|e| DownloadError::from(e).with_timeout(config.read_timeout))?;
+        if read == 0 {
+            return Err(DownloadError::ProxyError(format!(
+                "Proxy closed the connection before answering CONNECT {target}"
+            )));
+        }
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(DownloadError::ProxyError(format!(
+                "Proxy's answer to CONNECT is larger than {MAX_HEAD_SIZE} bytes"
+            )));
+        }
+        head.push(byte[0]);
+    }
+
+    let headers = parse_response_headers(&head)
+        .map_err(|e| DownloadError::ProxyError(format!("Bad answer to CONNECT {target}: {e}")))?;
+    if headers.status_code != 200 {
+        return Err(DownloadError::ProxyError(format!(
+            "Proxy answered CONNECT {target} with {} {}",
+            headers.status_code, headers.reason
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_handshake(_stream: TcpStream, _config: &DownloadConfig) -> Result<Stream, DownloadError> {
+    Err(DownloadError::Tls(
+        "use_tls is set but this was built without the tls feature".into(),
+    ))
+}
+
+#[cfg(feature = "tls")]
+fn tls_handshake(mut stream: TcpStream, config: &DownloadConfig) -> Result<Stream, DownloadError> {
+    let name = server_name(&config.server_addr)?;
+    let mut tls = ClientConnection::new(tls_config(config)?, name)?;
+    // Do the handshake up front, otherwise a bad certificate only shows up as an IO error when
+    // the first request gets sent
+    while tls.is_handshaking() {
+        tls.complete_io(&mut stream).map_err(|e| {
+            DownloadError::Tls(format!("Handshake with {} failed: {e}", config.server_addr))
+        })?;
+    }
+    Ok(Stream::Tls(Box::new(StreamOwned::new(tls, stream))))
+}
+
+// Drops whatever addr_family rules out. The proxy was given as an address so it's left alone,
+// except that a socket bound to an IPv4 address can only reach IPv4 ones and the same for IPv6
+pub(crate) fn filter_family(
+    candidates: &mut Vec<SocketAddr>,
+    config: &DownloadConfig,
+) -> Result<(), DownloadError> {
+    if let Some(bind) = config.bind_addr {
+        candidates.retain(|addr| addr.is_ipv4() == bind.is_ipv4());
+        if candidates.is_empty() {
+            return Err(DownloadError::Network(
+                format!(
+                    "No addresses for {} are the same family as the bind address {bind}",
+                    config.server_addr
+                )
+                .into(),
+            ));
+        }
+    }
+    if config.proxy.is_some() || config.socks5_proxy.is_some() {
+        return Ok(());
+    }
+    candidates.retain(|addr| config.addr_family.allows(addr));
+    if candidates.is_empty() {
+        let family = if config.addr_family == AddrFamily::Ipv4Only {
+            "IPv4"
+        } else {
+            "IPv6"
+        };
+        return Err(DownloadError::Network(
+            format!("No {family} addresses found for {}", config.server_addr).into(),
+        ));
+    }
+    Ok(())
+}
+
+// Whichever address worked last goes first, then IPv6, otherwise the order the resolver gave.
+// The sort is stable
+pub(crate) fn order_candidates(candidates: &mut [SocketAddr], preferred: Option<SocketAddr>) {
+    candidates.sort_by_key(|addr| (Some(*addr) != preferred, addr.is_ipv4()));
+}
+
+// Tries each address in turn, each one getting the full connect timeout, and the first to connect
+// wins. If they all fail with just the one address it's that IO error like before, with several
+// it's a Network error holding a ConnectError with every failure
+fn connect_any(
+    candidates: &[SocketAddr],
+    config: &DownloadConfig,
+) -> Result<(TcpStream, SocketAddr), DownloadError> {
+    let mut failures = Vec::new();
+    for &addr in candidates {
+        // Setting the socket up is all local, if that fails every address would too
+        let socket = open_socket(addr, config)?;
+        match socket.connect_timeout(&addr.into(), config.connect_timeout) {
+            Ok(()) => {
+                log_fallback(addr, failures.len());
+                return Ok((TcpStream::from(socket), addr));
+            }
+            Err(e) => failures.push((addr, e)),
+        }
+    }
+    Err(connect_failed(config, failures))
+}
+
+// What TcpStream::connect_timeout does but through socket2, so the buffer sizes and bind_addr
+// can go on first. SO_RCVBUF has to be set before the handshake to have any say in the TCP window
+// scale. Gives back the socket ready to connect to addr
+fn open_socket(addr: SocketAddr, config: &DownloadConfig) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let Some(size) = config.tcp_recv_buf {
+        socket.set_recv_buffer_size(size)?;
+        warn_if_capped("receive", size, socket.recv_buffer_size()?);
+    }
+    if let Some(size) = config.tcp_send_buf {
+        socket.set_send_buffer_size(size)?;
+        warn_if_capped("send", size, socket.send_buffer_size()?);
+    }
+    if let Some(bind) = config.bind_addr {
+        // So a fixed port can be bound again straight away while the last connection from it is
+        // still in TIME_WAIT. On Windows it would let another socket take the port from us
+        #[cfg(not(target_os = "windows"))]
+        socket.set_reuse_address(true)?;
+        socket
+            .bind(&bind.into())
+            .map_err(|e| bind_failed(bind, e))?;
+    }
+    Ok(socket)
+}
+
+pub(crate) fn bind_failed(bind: SocketAddr, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("Couldn't bind to {bind}: {e}"))
+}
+
+// The OS doesn't complain about a buffer bigger than it allows, it just quietly caps it (on Linux
+// at net.core.rmem_max/wmem_max). Linux also doubles whatever it's given for its own bookkeeping,
+// so only getting less than half back means it was capped. Only said once for each, every
+// reconnect would just say the same again
+pub(crate) fn warn_if_capped(which: &str, requested: usize, actual: usize) {
+    static RECEIVE_WARNED: AtomicBool = AtomicBool::new(false);
+    static SEND_WARNED: AtomicBool = AtomicBool::new(false);
+    let warned = if which == "receive" {
+        &RECEIVE_WARNED
+    } else {
+        &SEND_WARNED
+    };
+    if actual < requested / 2 && !warned.swap(true, Ordering::Relaxed) {
+        warn!("TCP {which} buffer was capped at {actual} bytes, {requested} were asked for");
+    }
+}
+
+// Only worth mentioning if an earlier address didn't work
+pub(crate) fn log_fallback(addr: SocketAddr, failed: usize) {
+    if failed > 0 {
+        let family = if addr.is_ipv4() { "IPv4" } else { "IPv6" };
+        info!("Connected to {addr} over {family} after {failed} failed attempt(s)");
+    }
+}
+
+// What connect_any gives back once every address has failed
+pub(crate) fn connect_failed(
+    config: &DownloadConfig,
+    mut failures: Vec<(SocketAddr, io::Error)>,
+) -> DownloadError {
+    if failures.len() == 1 {
+        let (addr, e) = failures.remove(0);
+        if e.kind() == io::ErrorKind::ConnectionRefused {
+            return DownloadError::ConnectionRefused { addr };
+        }
+        return DownloadError::from(e).with_timeout(config.connect_timeout);
+    }
+    DownloadError::Network(Box::new(ConnectError {
+        server: config.server_addr.clone(),
+        failures,
+    }))
+}
+
+// What goes in SNI and gets checked against the certificate, the host without the port
+#[cfg(feature = "tls")]
+fn server_name(addr: &str) -> Result<ServerName<'static>, DownloadError> {
+    let host = server_host(addr);
+    ServerName::try_from(host.to_owned())
+        .map_err(|e| DownloadError::Tls(format!("Invalid server name {host}: {e}")))
+}
+
+// addr without the port or an IPv6 address's brackets
+#[cfg(feature = "tls")]
+pub(crate) fn server_host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(feature = "tls")]
+pub(crate) fn tls_config(config: &DownloadConfig) -> Result<Arc<ClientConfig>, DownloadError> {
+    if config.verify_tls {
+        verified_tls_config()
+    } else {
+        // DANGER: nothing checks who is on the other end. Anyone between us and the server can
+        // pretend to be it, read everything (including Basic auth credentials) and send back
+        // whatever they like, leaving the hash check as the only thing that would notice. Only
+        // for development servers with a self-signed certificate
+        Ok(insecure_tls_config())
+    }
+}
+
+// Every TLS config uses ring
+#[cfg(feature = "tls")]
+fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+// Reading the system root store is slow so it only happens once, every connection shares the
+// result
+#[cfg(feature = "tls")]
+fn verified_tls_config() -> Result<Arc<ClientConfig>, DownloadError> {
+    static CONFIG: OnceLock<Result<Arc<ClientConfig>, String>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let native = rustls_native_certs::load_native_certs();
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots.add_parsable_certificates(native.certs);
+            if added == 0 {
+                let why = native
+                    .errors
+                    .first()
+                    .map_or_else(|| "store is empty".to_owned(), ToString::to_string);
+                return Err(format!("No usable system root certificates, {why}"));
+            }
+
+            let config = ClientConfig::builder_with_provider(crypto_provider())
+                .with_safe_default_protocol_versions()
+                .map_err(|e| e.to_string())?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            Ok(Arc::new(config))
+        })
+        .clone()
+        .map_err(DownloadError::Tls)
+}
+
+// verify_tls = false. Handshake signatures still get checked so the connection is at least
+// with whoever holds the key for the certificate it sent, just not that it's the right one
+#[cfg(feature = "tls")]
+fn insecure_tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let provider = crypto_provider();
+            let verifier = Arc::new(NoCertificateVerification(Arc::clone(&provider)));
+            let config = ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .expect("ring supports the default protocol versions")
+                .dangerous()
+                .with_custom_certificate_verifier(verifier)
+                .with_no_client_auth();
+            Arc::new(config)
+        })
+        .clone()
+}
+
+// Accepts any certificate at all
+#[cfg(feature = "tls")]
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<CryptoProvider>);
+
+#[cfg(feature = "tls")]
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn server_name_from_addr() {
+        assert_eq!(
+            server_name("files.example.com:443").unwrap(),
+            ServerName::try_from("files.example.com").unwrap()
+        );
+        assert!(matches!(
+            server_name("[::1]:443").unwrap(),
+            ServerName::IpAddress(_)
+        ));
+        assert!(matches!(
+            server_name("not a host:443"),
+            Err(DownloadError::Tls(_))
+        ));
+    }
+
+    // A port nothing is listening on
+    fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[test]
+    fn address_family_filter() {
+        let v4: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let v6: SocketAddr = "[::1]:8080".parse().unwrap();
+        let filtered = |addr_family, candidates: &[SocketAddr]| {
+            let config = DownloadConfig {
+                server_addr: "localhost:8080".into(),
+                addr_family,
+                ..Default::default()
+            };
+            let mut candidates = candidates.to_vec();
+            filter_family(&mut candidates, &config).map(|()| candidates)
+        };
+
+        assert_eq!(filtered(AddrFamily::Any, &[v4, v6]).unwrap(), [v4, v6]);
+        assert_eq!(filtered(AddrFamily::Ipv4Only, &[v6, v4]).unwrap(), [v4]);
+        assert_eq!(filtered(AddrFamily::Ipv6Only, &[v6, v4]).unwrap(), [v6]);
+        match filtered(AddrFamily::Ipv6Only, &[v4]) {
+            Err(DownloadError::Network(msg)) => assert_eq!(
+                msg.to_string(),
+                "No IPv6 addresses found for localhost:8080"
+            ),
+            other => panic!("Expected Network error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let dead: SocketAddr = format!("127.0.0.1:{}", closed_port()).parse().unwrap();
+
+        let config = DownloadConfig::default();
+        let (stream, addr) = connect_any(&[dead, open], &config).unwrap();
+        assert_eq!(addr, open);
+        assert_eq!(stream.peer_addr().unwrap(), open);
+    }
+
+    #[test]
+    fn nodelay_only_when_asked() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        for tcp_nodelay in [false, true] {
+            let config = DownloadConfig {
+                server_addr: listener.local_addr().unwrap().to_string(),
+                use_tls: false,
+                tcp_nodelay,
+                ..Default::default()
+            };
+            // Without tls or quic there's only the one variant
+            #[allow(irrefutable_let_patterns)]
+            let (Stream::Plain(stream), _) = connect(&config, None).unwrap() else {
+                panic!("Expected a plain stream");
+            };
+            assert_eq!(stream.nodelay().unwrap(), tcp_nodelay);
+        }
+    }
+
+    #[test]
+    fn socket_buffers_get_set() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = DownloadConfig {
+            tcp_recv_buf: Some(64 * 1024),
+            tcp_send_buf: Some(32 * 1024),
+            ..Default::default()
+        };
+        let (stream, _) = connect_any(&[listener.local_addr().unwrap()], &config).unwrap();
+        // Linux reports double what was set, other systems what was set
+        let socket = socket2::SockRef::from(&stream);
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 32 * 1024);
+    }
+
+    // All of 127/8 is loopback on Linux, elsewhere it's usually just 127.0.0.1
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn connects_from_the_bind_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = DownloadConfig {
+            bind_addr: Some("127.0.0.2:0".parse().unwrap()),
+            ..Default::default()
+        };
+        let (stream, _) = connect_any(&[listener.local_addr().unwrap()], &config).unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip().to_string(), "127.0.0.2");
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer, stream.local_addr().unwrap());
+    }
+
+    #[test]
+    fn bad_bind_address_is_an_io_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // TEST-NET-1, which nothing's going to have
+        let config = DownloadConfig {
+            bind_addr: Some("192.0.2.1:0".parse().unwrap()),
+            ..Default::default()
+        };
+        match connect_any(&[listener.local_addr().unwrap()], &config) {
+            Err(DownloadError::Io(e)) => assert!(e.to_string().contains("192.0.2.1:0"), "{e}"),
+            other => panic!("Expected Io error, got {other:?}"),
+        }
+
+        let v6 = DownloadConfig {
+            bind_addr: Some("[::1]:0".parse().unwrap()),
+            ..config
+        };
+        let mut candidates = vec![listener.local_addr().unwrap()];
+        assert!(matches!(
+            filter_family(&mut candidates, &v6),
+            Err(DownloadError::Network(_))
+        ));
+    }
+
+    #[test]
+    fn every_failure_is_reported() {
+        let first: SocketAddr = format!("127.0.0.1:{}", closed_port()).parse().unwrap();
+        let second: SocketAddr = format!("127.0.0.1:{}", closed_port()).parse().unwrap();
+        let config = DownloadConfig {
+            server_addr: "dual.example.com:8080".into(),
+            ..Default::default()
+        };
+
+        match connect_any(&[first, second], &config) {
+            Err(DownloadError::Network(e)) => {
+                let e = e.downcast_ref::<ConnectError>().unwrap();
+                assert_eq!(e.failures.len(), 2);
+                assert_eq!(e.failures[0].0, first);
+                assert!(e
+                    .to_string()
+                    .starts_with("Could not connect to dual.example.com:8080"));
+            }
+            other => panic!("Expected Network error, got {other:?}"),
+        }
+
+        // Just the one address says which one refused
+        assert!(matches!(
+            connect_any(&[first], &config),
+            Err(DownloadError::ConnectionRefused { addr }) if addr == first
+        ));
+    }
+
+    #[test]
+    fn plain_http_server_is_a_tls_error() {
+        // Something that answers in plain text like the python server would
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let _ = socket.write_all(b"HTTP/1.0 400 Bad Request\r\n\r\n");
+        });
+
+        let config = DownloadConfig {
+            server_addr: format!("127.0.0.1:{port}"),
+            use_tls: true,
+            ..Default::default()
+        };
+        let result = Connection::new().stream(&config).map(|_| ());
+        assert!(matches!(result, Err(DownloadError::Tls(_))), "{result:?}");
+        server.join().unwrap();
+    }
+
+    // Reads a CONNECT off the socket and hands back the request
+    fn read_connect(socket: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0u8];
+        while !head_complete(&head) && socket.read(&mut byte).unwrap() == 1 {
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[test]
+    fn proxy_refusing_connect_is_a_proxy_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let request = read_connect(&mut socket);
+            socket
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .unwrap();
+            request
+        });
+
+        let config = DownloadConfig {
+            server_addr: "files.example.com:443".into(),
+            use_tls: true,
+            proxy: Some(proxy),
+            ..Default::default()
+        };
+        let result = Connection::new().stream(&config).map(|_| ());
+        match result {
+            Err(DownloadError::ProxyError(msg)) => assert!(msg.contains("407"), "{msg}"),
+            other => panic!("Expected ProxyError, got {other:?}"),
+        }
+        assert!(server.join().unwrap().starts_with(
+            "CONNECT files.example.com:443 HTTP/1.1\r\nHost: files.example.com:443\r\n"
+        ));
+    }
+
+    // An HTTPS server on localhost with the self-signed certificate in tests/fixtures that does
+    // one handshake and then answers with a 200
+    #[cfg(feature = "tls")]
+    fn self_signed_server() -> (u16, thread::JoinHandle<()>) {
+        use rustls::pki_types::{pem::PemObject, PrivateKeyDer};
+        use rustls::{ServerConfig, ServerConnection};
+
+        let certs =
+            CertificateDer::pem_slice_iter(include_bytes!("../tests/fixtures/localhost_cert.pem"))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+        let key =
+            PrivateKeyDer::from_pem_slice(include_bytes!("../tests/fixtures/localhost_key.pem"))
+                .unwrap();
+        let config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let conn = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut tls = StreamOwned::new(conn, socket);
+            // The handshake happens on the first write, which fails if the client gave up
+            let _ = tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            tls.conn.send_close_notify();
+            let _ = tls.flush();
+        });
+        (port, server)
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn self_signed_needs_insecure() {
+        let (port, server) = self_signed_server();
+        let config = DownloadConfig {
+            server_addr: format!("localhost:{port}"),
+            use_tls: true,
+            ..Default::default()
+        };
+        let result = Connection::new().stream(&config).map(|_| ());
+        assert!(matches!(result, Err(DownloadError::Tls(_))), "{result:?}");
+        server.join().unwrap();
+
+        let (port, server) = self_signed_server();
+        let config = DownloadConfig {
+            server_addr: format!("localhost:{port}"),
+            use_tls: true,
+            verify_tls: false,
+            ..Default::default()
+        };
+        let mut conn = Connection::new();
+        let mut response = String::new();
+        conn.stream(&config)
+            .unwrap()
+            .read_to_string(&mut response)
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response:?}");
+        server.join().unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_goes_through_a_connect_tunnel() {
+        let (port, server) = self_signed_server();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        // Says yes to the CONNECT then just copies bytes both ways
+        let proxy = thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let request = read_connect(&mut client);
+            let mut upstream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .unwrap();
+            let mut to_server = (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            let forward = thread::spawn(move || {
+                let _ = io::copy(&mut to_server.0, &mut to_server.1);
+            });
+            let _ = io::copy(&mut upstream, &mut client);
+            let _ = client.shutdown(std::net::Shutdown::Both);
+            forward.join().unwrap();
+            request
+        });
+
+        let config = DownloadConfig {
+            server_addr: format!("localhost:{port}"),
+            use_tls: true,
+            verify_tls: false,
+            proxy: Some(proxy_addr),
+            ..Default::default()
+        };
+        let mut conn = Connection::new();
+        let mut response = String::new();
+        conn.stream(&config)
+            .unwrap()
+            .read_to_string(&mut response)
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response:?}");
+        conn.close();
+        server.join().unwrap();
+        assert!(proxy
+            .join()
+            .unwrap()
+            .starts_with(&format!("CONNECT localhost:{port} HTTP/1.1\r\n")));
+    }
+}
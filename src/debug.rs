@@ -0,0 +1,179 @@
+// Looking at the raw bytes a server sent, for when the hash doesn't match and it's not obvious why
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+// Anything up to this gets dumped in full, past it only the first and last HEX_DUMP_EDGE bytes
+pub const HEX_DUMP_MAX: usize = 4 * 1024;
+pub const HEX_DUMP_EDGE: usize = 256;
+
+// The same layout as hexdump -C, 16 bytes a line:
+// 00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a        |Hello, world!.|
+// offset is where data starts in the file so the left column lines up with it
+#[must_use]
+pub fn hex_dump(data: &[u8], offset: u64) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x}  ", offset + i as u64 * 16);
+        for slot in 0..16 {
+            match line.get(slot) {
+                Some(byte) => {
+                    let _ = write!(out, "{byte:02x} ");
+                }
+                None => out.push_str("   "),
+            }
+            if slot == 7 {
+                out.push(' ');
+            }
+        }
+        out.push_str(" |");
+        out.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                char::from(b)
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+// The whole of data if it's small, otherwise the two ends with ... between them
+#[must_use]
+pub fn hex_dump_preview(data: &[u8]) -> String {
+    if data.len() <= HEX_DUMP_MAX {
+        return hex_dump(data, 0);
+    }
+    let tail_start = data.len() - HEX_DUMP_EDGE;
+    edges(
+        &data[..HEX_DUMP_EDGE],
+        &data[tail_start..],
+        tail_start as u64,
+    )
+}
+
+// hex_dump_preview for a file on disk, only the bytes that get shown are read
+pub fn hex_dump_file_preview(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len <= HEX_DUMP_MAX as u64 {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        return Ok(hex_dump(&data, 0));
+    }
+    let mut head = [0u8; HEX_DUMP_EDGE];
+    file.read_exact(&mut head)?;
+    let tail_start = len - HEX_DUMP_EDGE as u64;
+    file.seek(SeekFrom::Start(tail_start))?;
+    let mut tail = [0u8; HEX_DUMP_EDGE];
+    file.read_exact(&mut tail)?;
+    Ok(edges(&head, &tail, tail_start))
+}
+
+fn edges(head: &[u8], tail: &[u8], tail_start: u64) -> String {
+    format!("{}...\n{}", hex_dump(head, 0), hex_dump(tail, tail_start))
+}
+
+// What DownloadConfig::debug_headers prints for the first chunk, the request that went out and
+// the head of the response that came back, a delimited block of each. Credentials are masked
+#[must_use]
+pub fn header_dump(chunk: u64, request: &str, head: &[u8]) -> String {
+    let mut out = format!("----- chunk {chunk} request -----\n");
+    for line in head_lines(request) {
+        out.push_str(&mask_authorization(line));
+        out.push('\n');
+    }
+    let _ = writeln!(out, "----- chunk {chunk} response -----");
+    for line in head_lines(&String::from_utf8_lossy(head)) {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str("-----\n");
+    out
+}
+
+// Up to the blank line that ends the head, without the CRLFs
+fn head_lines(head: &str) -> impl Iterator<Item = &str> {
+    head.split("\r\n").take_while(|line| !line.is_empty())
+}
+
+// Authorization: Basic dXNlcjpwYXNz -> Authorization: Basic ***, and the same for
+// Proxy-Authorization. Anything else is left alone
+#[must_use]
+pub fn mask_authorization(line: &str) -> String {
+    let Some((name, value)) = line.split_once(':') else {
+        return line.to_owned();
+    };
+    if !name.trim().to_ascii_lowercase().ends_with("authorization") {
+        return line.to_owned();
+    }
+    match value.trim().split_once(' ') {
+        Some((scheme, _)) => format!("{name}: {scheme} ***"),
+        None => format!("{name}: ***"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_hexdump_c() {
+        let data = b"Hello, world!\n\x00\x01\x7f\xffABCDEFGHIJKLMNOP";
+        assert_eq!(
+            hex_dump(data, 0),
+            "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 01  |Hello, world!...|\n\
+             00000010  7f ff 41 42 43 44 45 46  47 48 49 4a 4b 4c 4d 4e  |..ABCDEFGHIJKLMN|\n\
+             00000020  4f 50                                             |OP|\n"
+        );
+        assert_eq!(
+            hex_dump(b"abc", 0x1230),
+            "00001230  61 62 63                                          |abc|\n"
+        );
+        assert_eq!(hex_dump(b"", 0), "");
+    }
+
+    #[test]
+    fn header_dump_masks_credentials() {
+        let request = "GET /data HTTP/1.1\r\nHost: x\r\nAuthorization: Basic dXNlcjpwYXNz\r\n\
+                       proxy-authorization: secret\r\n\r\n";
+        let head = b"HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\n\r\n";
+        assert_eq!(
+            header_dump(1, request, head),
+            "----- chunk 1 request -----\n\
+             GET /data HTTP/1.1\n\
+             Host: x\n\
+             Authorization: Basic ***\n\
+             proxy-authorization: ***\n\
+             ----- chunk 1 response -----\n\
+             HTTP/1.1 206 Partial Content\n\
+             Content-Length: 3\n\
+             -----\n"
+        );
+        assert_eq!(mask_authorization("X-Auth: kept"), "X-Auth: kept");
+    }
+
+    #[test]
+    fn preview_keeps_the_ends_of_big_data() {
+        let small = vec![b'x'; HEX_DUMP_MAX];
+        assert_eq!(hex_dump_preview(&small), hex_dump(&small, 0));
+
+        let big: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let preview = hex_dump_preview(&big);
+        // 16 lines of each end and the ... line
+        assert_eq!(preview.lines().count(), 33);
+        assert_eq!(preview.lines().nth(16), Some("..."));
+        assert!(preview.lines().nth(17).unwrap().starts_with("00002610  "));
+
+        let path =
+            std::env::temp_dir().join(format!("glitchy-http-hexdump-{}", std::process::id()));
+        std::fs::write(&path, &big).unwrap();
+        let from_file = hex_dump_file_preview(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(from_file.unwrap(), preview);
+    }
+}
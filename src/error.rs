@@ -1,50 +1,634 @@
-use std::{fmt, io};
+use std::{
+    error::Error,
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+use crate::sha::HashAlgorithm;
+
+// What Network and Parse hold. A plain message turns into one with .into(), otherwise it's the
+// original error so callers can downcast to it
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+// Most of an error response's body ServerError keeps
+pub const MAX_ERROR_BODY: usize = 4 * 1024;
 
 #[derive(Debug)]
 pub enum DownloadError {
     Io(io::Error),
-    Network(String),
-    Parse(String),
+    // A connect, read or write that ran past its timeout in DownloadConfig
+    Timeout {
+        timeout: Duration,
+    },
+    // Nothing listening at addr, the server's most likely not running. Only when there was just
+    // the one address to try, several failures end up in a ConnectError
+    ConnectionRefused {
+        addr: SocketAddr,
+    },
+    Network(BoxError),
+    Parse(BoxError),
     Logic(String), // This probably should be a panic instead tbh. Logic errors in client code
     // shouldn't be like this
-    HashMismatch { expected: String, actual: String },
+    HashMismatch {
+        algorithm: HashAlgorithm,
+        expected: String,
+        actual: String,
+    },
+    // Server answered with something other than 200/206 that isn't an error, ie a 204 or a 3xx
+    HttpStatus {
+        code: u16,
+        reason: String,
+    },
+    // Server answered 4xx or 5xx (other than 401 and 429), body is the first MAX_ERROR_BODY bytes
+    // of whatever it sent with it since that's usually where it says what went wrong
+    ServerError {
+        status: u16,
+        body: Vec<u8>,
+    },
     Args(String),
+    // Setting up or talking over a TLS connection went wrong, ie a bad certificate
+    Tls(String),
+    // Server answered 401, never contains the password
+    Auth(String),
+    // Server answered 429 Too Many Requests, with however long its Retry-After said to wait
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    // The proxy in DownloadConfig::proxy wouldn't take us to the server, ie it answered CONNECT
+    // with something other than 200
+    ProxyError(String),
+    // Every one of a chunk's attempts failed, last_error is what went wrong the final time
+    RetriesExhausted {
+        attempts: u32,
+        last_error: Box<DownloadError>,
+    },
+    // The config's cancel_token was set, see DownloadConfig::cancel_token
+    Cancelled,
+    // A file download that stopped partway, the file holds the first `written` bytes
+    PartialDownload {
+        written: u64,
+        total: u64,
+        cause: Box<DownloadError>,
+    },
+    // Opening, writing, seeking in or flushing the file at path went wrong, as opposed to an Io
+    // error which is the connection
+    FilesystemError {
+        path: PathBuf,
+        inner: io::Error,
+    },
+    // Bytes gap_start..=gap_end of an assembled download weren't written by exactly one chunk,
+    // either none of them covered it or more than one did. See client::verify_buffer_completeness
+    RangeMismatch {
+        gap_start: u64,
+        gap_end: u64,
+    },
+    // A chunk written at some offset in the file didn't all go in, the file ends at `written`
+    // where it should have reached `expected`. See client::verify_write_result
+    PartialWrite {
+        written: u64,
+        expected: u64,
+    },
+}
+
+impl DownloadError {
+    // Name of the variant, for when something other than a person needs to know what went wrong
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DownloadError::Io(_) => "Io",
+            DownloadError::Timeout { .. } => "Timeout",
+            DownloadError::ConnectionRefused { .. } => "ConnectionRefused",
+            DownloadError::Network(_) => "Network",
+            DownloadError::Parse(_) => "Parse",
+            DownloadError::Logic(_) => "Logic",
+            DownloadError::HashMismatch { .. } => "HashMismatch",
+            DownloadError::HttpStatus { .. } => "HttpStatus",
+            DownloadError::ServerError { .. } => "ServerError",
+            DownloadError::Args(_) => "Args",
+            DownloadError::Tls(_) => "Tls",
+            DownloadError::Auth(_) => "Auth",
+            DownloadError::RateLimited { .. } => "RateLimited",
+            DownloadError::ProxyError(_) => "ProxyError",
+            DownloadError::RetriesExhausted { .. } => "RetriesExhausted",
+            DownloadError::Cancelled => "Cancelled",
+            DownloadError::PartialDownload { .. } => "PartialDownload",
+            DownloadError::FilesystemError { .. } => "FilesystemError",
+            DownloadError::RangeMismatch { .. } => "RangeMismatch",
+            DownloadError::PartialWrite { .. } => "PartialWrite",
+        }
+    }
+
+    // What the binary exits with, so a script can tell a bad download from a bad argument. A
+    // RetriesExhausted or PartialDownload goes by whatever caused it, anything not listed is 1.
+    // 130 for Cancelled is what a shell gives a process killed by Ctrl-C
+    #[must_use]
+    pub fn to_exit_code(&self) -> u8 {
+        match self {
+            DownloadError::Args(_) => 2,
+            DownloadError::HashMismatch { .. } => 3,
+            DownloadError::Network(_)
+            | DownloadError::Timeout { .. }
+            | DownloadError::ConnectionRefused { .. } => 4,
+            DownloadError::Io(_) | DownloadError::FilesystemError { .. } => 5,
+            DownloadError::Parse(_) => 6,
+            DownloadError::ServerError {
+                status: 401..=403, ..
+            }
+            | DownloadError::Auth(_) => 7,
+            DownloadError::Cancelled => 130,
+            DownloadError::RetriesExhausted {
+                last_error: cause, ..
+            }
+            | DownloadError::PartialDownload { cause, .. } => cause.to_exit_code(),
+            _ => 1,
+        }
+    }
+}
+
+impl From<DownloadError> for ExitCode {
+    fn from(e: DownloadError) -> Self {
+        ExitCode::from(e.to_exit_code())
+    }
 }
 
 impl fmt::Display for DownloadError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             DownloadError::Io(e) => write!(f, "IO Error: {e}"),
+            DownloadError::Timeout { timeout } => {
+                write!(f, "Connection timed out after {}s", timeout.as_secs_f64())
+            }
+            DownloadError::ConnectionRefused { addr } => write!(
+                f,
+                "Server at {addr} refused connection, check that it's running and listening on \
+                 that port"
+            ),
             DownloadError::Network(s) => write!(f, "Network Error: {s}"),
             DownloadError::Parse(s) => write!(f, "Response Parse Error: {s}"),
             DownloadError::Logic(s) => write!(f, "Logic Error: {s}"),
-            DownloadError::HashMismatch { expected, actual } => {
+            DownloadError::HashMismatch {
+                algorithm,
+                expected,
+                actual,
+            } => {
                 write!(
                     f,
-                    "Hash HashMismatch!\n Expected: {expected}\n Actual:  {actual}"
+                    "Hash HashMismatch ({algorithm})!\n Expected: {expected}\n Actual:  {actual}"
                 )
             }
+            DownloadError::HttpStatus { code, reason } => {
+                write!(f, "HTTP Error: server returned {code} {reason}")
+            }
+            DownloadError::ServerError { status, body } => {
+                write!(f, "Server Error: server returned {status}")?;
+                match std::str::from_utf8(body) {
+                    Ok(text) if text.trim().is_empty() => Ok(()),
+                    Ok(text) => write!(f, ": {}", text.trim_end()),
+                    Err(_) => write!(f, ": <binary body, {} bytes>", body.len()),
+                }
+            }
             DownloadError::Args(s) => write!(f, "Argument Error: {s}"),
+            DownloadError::Tls(s) => write!(f, "TLS Error: {s}"),
+            DownloadError::Auth(s) => write!(f, "Authentication Error: {s}"),
+            DownloadError::RateLimited {
+                retry_after: Some(after),
+            } => write!(
+                f,
+                "Rate Limited: server returned 429, retry after {}s",
+                after.as_secs()
+            ),
+            DownloadError::RateLimited { retry_after: None } => {
+                write!(f, "Rate Limited: server returned 429")
+            }
+            DownloadError::ProxyError(s) => write!(f, "Proxy Error: {s}"),
+            DownloadError::RetriesExhausted {
+                attempts,
+                last_error,
+            } => write!(
+                f,
+                "Gave up after {attempts} attempts, the last one failed with: {last_error}"
+            ),
+            DownloadError::Cancelled => write!(f, "Download cancelled"),
+            DownloadError::PartialDownload {
+                written,
+                total,
+                cause,
+            } => write!(
+                f,
+                "{cause}\n Download incomplete: {written}/{total} bytes written to file"
+            ),
+            DownloadError::FilesystemError { path, inner } => {
+                write!(f, "Filesystem Error: {}: {inner}", path.display())
+            }
+            DownloadError::RangeMismatch { gap_start, gap_end } => write!(
+                f,
+                "Range Mismatch: bytes {gap_start}-{gap_end} weren't written exactly once"
+            ),
+            DownloadError::PartialWrite { written, expected } => write!(
+                f,
+                "Partial Write: the file only got to byte {written} of {expected}"
+            ),
+        }
+    }
+}
+
+impl Error for DownloadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DownloadError::Io(e) | DownloadError::FilesystemError { inner: e, .. } => Some(e),
+            DownloadError::Network(e) | DownloadError::Parse(e) => Some(e.as_ref()),
+            DownloadError::PartialDownload { cause, .. } => Some(cause.as_ref()),
+            DownloadError::RetriesExhausted { last_error, .. } => Some(last_error.as_ref()),
+            DownloadError::Logic(_)
+            | DownloadError::Timeout { .. }
+            | DownloadError::ConnectionRefused { .. }
+            | DownloadError::HashMismatch { .. }
+            | DownloadError::HttpStatus { .. }
+            | DownloadError::ServerError { .. }
+            | DownloadError::Args(_)
+            | DownloadError::Tls(_)
+            | DownloadError::Auth(_)
+            | DownloadError::RateLimited { .. }
+            | DownloadError::ProxyError(_)
+            | DownloadError::Cancelled
+            | DownloadError::RangeMismatch { .. }
+            | DownloadError::PartialWrite { .. } => None,
         }
     }
 }
 
-impl std::error::Error for DownloadError {}
+// For map_err on whatever gets done to the file at path
+pub(crate) fn in_file(path: &Path) -> impl FnOnce(io::Error) -> DownloadError + '_ {
+    move |inner| DownloadError::FilesystemError {
+        path: path.to_owned(),
+        inner,
+    }
+}
+
+// Every address a connection was tried on and why it didn't work
+#[derive(Debug)]
+pub struct ConnectError {
+    pub server: String,
+    pub failures: Vec<(SocketAddr, io::Error)>,
+}
 
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Could not connect to {}", self.server)?;
+        for (addr, e) in &self.failures {
+            write!(f, "\n  {addr}: {e}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConnectError {}
+
+impl DownloadError {
+    // The io::Error conversion can't know which timeout ran out, the code that set it fills it in.
+    // Anything else, or a Timeout that already knows, goes through untouched
+    pub(crate) fn with_timeout(self, timeout: Duration) -> Self {
+        match self {
+            DownloadError::Timeout { timeout: unknown } if unknown.is_zero() => {
+                DownloadError::Timeout { timeout }
+            }
+            other => other,
+        }
+    }
+}
+
+// A socket timeout comes back as TimedOut or, on unix, WouldBlock. Those become Timeout with a
+// zero timeout until with_timeout says what it was
 impl From<io::Error> for DownloadError {
     fn from(err: io::Error) -> Self {
-        DownloadError::Io(err)
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => DownloadError::Timeout {
+                timeout: Duration::ZERO,
+            },
+            _ => DownloadError::Io(err),
+        }
     }
 }
 
 impl From<std::num::ParseIntError> for DownloadError {
     fn from(value: std::num::ParseIntError) -> Self {
-        DownloadError::Parse(format!("Failed to parse integer: {value}"))
+        DownloadError::Parse(Box::new(value))
     }
 }
 
 impl From<std::str::Utf8Error> for DownloadError {
     fn from(value: std::str::Utf8Error) -> Self {
-        DownloadError::Parse(format!("Failed to parse UTF-8 string: {value}"))
+        DownloadError::Parse(Box::new(value))
+    }
+}
+
+// A u64 from the wire that doesn't fit a usize on this platform, ie a >4 GiB offset on 32 bit.
+// Means the caller asked for something we can never hold rather than anything the server did
+impl From<std::num::TryFromIntError> for DownloadError {
+    fn from(value: std::num::TryFromIntError) -> Self {
+        DownloadError::Logic(format!("Number out of range for this platform: {value}"))
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rustls::Error> for DownloadError {
+    fn from(value: rustls::Error) -> Self {
+        DownloadError::Tls(value.to_string())
+    }
+}
+
+// Every way a QUIC connection can end. A TLS alert (the 0x1XX crypto error codes) either side
+// sent is a Tls error like it would be over TCP and the idle timeout is a Timeout, the rest are
+// Network errors holding the original
+#[cfg(feature = "quic")]
+impl From<quinn::ConnectionError> for DownloadError {
+    fn from(value: quinn::ConnectionError) -> Self {
+        use quinn::ConnectionError as E;
+        match value {
+            E::TransportError(e) if is_tls_alert(e.code) => DownloadError::Tls(e.to_string()),
+            E::ConnectionClosed(close) if is_tls_alert(close.error_code) => {
+                DownloadError::Tls(format!("Server closed the connection: {close}"))
+            }
+            E::TimedOut => DownloadError::Timeout {
+                timeout: Duration::ZERO,
+            },
+            other @ (E::VersionMismatch
+            | E::TransportError(_)
+            | E::ConnectionClosed(_)
+            | E::ApplicationClosed(_)
+            | E::Reset
+            | E::LocallyClosed
+            | E::CidsExhausted) => DownloadError::Network(Box::new(other)),
+        }
+    }
+}
+
+#[cfg(feature = "quic")]
+fn is_tls_alert(code: quinn::TransportErrorCode) -> bool {
+    (0x100..0x200).contains(&u64::from(code))
+}
+
+// What quinn refuses before sending anything. Only a bad server name is down to the config, the
+// rest are quinn running out of something
+#[cfg(feature = "quic")]
+impl From<quinn::ConnectError> for DownloadError {
+    fn from(value: quinn::ConnectError) -> Self {
+        use quinn::ConnectError as E;
+        match value {
+            E::InvalidServerName(name) => DownloadError::Tls(format!("Invalid server name {name}")),
+            other @ (E::EndpointStopping
+            | E::CidsExhausted
+            | E::InvalidRemoteAddress(_)
+            | E::NoDefaultClientConfig
+            | E::UnsupportedVersion) => DownloadError::Network(Box::new(other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_chain() {
+        let io = DownloadError::from(io::Error::other("boom"));
+        assert_eq!(io.source().unwrap().to_string(), "boom");
+
+        let parse = DownloadError::from("abc".parse::<u64>().unwrap_err());
+        assert!(parse
+            .source()
+            .unwrap()
+            .downcast_ref::<std::num::ParseIntError>()
+            .is_some());
+        assert!(DownloadError::Args("nope".into()).source().is_none());
+
+        let fs = DownloadError::FilesystemError {
+            path: PathBuf::from("/tmp/out.bin"),
+            inner: io::Error::from(io::ErrorKind::PermissionDenied),
+        };
+        assert_eq!(fs.kind(), "FilesystemError");
+        assert_eq!(
+            fs.to_string(),
+            "Filesystem Error: /tmp/out.bin: permission denied"
+        );
+        assert_eq!(
+            fs.source()
+                .and_then(|e| e.downcast_ref::<io::Error>())
+                .map(io::Error::kind),
+            Some(io::ErrorKind::PermissionDenied)
+        );
+
+        // Wrapping errors hand back the thing they wrap
+        let partial = DownloadError::PartialDownload {
+            written: 1,
+            total: 2,
+            cause: Box::new(DownloadError::Network("gone".into())),
+        };
+        let cause = partial.source().unwrap();
+        assert_eq!(cause.to_string(), "Network Error: gone");
+        assert_eq!(cause.source().unwrap().to_string(), "gone");
+
+        let exhausted = DownloadError::RetriesExhausted {
+            attempts: 3,
+            last_error: Box::new(DownloadError::Timeout {
+                timeout: Duration::from_secs(10),
+            }),
+        };
+        assert_eq!(
+            exhausted.to_string(),
+            "Gave up after 3 attempts, the last one failed with: Connection timed out after 10s"
+        );
+        assert_eq!(exhausted.kind(), "RetriesExhausted");
+        assert!(matches!(
+            exhausted.source().unwrap().downcast_ref::<DownloadError>(),
+            Some(DownloadError::Timeout { .. })
+        ));
+    }
+
+    #[test]
+    fn exit_codes() {
+        let server = |status| DownloadError::ServerError {
+            status,
+            body: Vec::new(),
+        };
+        let cases = [
+            (DownloadError::Args("bad".into()), 2),
+            (
+                DownloadError::HashMismatch {
+                    algorithm: HashAlgorithm::Sha256,
+                    expected: "a".into(),
+                    actual: "b".into(),
+                },
+                3,
+            ),
+            (DownloadError::Network("down".into()), 4),
+            (
+                DownloadError::Timeout {
+                    timeout: Duration::from_secs(1),
+                },
+                4,
+            ),
+            (io::Error::other("disk").into(), 5),
+            (in_file(Path::new("out.bin"))(io::Error::other("full")), 5),
+            (DownloadError::Parse("garbled".into()), 6),
+            (server(401), 7),
+            (server(403), 7),
+            (server(404), 1),
+            (server(500), 1),
+            (DownloadError::Cancelled, 130),
+            (DownloadError::Logic("oops".into()), 1),
+            // Goes by the cause
+            (
+                DownloadError::RetriesExhausted {
+                    attempts: 3,
+                    last_error: Box::new(DownloadError::Network("down".into())),
+                },
+                4,
+            ),
+            (
+                DownloadError::PartialDownload {
+                    written: 1,
+                    total: 2,
+                    cause: Box::new(DownloadError::Cancelled),
+                },
+                130,
+            ),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.to_exit_code(), code, "{error:?}");
+            assert_eq!(ExitCode::from(error), ExitCode::from(code));
+        }
+    }
+
+    #[test]
+    fn kind_names_the_variant() {
+        assert_eq!(DownloadError::Network("refused".into()).kind(), "Network");
+        assert_eq!(DownloadError::from(io::Error::other("boom")).kind(), "Io");
+        // Doesn't look through to the cause
+        let partial = DownloadError::PartialDownload {
+            written: 0,
+            total: 1,
+            cause: Box::new(DownloadError::Args("nope".into())),
+        };
+        assert_eq!(partial.kind(), "PartialDownload");
+        let mismatch = DownloadError::RangeMismatch {
+            gap_start: 4,
+            gap_end: 7,
+        };
+        assert_eq!(mismatch.kind(), "RangeMismatch");
+        assert_eq!(
+            mismatch.to_string(),
+            "Range Mismatch: bytes 4-7 weren't written exactly once"
+        );
+    }
+
+    #[test]
+    fn conversions() {
+        for kind in [io::ErrorKind::TimedOut, io::ErrorKind::WouldBlock] {
+            let timeout = DownloadError::from(io::Error::from(kind));
+            assert!(
+                matches!(timeout, DownloadError::Timeout { .. }),
+                "{timeout:?}"
+            );
+            let timeout = timeout.with_timeout(Duration::from_millis(2500));
+            assert_eq!(timeout.to_string(), "Connection timed out after 2.5s");
+            // Only the first one to know gets to say
+            let timeout = timeout.with_timeout(Duration::from_secs(1));
+            assert_eq!(timeout.kind(), "Timeout");
+            assert_eq!(timeout.to_string(), "Connection timed out after 2.5s");
+        }
+        let refused = DownloadError::from(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(matches!(refused, DownloadError::Io(_)), "{refused:?}");
+
+        let overflow = DownloadError::from(u8::try_from(300u32).unwrap_err());
+        assert!(matches!(overflow, DownloadError::Logic(_)), "{overflow:?}");
+
+        #[cfg(feature = "tls")]
+        {
+            let tls = DownloadError::from(rustls::Error::HandshakeNotComplete);
+            assert!(matches!(tls, DownloadError::Tls(_)), "{tls:?}");
+        }
+    }
+
+    #[test]
+    fn display_unchanged_for_messages() {
+        assert_eq!(
+            DownloadError::Parse("bad header".into()).to_string(),
+            "Response Parse Error: bad header"
+        );
+        assert_eq!(
+            DownloadError::Network("refused".into()).to_string(),
+            "Network Error: refused"
+        );
+        assert_eq!(
+            DownloadError::Tls("bad certificate".into()).to_string(),
+            "TLS Error: bad certificate"
+        );
+        assert_eq!(
+            DownloadError::ConnectionRefused {
+                addr: "127.0.0.1:8080".parse().unwrap()
+            }
+            .to_string(),
+            "Server at 127.0.0.1:8080 refused connection, check that it's running and listening on \
+             that port"
+        );
+    }
+
+    #[cfg(feature = "quic")]
+    #[test]
+    fn quic_errors() {
+        use quinn::{ConnectionError, TransportErrorCode};
+
+        assert!(is_tls_alert(TransportErrorCode::crypto(42)));
+        assert!(!is_tls_alert(TransportErrorCode::PROTOCOL_VIOLATION));
+        assert!(matches!(
+            DownloadError::from(ConnectionError::TimedOut).with_timeout(Duration::from_secs(3)),
+            DownloadError::Timeout { timeout } if timeout == Duration::from_secs(3)
+        ));
+        for lost in [
+            ConnectionError::VersionMismatch,
+            ConnectionError::Reset,
+            ConnectionError::LocallyClosed,
+            ConnectionError::CidsExhausted,
+        ] {
+            let err = DownloadError::from(lost.clone());
+            assert_eq!(err.kind(), "Network");
+            assert_eq!(
+                err.source().unwrap().downcast_ref::<ConnectionError>(),
+                Some(&lost)
+            );
+        }
+
+        assert!(matches!(
+            DownloadError::from(quinn::ConnectError::InvalidServerName("a b".into())),
+            DownloadError::Tls(_)
+        ));
+        assert!(matches!(
+            DownloadError::from(quinn::ConnectError::UnsupportedVersion),
+            DownloadError::Network(_)
+        ));
+    }
+
+    #[test]
+    fn server_error_display() {
+        let error = |body: &[u8]| {
+            DownloadError::ServerError {
+                status: 502,
+                body: body.to_vec(),
+            }
+            .to_string()
+        };
+        assert_eq!(
+            error(b"upstream timed out\n"),
+            "Server Error: server returned 502: upstream timed out"
+        );
+        assert_eq!(
+            error(&[0xff, 0xfe, 0x00]),
+            "Server Error: server returned 502: <binary body, 3 bytes>"
+        );
+        assert_eq!(error(b""), "Server Error: server returned 502");
     }
 }
@@ -0,0 +1,113 @@
+// DownloadConfig::etag_cache_dir, so a file that hasn't changed on the server doesn't get
+// downloaded again. For each URL the cache keeps the ETag the server sent as <key>.etag and the
+// data that went with it as <key>.data, key being the SHA-256 of the URL. download_full_data
+// sends the ETag back in If-None-Match and a 304 means the data here is still good
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::config::DownloadConfig;
+use crate::sha::calculate_sha256;
+
+// What the server sent last time for a URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedCopy {
+    // Exactly as it came in the ETag header, quotes and all
+    pub etag: String,
+    pub data: Vec<u8>,
+}
+
+// Hex SHA-256 of the URL config points at, the scheme included so http and https don't share
+#[must_use]
+pub fn cache_key(config: &DownloadConfig) -> String {
+    let scheme = if config.use_tls { "https" } else { "http" };
+    calculate_sha256(format!("{scheme}://{}{}", config.server_addr, config.path).as_bytes())
+}
+
+fn cache_paths(dir: &Path, config: &DownloadConfig) -> (PathBuf, PathBuf) {
+    let key = cache_key(config);
+    (
+        dir.join(format!("{key}.etag")),
+        dir.join(format!("{key}.data")),
+    )
+}
+
+// None unless there's an ETag and data for the URL and the data is total_size long. A cache that
+// can't be read is as good as an empty one
+#[must_use]
+pub fn load_cached(dir: &Path, config: &DownloadConfig, total_size: u64) -> Option<CachedCopy> {
+    let (etag_path, data_path) = cache_paths(dir, config);
+    let etag = fs::read_to_string(etag_path).ok()?.trim().to_owned();
+    let data = fs::read(data_path).ok()?;
+    (!etag.is_empty() && data.len() as u64 == total_size).then_some(CachedCopy { etag, data })
+}
+
+// Makes dir if it has to. The old ETag goes first and the new one last, so one that's there
+// always goes with the data next to it even if this fails halfway
+pub fn store_cached(
+    dir: &Path,
+    config: &DownloadConfig,
+    etag: &str,
+    data: &[u8],
+) -> io::Result<()> {
+    let (etag_path, data_path) = cache_paths(dir, config);
+    fs::create_dir_all(dir)?;
+    match fs::remove_file(&etag_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::write(data_path, data)?;
+    fs::write(etag_path, etag)
+}
+
+// The ETag as If-None-Match wants it. Servers are meant to send it quoted (or W/ and quoted) but
+// plenty don't
+pub(crate) fn if_none_match(etag: &str) -> String {
+    let opaque = etag.strip_prefix("W/").unwrap_or(etag);
+    if opaque.len() >= 2 && opaque.starts_with('"') && opaque.ends_with('"') {
+        etag.to_owned()
+    } else {
+        format!("\"{etag}\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_and_loads_by_url() {
+        let dir = std::env::temp_dir().join(format!("glitchy-http-etag-{}", std::process::id()));
+        let config = DownloadConfig {
+            path: "/data.bin".into(),
+            ..Default::default()
+        };
+        assert_eq!(load_cached(&dir, &config, 4), None);
+
+        store_cached(&dir, &config, "\"v1\"", b"abcd").unwrap();
+        let cached = CachedCopy {
+            etag: "\"v1\"".into(),
+            data: b"abcd".to_vec(),
+        };
+        assert_eq!(load_cached(&dir, &config, 4), Some(cached));
+        // Another size is another file as far as the cache goes
+        assert_eq!(load_cached(&dir, &config, 5), None);
+        let other = DownloadConfig {
+            use_tls: true,
+            ..config.clone()
+        };
+        assert_eq!(load_cached(&dir, &other, 4), None);
+
+        store_cached(&dir, &config, "\"v2\"", b"efgh").unwrap();
+        assert_eq!(load_cached(&dir, &config, 4).unwrap().etag, "\"v2\"");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn quotes_bare_etags() {
+        assert_eq!(if_none_match("\"abc\""), "\"abc\"");
+        assert_eq!(if_none_match("W/\"abc\""), "W/\"abc\"");
+        assert_eq!(if_none_match("abc"), "\"abc\"");
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::error::DownloadError;
+
+const DELIMITER: &[u8] = b"\r\n\r\n";
+
+// Everything we care about from the header section of a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHeaders {
+    // ie "HTTP/1.1", the python server sends HTTP/1.0
+    pub version: String,
+    pub status_code: u16,
+    pub reason: String,
+    pub content_length: Option<u64>,
+    pub content_range: Option<ContentRange>,
+    // Every header including the ones above, keys are lowercased since header names are case
+    // insensitive. Repeated headers get joined with ", " like RFC 7230 section 3.2.2 says
+    pub headers: HashMap<String, String>,
+}
+
+impl ResponseHeaders {
+    // Case insensitive lookup
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    // Transfer-Encoding: chunked, which wins over any Content-Length. chunked has to be the last
+    // coding if it's there at all
+    #[must_use]
+    pub fn is_chunked(&self) -> bool {
+        self.get("transfer-encoding").is_some_and(|codings| {
+            codings
+                .rsplit(',')
+                .next()
+                .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+        })
+    }
+
+    // Whether the server is happy for us to send another request on the same connection
+    #[must_use]
+    pub fn keep_alive(&self) -> bool {
+        match self.get("connection") {
+            Some(v) if v.eq_ignore_ascii_case("close") => false,
+            Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+            // HTTP/1.1 is persistent by default, HTTP/1.0 isn't
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+}
+
+// Content-Range: bytes <start>-<end>/<total>, start and end are inclusive like the Range header.
+// total can be * if the server doesn't know it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub start: u64,
+    pub end: u64,
+    pub total: Option<u64>,
+}
+
+impl ContentRange {
+    // Number of bytes the range covers, never 0 since both ends are inclusive
+    #[must_use]
+    pub fn byte_len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    // Ok(None) for the unsatisfied form "bytes */<total>" that comes with a 416
+    pub fn parse(value: &str) -> Result<Option<Self>, DownloadError> {
+        let invalid =
+            || DownloadError::Parse(format!("Invalid Content-Range header: {value}").into());
+
+        let range = value
+            .trim()
+            .strip_prefix("bytes ")
+            .ok_or_else(invalid)?
+            .trim_start();
+        let (range, total) = range.split_once('/').ok_or_else(invalid)?;
+        if range == "*" {
+            return Ok(None);
+        }
+
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        let start: u64 = start.parse().map_err(|_| invalid())?;
+        let end: u64 = end.parse().map_err(|_| invalid())?;
+        let total = match total {
+            "*" => None,
+            t => Some(t.parse::<u64>().map_err(|_| invalid())?),
+        };
+        if start > end || total.is_some_and(|t| end >= t) {
+            return Err(invalid());
+        }
+        Ok(Some(Self { start, end, total }))
+    }
+}
+
+// Parses the status line and headers at the start of raw, which has to contain the blank line
+// that ends the header section. Anything after it (the body) is ignored
+pub fn parse_response_headers(raw: &[u8]) -> Result<ResponseHeaders, DownloadError> {
+    let head_end = raw
+        .windows(DELIMITER.len())
+        .position(|w| w == DELIMITER)
+        .ok_or_else(|| {
+            DownloadError::Parse("Chunk has no end of headers therefore no body".into())
+        })?;
+    let head = std::str::from_utf8(&raw[..head_end])?;
+
+    let mut lines = head.split("\r\n");
+    let (version, status_code, reason) = parse_status_line(lines.next().unwrap_or_default())?;
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for line in lines {
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| DownloadError::Parse(format!("Malformed header line: {line}").into()))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(DownloadError::Parse(
+                format!("Header line has no name: {line}").into(),
+            ));
+        }
+        let value = value.trim();
+        headers
+            .entry(key.to_ascii_lowercase())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_owned());
+    }
+
+    // If there were several differing Content-Lengths the joined value won't parse, which is
+    // what we want since the spec says to treat that as an error
+    let content_length = match headers.get("content-length") {
+        Some(len) => Some(len.parse::<u64>().map_err(|_| {
+            DownloadError::Parse(format!("Invalid Content-Length header: {len}").into())
+        })?),
+        None => None,
+    };
+    let content_range = match headers.get("content-range") {
+        Some(range) => ContentRange::parse(range)?,
+        None => None,
+    };
+
+    Ok(ResponseHeaders {
+        version,
+        status_code,
+        reason,
+        content_length,
+        content_range,
+        headers,
+    })
+}
+
+// Retry-After is either a number of seconds or an HTTP-date to wait until (RFC 9110 section
+// 10.2.3), now is what the date gets measured from. None if it's neither, a date that's already
+// passed means no wait at all
+#[must_use]
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = parse_http_date(value)?;
+    Some(at.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Just the IMF-fixdate form ie "Sun, 06 Nov 1994 08:49:37 GMT" since that's the one servers are
+// meant to send. The two obsolete forms give None and the caller falls back to its own backoff
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let (_weekday, rest) = value.split_once(", ")?;
+    let parts: Vec<&str> = rest.split(' ').collect();
+    let [day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| m == month)? as u32 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if time.next().is_some() || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60
+    {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar, Howard Hinnant's days_from_civil.
+// Counts the year from March so the leap day is at the end
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Status line looks like "HTTP/1.1 206 Partial Content", the python server actually sends
+// HTTP/1.0 so accept either. Reason phrase is optional in the spec so it can be empty
+fn parse_status_line(line: &str) -> Result<(String, u16, String), DownloadError> {
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(DownloadError::Parse(
+            format!("Unsupported HTTP version in status line: {line}").into(),
+        ));
+    }
+
+    let code = parts.next().unwrap_or_default();
+    if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(DownloadError::Parse(
+            format!("Invalid status code in status line: {line}").into(),
+        ));
+    }
+    let reason = parts.next().unwrap_or_default().to_owned();
+    Ok((version.to_owned(), code.parse()?, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn parses_python_server_response() {
+        let raw = b"HTTP/1.0 206 Partial Content\r\n\
+                    Server: BaseHTTP/0.6 Python/3.12.3\r\n\
+                    Content-Type: application/octet-stream\r\n\
+                    Content-Length: 32768\r\n\
+                    Connection: close\r\n\r\n\x00\x01";
+        let headers = parse_response_headers(raw).unwrap();
+        assert_eq!(headers.version, "HTTP/1.0");
+        assert_eq!(headers.status_code, 206);
+        assert_eq!(headers.reason, "Partial Content");
+        assert_eq!(headers.content_length, Some(32768));
+        assert_eq!(headers.content_range, None);
+        assert_eq!(
+            headers.get("content-type"),
+            Some("application/octet-stream")
+        );
+        assert!(!headers.keep_alive());
+    }
+
+    #[test]
+    fn content_range() {
+        let raw = b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-499/1234\r\n\r\n";
+        let range = parse_response_headers(raw).unwrap().content_range.unwrap();
+        assert_eq!(
+            range,
+            ContentRange {
+                start: 0,
+                end: 499,
+                total: Some(1234)
+            }
+        );
+        assert_eq!(range.byte_len(), 500);
+
+        assert_eq!(
+            ContentRange::parse("bytes 10-19/*").unwrap().unwrap().total,
+            None
+        );
+        assert_eq!(ContentRange::parse("bytes */1234").unwrap(), None);
+        assert!(ContentRange::parse("bytes 20-10/100").is_err());
+        assert!(ContentRange::parse("bytes 0-100/100").is_err());
+        assert!(ContentRange::parse("items 0-1/2").is_err());
+    }
+
+    #[test]
+    fn repeated_headers_are_joined() {
+        let raw = b"HTTP/1.1 200 OK\r\nVary: Accept\r\nvary: Range\r\nSet-Cookie: a=1\r\n\r\n";
+        let headers = parse_response_headers(raw).unwrap();
+        assert_eq!(headers.get("Vary"), Some("Accept, Range"));
+        assert_eq!(headers.get("SET-COOKIE"), Some("a=1"));
+        // HTTP/1.1 defaults to keep-alive
+        assert!(headers.keep_alive());
+    }
+
+    #[test]
+    fn transfer_encoding() {
+        let chunked = |raw: &[u8]| parse_response_headers(raw).unwrap().is_chunked();
+        assert!(chunked(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: Chunked\r\n\r\n"
+        ));
+        assert!(chunked(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"
+        ));
+        assert!(!chunked(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked, gzip\r\n\r\n"
+        ));
+        assert!(!chunked(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"));
+    }
+
+    #[test]
+    fn rejects_conflicting_content_length() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n";
+        assert!(matches!(
+            parse_response_headers(raw),
+            Err(DownloadError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::ZERO));
+        // now is exactly this date so 90 seconds later is a 90 second wait
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:51:07 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::ZERO)
+        );
+        // Already passed
+        assert_eq!(
+            parse_retry_after("Sat, 05 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_709_164_800))
+        );
+
+        for bad in [
+            "soon",
+            "-5",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "Sun, 06 Nov 1994 08:49:37 PST",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 25:49:37 GMT",
+        ] {
+            assert_eq!(parse_retry_after(bad, now), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for raw in [
+            &b"HTTP/1.1 200 OK\r\n"[..],
+            b"HTTP/1.1 200 OK\r\nNo colon here\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\n: empty name\r\n\r\n",
+            b"HTTP/2 200 OK\r\n\r\n",
+        ] {
+            assert!(matches!(
+                parse_response_headers(raw),
+                Err(DownloadError::Parse(_))
+            ));
+        }
+    }
+
+    proptest! {
+        // What the fuzz target checks, a few hundred cases here so it runs with cargo test too
+        #[test]
+        fn garbage_is_only_ever_a_parse_error(mut raw in proptest::collection::vec(any::<u8>(), 0..512)) {
+            raw.extend_from_slice(DELIMITER);
+            let result = parse_response_headers(&raw);
+            prop_assert!(
+                matches!(result, Ok(_) | Err(DownloadError::Parse(_))),
+                "{result:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,23 @@
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod chunk_map;
+pub mod client;
+pub mod codec;
+pub mod config;
+pub mod config_file;
+mod connection;
+pub mod debug;
+pub mod error;
+pub mod etag;
+pub mod headers;
+pub mod manifest;
+pub mod multi;
+pub mod progress;
+#[cfg(feature = "quic")]
+mod quic;
+pub mod redirect;
+pub mod request;
+pub mod retry;
+pub mod sha;
+mod socks5;
+pub mod stats;
@@ -1,74 +1,1311 @@
-use std::env;
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    os::fd::{BorrowedFd, RawFd},
+    sync::Arc,
+};
 
-use crate::client::download_full_data;
-use crate::sha::calculate_sha256;
-use error::DownloadError;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use serde::Serialize;
 
-mod client;
-mod error;
-mod sha;
+use glitchy_http::client::{
+    discover_total_size, download_full_data_with_progress, download_to_file_with_progress,
+    parallel_download_full_data_with_progress, plan_download, resolve_redirects, test_connection,
+};
+#[cfg(feature = "serde")]
+use glitchy_http::client::{download_plan_with_progress, DownloadPlan};
+#[cfg(feature = "quic")]
+use glitchy_http::config::Transport;
+use glitchy_http::config::{
+    parse_bind_addr, parse_chunk_size, parse_max_retries, parse_proxy, parse_retry_delay,
+    parse_socket_buffer, parse_timeout, parse_url, validate_header_name, validate_path,
+    validate_server_addr, validate_user_agent, AddrFamily, DownloadConfig, ServerUrl,
+    DEFAULT_CHUNK_SIZE, DEFAULT_CONNECT_TIMEOUT, DEFAULT_MAX_RETRIES, DEFAULT_PATH,
+    DEFAULT_READ_TIMEOUT, DEFAULT_RETRY_BASE_DELAY, DEFAULT_RETRY_MAX_DELAY, DEFAULT_SERVER_ADDR,
+    DEFAULT_USER_AGENT,
+};
+use glitchy_http::config_file::{load_config, Config};
+use glitchy_http::debug::{hex_dump_file_preview, hex_dump_preview};
+use glitchy_http::error::DownloadError;
+use glitchy_http::progress::{has_progress_fd, PrintProgressObserver, ProgressObserver};
+use glitchy_http::sha::{calculate_hash_file, read_checksum_file, HashAlgorithm};
+use glitchy_http::stats::DownloadStats;
 
-fn main() -> Result<(), DownloadError> {
+// Fallback for --server
+const SERVER_ENV_VAR: &str = "HTTP_CLIENT_SERVER";
+
+// println! unless --quiet or --json wants stdout quiet
+macro_rules! say {
+    ($quiet:expr, $($arg:tt)*) => {
+        if !$quiet {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Errors go to stderr (as JSON with --json) and the exit code says what kind it was, see
+// DownloadError::to_exit_code
+fn main() {
+    init_logging();
     let args: Vec<String> = env::args().collect();
-    // Expect 3 arguments: command, hash, size
+    // Checked up front as well so even a bad argument gets reported as JSON
+    let json = args.iter().any(|arg| arg == "--json");
+    let Err(e) = run_cli(&args, json) else {
+        return;
+    };
+    if json {
+        let error = JsonError {
+            error: e.to_string(),
+            error_kind: e.kind(),
+        };
+        match serde_json::to_string(&error) {
+            Ok(error) => eprintln!("{error}"),
+            Err(_) => eprintln!("Error: {e}"),
+        }
+    } else {
+        eprintln!("Error: {e}");
+    }
+    process::exit(e.to_exit_code().into());
+}
+
+fn run_cli(args: &[String], json: bool) -> Result<(), DownloadError> {
+    // Expect size and hash (both optional), plus any flags (-o, --server etc.) in any position
     // Hash and size are printed by the server so might as well use it
     // Technically speaking, we don't need the hash as we could verify manually but makes it easier
     // to check our work
     // Furthermore, we don't need the size to be passed at the CLI, because
     // judging by the Python HTTP Server: We could just get the total length by not passing in a
-    // range initially. But this reduces my burden slightly.
-    // NOTE: Update, for some reason, my code can't handle truncated data, ends up with a truncated
-    // data error. Perhaps I can figure out how to fix this
+    // range initially. If it's left out that's what discover_total_size does, it just hangs up
+    // after reading the headers so the truncated body doesn't matter
     // Unfortunately the server doesn't follow the HTTP Specification where it should actually send
     // a Content-Range header if a range is being sent to it. ie Content-Range:
     // <start>-<end>/<total>
-    if args.len() != 3 && args.len() != 2 {
-        eprintln!(
-            "Usage: {} <total_size_bytes> [<expected_sha256_hash>]",
-            args[0]
-        );
-        eprintln!("Example: {} 450 986f52d9...", args[0]);
-        eprintln!("Alternatively: {} 450", args[0]);
-        return Err(DownloadError::Args("Invalid number of arguments".into()));
+
+    let cli = match Cli::try_parse_from(args) {
+        Ok(cli) => Ok(cli),
+        // Just clap's first line, the usage and --help hint after it are for people
+        Err(e) if json && e.use_stderr() => {
+            let message = e.render().to_string();
+            let message = message.lines().next().unwrap_or_default();
+            Err(DownloadError::Args(
+                message.trim_start_matches("error: ").to_owned(),
+            ))
+        }
+        // Bad arguments print clap's error and usage, --help and --version end up here too. Exits
+        // with 2 like an Args error does, or 0 for --help
+        Err(e) => {
+            let _ = e.print();
+            process::exit(e.exit_code());
+        }
+    };
+    let summary = cli
+        .and_then(|cli| with_config_file(cli, args))
+        .and_then(run)?;
+
+    if json {
+        let out =
+            serde_json::to_string(&summary).map_err(|e| DownloadError::Logic(e.to_string()))?;
+        println!("{out}");
+    }
+    Ok(())
+}
+
+// The library's retry warnings come through log, they go to stderr as bare lines like they did
+// when it printed them itself. RUST_LOG=info or debug shows more, RUST_LOG=off hides them
+fn init_logging() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"))
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .init();
+}
+
+// What --json prints to stdout once everything's done
+#[derive(Debug, Serialize)]
+struct Summary {
+    total_bytes: u64,
+    // Keyed by the algorithm name ie "sha256": "..."
+    #[serde(flatten)]
+    hash: HashMap<String, String>,
+    duration_ms: u64,
+    chunks_downloaded: u32,
+    retries_total: u32,
+    // How long chunks took, see DownloadStats
+    slowest_chunk_ms: u64,
+    fastest_chunk_ms: u64,
+    average_chunk_ms: f64,
+    // None with --no-verify or --dry-run, otherwise whatever hash is in
+    hash_algorithm: Option<HashAlgorithm>,
+    // There was an expected hash and it matched
+    hash_verified: bool,
+}
+
+// And what it prints to stderr if it isn't
+#[derive(Debug, Serialize)]
+struct JsonError {
+    error: String,
+    error_kind: &'static str,
+}
+
+// Progress as normal unless it's meant to be quiet, or it's going to --progress-fd instead
+#[derive(Debug, Default)]
+struct CliProgress {
+    quiet: bool,
+    to_fd: bool,
+    printer: PrintProgressObserver,
+}
+
+impl ProgressObserver for CliProgress {
+    fn on_progress(&mut self, downloaded: u64, total: u64) {
+        if !self.quiet && !self.to_fd {
+            self.printer.on_progress(downloaded, total);
+        }
+    }
+
+    fn on_status(&mut self, message: &str) {
+        say!(self.quiet, "{message}");
     }
 
-    let total_size: u64 = args[1].parse().map_err(|_| {
-        DownloadError::Args(format!(
-            "Invalid total size provided: {}. Must be a non-negative integer",
-            args[1]
-        ))
-    })?;
+    fn on_throughput(&mut self, bytes_per_sec: f64) {
+        self.printer.on_throughput(bytes_per_sec);
+    }
+}
 
-    println!("Expected Total Size: {total_size} bytes");
+// Parses args again with the --config file's values as the flags' defaults, so they go through
+// the same checks and anything given on the command line still wins. Bad values in the file are
+// Args errors naming the file
+fn with_config_file(cli: Cli, args: &[String]) -> Result<Cli, DownloadError> {
+    let Some(path) = &cli.config else {
+        return Ok(cli);
+    };
+    let file = load_config(path)?;
+    let in_file = |message: String| DownloadError::Args(format!("{}: {message}", path.display()));
 
-    // Download data using the provided total_size. Largest function by far
-    let downloaded_data = download_full_data(total_size)?;
+    let mut command = Cli::command();
+    for (id, value) in config_defaults(&file).map_err(|e| in_file(e.to_string()))? {
+        command = command.mut_arg(id, |arg| arg.default_value(value));
+    }
+    let mut cli = command
+        .try_get_matches_from(args)
+        .and_then(|matches| Cli::from_arg_matches(&matches))
+        .map_err(|e| {
+            let message = e.render().to_string();
+            let message = message.lines().next().unwrap_or_default();
+            in_file(message.trim_start_matches("error: ").to_owned())
+        })?;
+    // The hash is a positional, a default for it would get mixed up with the size in
+    // size_and_hash so run picks it up when there's no other
+    cli.config_hash = file
+        .verification
+        .expected_hash
+        .map(|hash| hash.to_lowercase());
+    Ok(cli)
+}
 
-    // Verify downloaded size just in case (sanity check, perhaps remove this later)
-    if downloaded_data.len() as u64 != total_size {
-        return Err(DownloadError::Logic(format!(
-            "Final downloaded data size ({}) does not match expected size ({})",
-            downloaded_data.len(),
-            total_size,
-        )));
+// Each value in the file as the id of the flag it stands in for and what would be passed to it
+fn config_defaults(file: &Config) -> Result<Vec<(&'static str, String)>, DownloadError> {
+    let Config {
+        server,
+        download,
+        verification,
+    } = file;
+    let mut defaults = Vec::new();
+    let mut set = |id, value: Option<String>| {
+        if let Some(value) = value {
+            defaults.push((id, value));
+        }
+    };
+    let string = |value: &Option<String>| value.clone();
+    let number = |value: Option<u64>| value.map(|n| n.to_string());
+
+    set("server", string(&server.address));
+    set("path", string(&server.path));
+    set("proxy", server.proxy.map(|proxy| proxy.to_string()));
+    #[cfg(feature = "tls")]
+    set("tls", server.use_tls.map(|tls| tls.to_string()));
+    #[cfg(not(feature = "tls"))]
+    if server.use_tls == Some(true) {
+        return Err(DownloadError::Args(
+            "use_tls needs glitchy-http built with the tls feature".into(),
+        ));
+    }
+
+    set("chunk_size", number(download.chunk_size));
+    set("max_retries", number(download.max_retries.map(u64::from)));
+    set("attempts", number(download.attempts.map(u64::from)));
+    set("retry_delay", number(download.retry_delay_ms));
+    set("connect_timeout", number(download.connect_timeout_ms));
+    set("read_timeout", number(download.read_timeout_ms));
+    set("concurrency", download.concurrency.map(|n| n.to_string()));
+    set("pipeline", download.pipeline.map(|n| n.to_string()));
+    set("compressed", download.compressed.map(|on| on.to_string()));
+    set(
+        "follow_redirects",
+        download.follow_redirects.map(|on| on.to_string()),
+    );
+    set(
+        "output",
+        download
+            .output
+            .as_ref()
+            .map(|path| path.display().to_string()),
+    );
+
+    set(
+        "algo",
+        verification
+            .algorithm
+            .map(|algorithm| algorithm.to_string()),
+    );
+    Ok(defaults)
+}
+
+fn run(cli: Cli) -> Result<Summary, DownloadError> {
+    let started = Instant::now();
+    let quiet = cli.quiet || cli.json;
+
+    if let Some(Command::Verify {
+        path,
+        expected_hash,
+        algo,
+    }) = &cli.command
+    {
+        return verify_file(path, Some(expected_hash), *algo, quiet);
+    }
+    let (total_size, mut expected_hash) = cli.size_and_hash()?;
+    if expected_hash.is_none() && cli.checksum_file.is_none() {
+        expected_hash = cli.config_hash.clone();
+    }
+    // Checked here rather than by clap since a lone positional only turns out to be the hash in
+    // size_and_hash
+    let mut guessed_algorithm = None;
+    if let Some(path) = &cli.checksum_file {
+        if expected_hash.is_some() {
+            return Err(DownloadError::Args(
+                "Give the expected hash or --checksum-file, not both".into(),
+            ));
+        }
+        let (hash, name) = read_checksum_file(path)?;
+        // Only worth a warning, the file could have been renamed since the hash was taken
+        let target = cli.output.as_deref().or(cli.verify_file.as_deref());
+        if let (Some(name), Some(target)) = (name, target) {
+            if Path::new(&name).file_name() != target.file_name() {
+                eprintln!(
+                    "Warning: {} is the checksum for {name}, not {}",
+                    path.display(),
+                    target.display()
+                );
+            }
+        }
+        guessed_algorithm = HashAlgorithm::from_hex_len(hash.len());
+        expected_hash = Some(hash);
+    }
+    let algorithm = cli.algo.or(guessed_algorithm).unwrap_or_default();
+    if cli.no_verify && expected_hash.take().is_some() {
+        eprintln!("Warning: Hash verification disabled; provided hash will not be checked.");
+    }
+    if cli.append && cli.output.is_none() {
+        eprintln!("Warning: --append does nothing without -o, there's no file to append to.");
+    }
+    // Nothing to download, just check a file that's already here
+    if let Some(path) = &cli.verify_file {
+        return verify_file(path, expected_hash.as_deref(), algorithm, quiet);
+    }
+
+    // --url is the server and path in one, clap makes sure it doesn't come with either of them.
+    // Otherwise --server beats HTTP_CLIENT_SERVER beats the default
+    let (server_addr, path, use_tls) = match cli.url {
+        Some(url) => (url.server_addr, url.path, url.use_tls),
+        None => {
+            let server_addr = match cli.server {
+                Some(addr) => addr,
+                None => match env::var(SERVER_ENV_VAR) {
+                    Ok(addr) => {
+                        validate_server_addr(&addr)?;
+                        addr
+                    }
+                    Err(_) => DEFAULT_SERVER_ADDR.to_owned(),
+                },
+            };
+            (server_addr, cli.path, false)
+        }
+    };
+    #[cfg(feature = "tls")]
+    let use_tls = use_tls || cli.tls;
+    // QUIC is always encrypted, this is so --insecure goes with it
+    #[cfg(feature = "quic")]
+    let use_tls = use_tls || cli.quic;
+    #[cfg(feature = "tls")]
+    if cli.insecure && !use_tls {
+        return Err(DownloadError::Args(
+            "--insecure needs --tls or an https --url".into(),
+        ));
+    }
+
+    // clap hands them over flat, name value name value...
+    let extra_headers = cli
+        .custom_header
+        .chunks(2)
+        .map(|pair| {
+            validate_header_name(&pair[0])?;
+            Ok((pair[0].clone(), pair[1].clone()))
+        })
+        .collect::<Result<Vec<_>, DownloadError>>()?;
+    let retry_delay = cli.retry_delay.unwrap_or(DEFAULT_RETRY_BASE_DELAY);
+    let config = DownloadConfig {
+        server_addr,
+        path,
+        use_tls,
+        hash_algorithm: algorithm,
+        chunk_size: cli.chunk_size,
+        max_retries: cli.max_retries,
+        retry_base_delay: retry_delay,
+        // Otherwise a big --retry-delay would just get capped back down
+        retry_max_delay: DEFAULT_RETRY_MAX_DELAY.max(retry_delay),
+        connect_timeout: cli.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        read_timeout: cli.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT),
+        concurrency: cli.concurrency,
+        accept_encoding: cli.compressed,
+        quiet,
+        chunk_manifest: cli.chunk_manifest,
+        proxy: cli.proxy,
+        socks5_proxy: cli.socks5,
+        socks5_auth: cli.socks5_user.zip(cli.socks5_password),
+        max_total_attempts: cli.attempts,
+        pipeline_depth: cli.pipeline,
+        prefetch: cli.prefetch,
+        debug_headers: cli.print_headers,
+        compute_hash: !cli.no_verify,
+        tcp_nodelay: cli.tcp_nodelay,
+        tcp_recv_buf: cli.tcp_recv_buf,
+        tcp_send_buf: cli.tcp_send_buf,
+        bind_addr: cli.bind_addr,
+        chunk_map: cli.chunk_map,
+        append: cli.append,
+        follow_redirects: cli.follow_redirects,
+        extra_headers,
+        user_agent: cli.user_agent,
+        addr_family: if cli.ipv4 {
+            AddrFamily::Ipv4Only
+        } else if cli.ipv6 {
+            AddrFamily::Ipv6Only
+        } else {
+            AddrFamily::Any
+        },
+        auth: cli.user.zip(cli.password),
+        #[cfg(feature = "tls")]
+        verify_tls: !cli.insecure,
+        #[cfg(unix)]
+        progress_fd: cli.progress_fd.map(progress_file).transpose()?,
+        #[cfg(feature = "quic")]
+        transport: if cli.quic {
+            Transport::Quic
+        } else {
+            Transport::Tcp
+        },
+        ..Default::default()
+    };
+    #[cfg(feature = "tls")]
+    if cli.insecure {
+        eprintln!("WARNING: TLS certificate verification is disabled");
+    }
+
+    if cli.dry_run {
+        // Finding the size out would mean connecting which is the whole point of not doing it
+        let total_size = total_size.ok_or_else(|| {
+            DownloadError::Args("--dry-run needs the total size, it won't ask the server".into())
+        })?;
+        let plan = plan_download(total_size, &config);
+        #[cfg(feature = "serde")]
+        if let Some(path) = &cli.output_plan {
+            save_plan(&plan, path)?;
+            say!(
+                quiet,
+                "Wrote a plan of {} ranges to {}",
+                plan.ranges.len(),
+                path.display()
+            );
+            return Ok(dry_run_summary(total_size));
+        }
+        for (start, end) in &plan.ranges {
+            println!("{start}-{end}");
+        }
+        return Ok(dry_run_summary(total_size));
     }
+    if cli.test {
+        let rtt = test_connection(&config)?;
+        say!(quiet, "Server reachable, RTT: {} ms", rtt.as_millis());
+        return Ok(Summary {
+            duration_ms: rtt.as_millis() as u64,
+            ..dry_run_summary(0)
+        });
+    }
+    // The plan says where from and how big, so it goes in before anything asks the server
+    #[cfg(feature = "serde")]
+    let plan = cli.from_plan.as_deref().map(load_plan).transpose()?;
+    #[cfg(feature = "serde")]
+    let (config, total_size) = match &plan {
+        Some(plan) => {
+            if total_size.is_some_and(|size| size != plan.total_size) {
+                return Err(DownloadError::Args(format!(
+                    "The plan is for {} bytes, not {}",
+                    plan.total_size,
+                    total_size.unwrap_or_default()
+                )));
+            }
+            let config = DownloadConfig {
+                server_addr: plan.server.clone(),
+                path: plan.path.clone(),
+                chunk_size: plan.chunk_size,
+                ..config
+            };
+            (config, Some(plan.total_size))
+        }
+        None => (config, total_size),
+    };
+    if config.concurrency > 1 && cli.output.is_some() {
+        return Err(DownloadError::Args(
+            "--concurrency only works when downloading into memory, leave out -o".into(),
+        ));
+    }
+    // Once here so finding the size and the download don't both go through the redirects
+    let resolved = resolve_redirects(&config)?.into_owned();
+    if (&resolved.server_addr, &resolved.path, resolved.use_tls)
+        != (&config.server_addr, &config.path, config.use_tls)
+    {
+        say!(
+            quiet,
+            "Redirected to {}://{}{}",
+            if resolved.use_tls { "https" } else { "http" },
+            resolved.server_addr,
+            resolved.path
+        );
+    }
+    let config = resolved;
+
+    let total_size = match total_size {
+        Some(size) => size,
+        None => {
+            say!(quiet, "No size given, asking the server...");
+            discover_total_size(&config)?
+        }
+    };
+
+    say!(quiet, "Expected Total Size: {total_size} bytes");
+    // Twice the size leaves room for a few retries but not a server that never stops. Not by
+    // default with --compressed since the size might be the compressed one and the limit counts
+    // what it unpacks to
+    let config = DownloadConfig {
+        max_total_bytes: cli
+            .limit_total_bytes
+            .or((!config.accept_encoding).then(|| total_size.saturating_mul(2))),
+        ..config
+    };
 
-    // Calculate hash
-    println!("Calculating SHA-256 hash of downloaded data...");
-    let actual_hash = calculate_sha256(&downloaded_data);
-    println!("Actual SHA-256:   {actual_hash}");
-    let expected_hash = args.get(2).map(|p| p.to_lowercase());
+    // Goes to stderr, but --json means something is parsing the output so it stays out of the way
+    let hex_dump = cli.hex_dump && !cli.json;
+    let mut progress = CliProgress {
+        quiet,
+        to_fd: has_progress_fd(&config),
+        printer: PrintProgressObserver::default(),
+    };
+    // Only the planned ranges, and none of the resume checks below since what's in the file
+    // isn't a prefix of it
+    #[cfg(feature = "serde")]
+    let planned = match (&plan, &cli.output) {
+        (Some(plan), Some(path)) => Some(download_plan_with_progress(
+            plan,
+            path,
+            &config,
+            Some(&mut progress),
+        )?),
+        _ => None,
+    };
+    #[cfg(not(feature = "serde"))]
+    let planned: Option<DownloadStats> = None;
+    let mut stats = match (planned, &cli.output) {
+        (Some(stats), _) => stats,
+        // Streams straight to the file, picking up where an earlier run stopped if it's there
+        (None, Some(path)) => {
+            // Checking what's there means hashing it, so with --no-verify whatever's there gets
+            // resumed from as is
+            // Appending means what's there is someone else's first part, so it's never checked
+            // against the hash or thrown away, and one that's already full size is an error
+            let existing = if config.compute_hash && !config.append {
+                check_existing_output(
+                    path,
+                    total_size,
+                    expected_hash.as_deref(),
+                    cli.resume_verify.as_deref(),
+                    algorithm,
+                    quiet,
+                )?
+            } else {
+                None
+            };
+            let stats = match existing {
+                Some(hash) => {
+                    say!(quiet, "Already complete");
+                    DownloadStats {
+                        hash,
+                        hash_algorithm: Some(algorithm),
+                        ..Default::default()
+                    }
+                }
+                None => {
+                    download_to_file_with_progress(total_size, path, &config, Some(&mut progress))?
+                }
+            };
+            if hex_dump {
+                eprint!("{}", hex_dump_file_preview(path)?);
+            }
+            stats
+        }
+        (None, None) => {
+            // Download data using the provided total_size. Largest function by far
+            // The hash gets calculated as the chunks come in
+            let (downloaded_data, stats) = if config.concurrency > 1 {
+                parallel_download_full_data_with_progress(total_size, &config, Some(&mut progress))?
+            } else {
+                download_full_data_with_progress(total_size, &config, Some(&mut progress))?
+            };
+
+            // Verify downloaded size just in case (sanity check, perhaps remove this later). Not
+            // with --compressed, the size the server gave might be the compressed one
+            if !config.accept_encoding && downloaded_data.len() as u64 != total_size {
+                return Err(DownloadError::Logic(format!(
+                    "Final downloaded data size ({}) does not match expected size ({})",
+                    downloaded_data.len(),
+                    total_size,
+                )));
+            }
+            if hex_dump {
+                eprint!("{}", hex_dump_preview(&downloaded_data));
+            }
+            stats
+        }
+    };
+
+    let actual_hash = stats.hash.clone();
+    if config.compute_hash {
+        say!(quiet, "Actual {algorithm}:   {actual_hash}");
+    } else {
+        say!(quiet, "Hash not computed (--no-verify)");
+    }
 
     // Compare hashes together, hope they match
     if let Some(hash) = expected_hash {
         if actual_hash != hash {
             return Err(DownloadError::HashMismatch {
+                algorithm,
                 expected: hash,
                 actual: actual_hash,
             });
         }
-        println!("\nSuccess! Downloaded data matches the expected hash.");
+        say!(
+            quiet,
+            "\nSuccess! Downloaded data matches the expected hash."
+        );
+        stats.hash_verified = true;
+    }
+    // Nothing to say about a file that was already there
+    if stats.chunks_attempted > 0 {
+        say!(quiet, "{stats}");
     }
 
+    if let Some(path) = cli.output {
+        say!(quiet, "Saved {total_size} bytes to {}", path.display());
+    }
+
+    Ok(Summary {
+        total_bytes: total_size,
+        hash: if config.compute_hash {
+            HashMap::from([(algorithm.to_string(), actual_hash)])
+        } else {
+            HashMap::new()
+        },
+        duration_ms: started.elapsed().as_millis() as u64,
+        chunks_downloaded: stats.chunks_attempted,
+        retries_total: stats.total_retries,
+        slowest_chunk_ms: stats.slowest_chunk_ms,
+        fastest_chunk_ms: stats.fastest_chunk_ms,
+        average_chunk_ms: stats.average_chunk_ms,
+        hash_algorithm: stats.hash_algorithm,
+        hash_verified: stats.hash_verified,
+    })
+}
+
+// --dry-run has nothing to report but the size, and --test only how long it took
+fn dry_run_summary(total_size: u64) -> Summary {
+    Summary {
+        total_bytes: total_size,
+        hash: HashMap::new(),
+        duration_ms: 0,
+        chunks_downloaded: 0,
+        retries_total: 0,
+        slowest_chunk_ms: 0,
+        fastest_chunk_ms: 0,
+        average_chunk_ms: 0.0,
+        hash_algorithm: None,
+        hash_verified: false,
+    }
+}
+
+// --output-plan, pretty printed so it's easy to split up or edit by hand
+#[cfg(feature = "serde")]
+fn save_plan(plan: &DownloadPlan, path: &Path) -> Result<(), DownloadError> {
+    let json =
+        serde_json::to_string_pretty(plan).map_err(|e| DownloadError::Logic(e.to_string()))?;
+    fs::write(path, json + "\n")?;
     Ok(())
 }
+
+// --from-plan, a file that isn't there is the Io error from reading it
+#[cfg(feature = "serde")]
+fn load_plan(path: &Path) -> Result<DownloadPlan, DownloadError> {
+    serde_json::from_str(&fs::read_to_string(path)?).map_err(|e| {
+        DownloadError::Parse(format!("Invalid plan file {}: {e}", path.display()).into())
+    })
+}
+
+// verify and --verify-file, hashes what's on disk without going near the network
+fn verify_file(
+    path: &Path,
+    expected_hash: Option<&str>,
+    algorithm: HashAlgorithm,
+    quiet: bool,
+) -> Result<Summary, DownloadError> {
+    let started = Instant::now();
+    let total_size = fs::metadata(path)?.len();
+    let actual_hash = calculate_hash_file(path, algorithm)?;
+    say!(quiet, "{}: {total_size} bytes", path.display());
+    say!(quiet, "Actual {algorithm}:   {actual_hash}");
+    let mut hash_verified = false;
+    if let Some(hash) = expected_hash {
+        if actual_hash != hash {
+            return Err(DownloadError::HashMismatch {
+                algorithm,
+                expected: hash.to_owned(),
+                actual: actual_hash,
+            });
+        }
+        say!(quiet, "\nSuccess! File matches the expected hash.");
+        hash_verified = true;
+    }
+    Ok(Summary {
+        total_bytes: total_size,
+        hash: HashMap::from([(algorithm.to_string(), actual_hash)]),
+        duration_ms: started.elapsed().as_millis() as u64,
+        chunks_downloaded: 0,
+        retries_total: 0,
+        slowest_chunk_ms: 0,
+        fastest_chunk_ms: 0,
+        average_chunk_ms: 0.0,
+        hash_algorithm: Some(algorithm),
+        hash_verified,
+    })
+}
+
+// Looks at whatever an earlier run left at path. Returns its hash if it's already the whole file
+// so there's nothing to do. If the existing bytes don't hash to --resume-verify, or it's full size
+// but doesn't match the expected hash, it's deleted so the download starts over. Anything else is
+// left for download_to_file to resume from (or overwrite if it's too big)
+fn check_existing_output(
+    path: &Path,
+    total_size: u64,
+    expected_hash: Option<&str>,
+    resume_verify: Option<&str>,
+    algorithm: HashAlgorithm,
+    quiet: bool,
+) -> Result<Option<String>, DownloadError> {
+    let len = match fs::metadata(path) {
+        Ok(meta) if meta.is_file() => meta.len(),
+        _ => return Ok(None),
+    };
+    if len == 0 || len > total_size {
+        return Ok(None);
+    }
+
+    let existing_hash = calculate_hash_file(path, algorithm)?;
+
+    if resume_verify.is_some_and(|hash| hash != existing_hash) {
+        say!(
+            quiet,
+            "The {len} bytes already in {} don't match --resume-verify, starting over",
+            path.display()
+        );
+        fs::remove_file(path)?;
+        return Ok(None);
+    }
+    if len < total_size {
+        return Ok(None);
+    }
+    match expected_hash {
+        Some(hash) if hash != existing_hash => {
+            say!(
+                quiet,
+                "{} is full size but doesn't match the expected hash, starting over",
+                path.display()
+            );
+            fs::remove_file(path)?;
+            Ok(None)
+        }
+        _ => Ok(Some(existing_hash)),
+    }
+}
+
+const EXAMPLES: &str = "\
+Examples:
+  glitchy-http 450 986f52d9...                  Download 450 bytes and check the hash
+  glitchy-http 986f52d9...                      Let the server tell us the size
+  glitchy-http -o data.bin 450 986f52d9...      Save to a file, rerunning resumes it
+  glitchy-http verify data.bin 986f52d9...      Check a file you already have
+  glitchy-http --chunk-size 16k 450 986f52d9... Smaller chunks
+  glitchy-http --json 450 986f52d9...           Print one JSON summary for scripts";
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Downloads a file in chunks from a server that keeps cutting responses short, then \
+             checks its hash",
+    after_help = EXAMPLES,
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Both come in as plain strings since a lone positional can be either, size_and_hash sorts
+    // them out
+    #[arg(
+        help = "Size of the file in bytes. Left out it's asked from the server, and if it's the \
+                only argument and isn't a number it's taken as the expected hash instead"
+    )]
+    total_size: Option<String>,
+    #[arg(help = "Hash the downloaded data has to match, in hex")]
+    expected_hash: Option<String>,
+    // [verification] expected_hash from --config, see with_config_file
+    #[arg(skip)]
+    config_hash: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Read defaults from this TOML file with [server], [download] and [verification] \
+                sections. Flags given here win over it"
+    )]
+    config: Option<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        value_parser = |value: &str| plain(validate_output_path(value)),
+        help = "Write the data to this file as it arrives. If it's already partly there the \
+                download carries on from where it stopped"
+    )]
+    output: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "HASH",
+        requires = "output",
+        value_parser = lowercase,
+        help = "Hash the bytes already in the output file have to match before resuming, \
+                otherwise it starts over"
+    )]
+    resume_verify: Option<String>,
+    #[arg(
+        long,
+        requires = "output",
+        help = "Keep track of what's been written in <output>.chunkmap and resume from that, \
+                rather than the file's length. Slower, every chunk gets flushed"
+    )]
+    chunk_map: bool,
+    #[arg(
+        long,
+        conflicts_with = "resume_verify",
+        help = "Append to the output file, fetching only from its length to the total. The hash \
+                covers the whole file, what was there included"
+    )]
+    append: bool,
+    #[arg(
+        long = "hash-algo",
+        visible_alias = "algo",
+        value_name = "ALGORITHM",
+        help = "sha256, sha512 or blake3 [default: sha256, or going by the hash's length with \
+                --checksum-file]"
+    )]
+    algo: Option<HashAlgorithm>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Read the expected hash from a sha256sum style '<hash>  <file>' file instead of \
+                passing it"
+    )]
+    checksum_file: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with_all = ["resume_verify", "verify_file"],
+        help = "Don't hash the download at all, even with an expected hash. For benchmarking"
+    )]
+    no_verify: bool,
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        value_parser = |value: &str| plain(parse_server(value)),
+        help = format!("Server to download from, falls back to ${SERVER_ENV_VAR} then \
+                        {DEFAULT_SERVER_ADDR}")
+    )]
+    server: Option<String>,
+    #[arg(
+        long,
+        value_name = "URL",
+        value_parser = |value: &str| plain(parse_url(value)),
+        conflicts_with_all = ["server", "path"],
+        help = "Server and path in one, ie http://127.0.0.1:8080/files/data.bin. https:// turns on TLS"
+    )]
+    url: Option<ServerUrl>,
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        value_parser = |value: &str| plain(parse_proxy(value)),
+        help = "Go through this HTTP proxy, HTTPS gets tunnelled through it with CONNECT"
+    )]
+    proxy: Option<SocketAddr>,
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        value_parser = |value: &str| plain(parse_proxy(value)),
+        conflicts_with = "proxy",
+        help = "Go through this SOCKS5 proxy, ie 127.0.0.1:9050 for Tor. The proxy looks up the \
+                server's name"
+    )]
+    socks5: Option<SocketAddr>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        requires_all = ["socks5", "socks5_password"],
+        help = "Username for the SOCKS5 proxy"
+    )]
+    socks5_user: Option<String>,
+    #[arg(long, requires = "socks5_user", help = "Password for the SOCKS5 proxy")]
+    socks5_password: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "ipv6",
+        help = "Only connect to the server's IPv4 addresses"
+    )]
+    ipv4: bool,
+    #[arg(long, help = "Only connect to the server's IPv6 addresses")]
+    ipv6: bool,
+    #[arg(
+        long,
+        help = "Set TCP_NODELAY so each request goes out straight away instead of waiting on \
+                Nagle's algorithm. Lower latency with small chunks for more packets and CPU"
+    )]
+    tcp_nodelay: bool,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = |value: &str| plain(parse_socket_buffer(value, "receive")),
+        help = "Set SO_RCVBUF on every connection, ie 4m for a fast link with a long round trip. \
+                k and m suffixes work"
+    )]
+    tcp_recv_buf: Option<usize>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = |value: &str| plain(parse_socket_buffer(value, "send")),
+        help = "Set SO_SNDBUF on every connection. k and m suffixes work"
+    )]
+    tcp_send_buf: Option<usize>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = |value: &str| plain(parse_bind_addr(value)),
+        help = "Connect from this local address, ie 192.168.1.5:0 to go out a particular interface. \
+                Port 0 or no port lets the OS pick"
+    )]
+    bind_addr: Option<SocketAddr>,
+    #[cfg(feature = "quic")]
+    #[arg(
+        long,
+        conflicts_with_all = ["proxy", "socks5"],
+        help = "Send the requests over QUIC instead of TCP. Still HTTP/1.1, not HTTP/3, so the \
+                server has to speak that over QUIC"
+    )]
+    quic: bool,
+    #[arg(
+        long,
+        value_name = "URL-PATH",
+        value_parser = |value: &str| plain(parse_path(value)),
+        default_value = DEFAULT_PATH,
+        help = "Path to ask the server for, ie /files/data.bin"
+    )]
+    path: String,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = |value: &str| plain(parse_chunk_size(value)),
+        default_value_t = DEFAULT_CHUNK_SIZE,
+        help = "Bytes asked for per request, a power of two from 4k to 16m. k and m suffixes work"
+    )]
+    chunk_size: u64,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = |value: &str| plain(parse_max_retries(value)),
+        default_value_t = DEFAULT_MAX_RETRIES,
+        help = "Attempts per chunk before giving up, 1 to 1000"
+    )]
+    max_retries: u32,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = |value: &str| plain(parse_attempts(value)),
+        help = "Requests the whole download can make, retries of every chunk included. No limit \
+                unless given"
+    )]
+    attempts: Option<u32>,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = |value: &str| plain(parse_total_bytes(value)),
+        help = "Most bytes to take from the server, retries included, before giving up on it \
+                [default: twice the total size]"
+    )]
+    limit_total_bytes: Option<u64>,
+    #[arg(
+        long,
+        value_name = "MS",
+        value_parser = |value: &str| plain(parse_retry_delay(value)),
+        help = format!("Milliseconds before the first retry, doubling after that [default: {}]",
+                       DEFAULT_RETRY_BASE_DELAY.as_millis())
+    )]
+    retry_delay: Option<Duration>,
+    #[arg(
+        long,
+        value_name = "MS",
+        value_parser = |value: &str| plain(parse_timeout(value, "connect")),
+        help = format!("Milliseconds to wait for a connection, and for each write, 100 to 300000 \
+                        [default: {}]", DEFAULT_CONNECT_TIMEOUT.as_millis())
+    )]
+    connect_timeout: Option<Duration>,
+    #[arg(
+        long,
+        value_name = "MS",
+        value_parser = |value: &str| plain(parse_timeout(value, "read")),
+        help = format!("Milliseconds to wait for each read before giving up on the attempt, 100 \
+                        to 300000 [default: {}]", DEFAULT_READ_TIMEOUT.as_millis())
+    )]
+    read_timeout: Option<Duration>,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = |value: &str| plain(parse_concurrency(value)),
+        default_value_t = 1,
+        help = "Connections to download over at once, more than 1 doesn't work with -o"
+    )]
+    concurrency: usize,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = |value: &str| plain(parse_pipeline(value)),
+        default_value_t = 1,
+        help = "Chunk requests to send before reading the responses (HTTP pipelining). Ignored \
+                with --concurrency"
+    )]
+    pipeline: usize,
+    #[arg(
+        long,
+        help = "Request the next chunk on a second connection while the last one is being written. \
+                Ignored with --concurrency or --pipeline"
+    )]
+    prefetch: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Only print a JSON summary at the end, errors go to stderr as JSON too"
+    )]
+    json: bool,
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "Nothing on stdout at all, errors still go to stderr"
+    )]
+    quiet: bool,
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_name = "FD",
+        value_parser = |value: &str| plain(parse_progress_fd(value)),
+        help = "Write '<downloaded>\\n<total>\\n' to this open file descriptor after every chunk instead \
+                of the progress line, ie for pv or dialog --gauge"
+    )]
+    progress_fd: Option<RawFd>,
+    #[arg(
+        long,
+        help = "Print the byte range of every chunk that would be requested and stop"
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        conflicts_with = "dry_run",
+        help = "Check the server answers and print how long a request took, without downloading"
+    )]
+    test: bool,
+    #[cfg(feature = "serde")]
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "dry_run",
+        help = "With --dry-run, write the plan to this file as JSON instead of printing it, for \
+                --from-plan"
+    )]
+    output_plan: Option<PathBuf>,
+    #[cfg(feature = "serde")]
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "output",
+        conflicts_with_all = ["dry_run", "url", "server", "path", "chunk_size", "append"],
+        help = "Download just the ranges in this JSON plan into -o, from the server and path it \
+                names. Ranges the file already has non-zero bytes in are skipped"
+    )]
+    from_plan: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Check every chunk against this file of '<start>-<end> <sha256>' lines as it arrives, \
+                retrying the ones that don't match"
+    )]
+    chunk_manifest: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Ask for gzip or deflate compressed responses and decompress them"
+    )]
+    compressed: bool,
+    #[arg(
+        short = 'L',
+        long,
+        help = "Follow 301, 302, 307 and 308 redirects, up to 5 of them"
+    )]
+    follow_redirects: bool,
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["NAME", "VALUE"],
+        action = clap::ArgAction::Append,
+        help = "Send this header with every request, ie --custom-header Authorization 'Bearer <token>'. \
+                Can be given more than once"
+    )]
+    custom_header: Vec<String>,
+    #[arg(
+        long,
+        help = "Hex dump what was downloaded to stderr, only the first and last 256 bytes past 4 KiB. \
+                Ignored with --json"
+    )]
+    hex_dump: bool,
+    #[arg(
+        long,
+        conflicts_with = "json",
+        help = "Print the first chunk's request and response headers to stderr, Authorization \
+                masked, and one line for every chunk after"
+    )]
+    print_headers: bool,
+    #[arg(
+        long,
+        value_name = "VALUE",
+        default_value = DEFAULT_USER_AGENT,
+        value_parser = |value: &str| plain(parse_user_agent(value)),
+        help = "User-Agent to send with every request, empty to send none"
+    )]
+    user_agent: String,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Hash this file instead of downloading anything, same as verify"
+    )]
+    verify_file: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    #[arg(long, help = "Talk HTTPS to the server instead of plain HTTP")]
+    tls: bool,
+    #[cfg(feature = "tls")]
+    #[arg(
+        short = 'k',
+        long,
+        help = "Don't check the server's certificate, for development servers with a self-signed \
+                one. Anyone in between could read and change the download"
+    )]
+    insecure: bool,
+    #[arg(
+        long,
+        value_name = "NAME",
+        requires = "password",
+        help = "Username for HTTP Basic auth"
+    )]
+    user: Option<String>,
+    #[arg(long, requires = "user", help = "Password for HTTP Basic auth")]
+    password: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    #[command(about = "Hash a file that's already on disk and check it against the expected hash")]
+    Verify {
+        #[arg(help = "File to check")]
+        path: PathBuf,
+        #[arg(value_parser = lowercase, help = "Hash it has to match, in hex")]
+        expected_hash: String,
+        #[arg(
+            long,
+            visible_alias = "hash-algo",
+            value_name = "ALGORITHM",
+            default_value_t,
+            help = "sha256, sha512 or blake3"
+        )]
+        algo: HashAlgorithm,
+    },
+}
+
+impl Cli {
+    // [<total_size>] [<expected_hash>]. With a single positional it's the size if it's all digits,
+    // otherwise it's the hash. A hex hash with no letters at all isn't going to happen
+    fn size_and_hash(&self) -> Result<(Option<u64>, Option<String>), DownloadError> {
+        let is_size = |arg: &str| arg.bytes().all(|b| b.is_ascii_digit());
+        let (total_size, expected_hash) =
+            match (self.total_size.as_deref(), self.expected_hash.as_deref()) {
+                (Some(hash), None) if !is_size(hash) => (None, Some(hash)),
+                other => other,
+            };
+
+        let total_size = total_size
+            .map(|size| {
+                size.parse::<u64>().map_err(|_| {
+                    DownloadError::Args(format!(
+                        "Invalid total size provided: {size}. Must be a non-negative integer"
+                    ))
+                })
+            })
+            .transpose()?;
+        Ok((total_size, expected_hash.map(str::to_lowercase)))
+    }
+}
+
+// Clap already says which argument was bad so it only wants the message, not "Argument Error:"
+// in front of it as well
+fn plain<T>(result: Result<T, DownloadError>) -> Result<T, String> {
+    result.map_err(|e| match e {
+        DownloadError::Args(message) => message,
+        other => other.to_string(),
+    })
+}
+
+// Hashes get compared as lowercase hex
+fn lowercase(value: &str) -> Result<String, DownloadError> {
+    Ok(value.to_lowercase())
+}
+
+fn parse_server(value: &str) -> Result<String, DownloadError> {
+    validate_server_addr(value)?;
+    Ok(value.to_owned())
+}
+
+fn parse_path(value: &str) -> Result<String, DownloadError> {
+    validate_path(value)?;
+    Ok(value.to_owned())
+}
+
+fn parse_user_agent(value: &str) -> Result<String, DownloadError> {
+    validate_user_agent(value)?;
+    Ok(value.to_owned())
+}
+
+fn parse_attempts(value: &str) -> Result<u32, DownloadError> {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(DownloadError::Args(format!(
+            "Invalid attempts: {value}. Must be at least 1"
+        ))),
+    }
+}
+
+fn parse_total_bytes(value: &str) -> Result<u64, DownloadError> {
+    match value.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(DownloadError::Args(format!(
+            "Invalid total bytes limit: {value}. Must be at least 1"
+        ))),
+    }
+}
+
+// Has to be open already, ie 3>pipe on the command line, otherwise every write would just fail
+#[cfg(unix)]
+fn parse_progress_fd(value: &str) -> Result<RawFd, DownloadError> {
+    let fd = value
+        .parse::<RawFd>()
+        .ok()
+        .filter(|fd| *fd >= 0)
+        .ok_or_else(|| {
+            DownloadError::Args(format!(
+                "Invalid progress fd: {value}. Must be a file descriptor number"
+            ))
+        })?;
+    if fs::metadata(format!("/dev/fd/{fd}")).is_err() {
+        return Err(DownloadError::Args(format!(
+            "File descriptor {fd} isn't open"
+        )));
+    }
+    Ok(fd)
+}
+
+// A copy of the fd for the config to own, the one from the command line stays as it was
+#[cfg(unix)]
+fn progress_file(fd: RawFd) -> Result<Arc<fs::File>, DownloadError> {
+    // SAFETY: parse_progress_fd checked it's open, and it's only borrowed long enough to dup it.
+    // Nothing in here ever closes the original
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    Ok(Arc::new(fs::File::from(fd.try_clone_to_owned()?)))
+}
+
+fn parse_pipeline(value: &str) -> Result<usize, DownloadError> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(DownloadError::Args(format!(
+            "Invalid pipeline depth: {value}. Must be at least 1"
+        ))),
+    }
+}
+
+fn parse_concurrency(value: &str) -> Result<usize, DownloadError> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(DownloadError::Args(format!(
+            "Invalid concurrency: {value}. Must be at least 1"
+        ))),
+    }
+}
+
+// Catch a missing directory before we spend ages downloading, not after
+fn validate_output_path(path: &str) -> Result<PathBuf, DownloadError> {
+    let path = PathBuf::from(path);
+    let parent = match path.parent() {
+        // A bare file name has an empty parent which means the current directory
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if !parent.is_dir() {
+        return Err(DownloadError::Args(format!(
+            "Output directory does not exist: {}",
+            parent.display()
+        )));
+    }
+    Ok(path)
+}
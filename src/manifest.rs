@@ -0,0 +1,122 @@
+// Per chunk SHA-256 hashes, so a chunk that came back the right length but with the wrong bytes
+// gets retried straight away instead of the whole file failing its hash at the end. The file is
+// one line per chunk:
+// 0-32767 2dd68fc089b24751559de2d45463341a780dd388f70d4053a5d49cef2cc19e6a
+// with inclusive ranges like the Range header. Blank lines and lines starting with # are skipped
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::error::DownloadError;
+use crate::sha::{calculate_sha256, HashAlgorithm};
+
+// (start, end) of each chunk to its lowercase hex hash
+pub type ChunkManifest = HashMap<(u64, u64), String>;
+
+pub fn load_manifest(path: &Path) -> Result<ChunkManifest, DownloadError> {
+    parse_manifest(&fs::read_to_string(path)?)
+}
+
+pub fn parse_manifest(text: &str) -> Result<ChunkManifest, DownloadError> {
+    let mut manifest = ChunkManifest::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |why: &str| {
+            DownloadError::Parse(format!("Chunk manifest line {}: {why}: {line}", i + 1).into())
+        };
+
+        let (range, hash) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| invalid("Expected <start>-<end> <hash>"))?;
+        let (start, end) = range
+            .split_once('-')
+            .ok_or_else(|| invalid("Expected <start>-<end> <hash>"))?;
+        let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end),
+            _ => return Err(invalid("Invalid range")),
+        };
+        let hash = hash.trim();
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid("Expected a SHA-256 hash"));
+        }
+        if manifest
+            .insert((start, end), hash.to_ascii_lowercase())
+            .is_some()
+        {
+            return Err(invalid("Range is listed twice"));
+        }
+    }
+    Ok(manifest)
+}
+
+// Checks data, which was downloaded from start, against its line in the manifest. A range the
+// manifest doesn't list (ie a different chunk size) has nothing to check against so it passes
+pub fn verify_chunk(
+    start: u64,
+    data: &[u8],
+    manifest: &ChunkManifest,
+) -> Result<(), DownloadError> {
+    let Some(end) = (start + data.len() as u64).checked_sub(1) else {
+        return Ok(());
+    };
+    let Some(expected) = manifest.get(&(start, end)) else {
+        return Ok(());
+    };
+    let actual = calculate_sha256(data);
+    if actual == *expected {
+        Ok(())
+    } else {
+        Err(DownloadError::HashMismatch {
+            algorithm: HashAlgorithm::Sha256,
+            expected: expected.clone(),
+            actual,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_verifies() {
+        let hello = calculate_sha256(b"hello");
+        let text = format!(
+            "# made by hand\n0-4 {}\n\n5-9 {}\n",
+            hello.to_uppercase(),
+            calculate_sha256(b"world")
+        );
+        let manifest = parse_manifest(&text).unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(manifest[&(0, 4)], hello);
+
+        assert!(verify_chunk(0, b"hello", &manifest).is_ok());
+        assert!(verify_chunk(5, b"world", &manifest).is_ok());
+        match verify_chunk(0, b"jello", &manifest) {
+            Err(DownloadError::HashMismatch { expected, .. }) => assert_eq!(expected, hello),
+            other => panic!("Expected HashMismatch, got {other:?}"),
+        }
+        // Not in there so nothing to say about it
+        assert!(verify_chunk(0, b"hell", &manifest).is_ok());
+        assert!(verify_chunk(0, b"", &manifest).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        let hash = calculate_sha256(b"x");
+        for bad in [
+            "0-4".to_owned(),
+            format!("04 {hash}"),
+            format!("4-0 {hash}"),
+            format!("a-4 {hash}"),
+            "0-4 abc".to_owned(),
+            format!("0-4 {hash}\n0-4 {hash}"),
+        ] {
+            assert!(
+                matches!(parse_manifest(&bad), Err(DownloadError::Parse(_))),
+                "{bad}"
+            );
+        }
+    }
+}
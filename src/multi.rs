@@ -0,0 +1,143 @@
+// Several files off the same server one after another (or a few at a time), ie the parts of a
+// package. They all share one max_total_attempts budget rather than getting one each
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+};
+
+use crate::client::{download_to_file, download_to_file_with_progress};
+use crate::config::DownloadConfig;
+use crate::error::DownloadError;
+use crate::stats::DownloadStats;
+
+// One file to fetch. url_path replaces DownloadConfig::path, the hash is in the config's
+// hash_algorithm and gets checked once the file's done
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpec {
+    pub url_path: String,
+    pub total_size: u64,
+    pub expected_hash: Option<String>,
+    pub output_path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct MultiFileDownloader {
+    config: DownloadConfig,
+    files: Vec<FileSpec>,
+}
+
+impl MultiFileDownloader {
+    #[must_use]
+    pub fn new(config: DownloadConfig) -> Self {
+        Self {
+            config,
+            files: Vec::new(),
+        }
+    }
+
+    pub fn add_file(&mut self, spec: FileSpec) {
+        self.files.push(spec);
+    }
+
+    #[must_use]
+    pub fn files(&self) -> &[FileSpec] {
+        &self.files
+    }
+
+    // Downloads every file in the order they were added, one result each in the same order. A
+    // file failing doesn't stop the rest, though once the attempt budget's gone they all fail
+    #[must_use]
+    pub fn download_all(&self) -> Vec<Result<DownloadStats, DownloadError>> {
+        let config = self.shared_config();
+        self.files
+            .iter()
+            .map(|spec| {
+                download_file(spec, &file_config(&config, spec), |path, config| {
+                    download_to_file(spec.total_size, path, config)
+                })
+            })
+            .collect()
+    }
+
+    // download_all with up to config.concurrency files in flight, each over its own connection.
+    // Nothing gets printed since the files would talk over each other. Results are still in the
+    // order the files were added
+    #[must_use]
+    pub fn download_all_parallel(&self) -> Vec<Result<DownloadStats, DownloadError>> {
+        let config = self.shared_config();
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(
+            (0..self.files.len())
+                .map(|_| Err(DownloadError::Logic("File was never downloaded".into())))
+                .collect::<Vec<_>>(),
+        );
+        let workers = self.config.concurrency.clamp(1, self.files.len().max(1));
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    // Each worker takes whichever file's next until there are none left
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(spec) = self.files.get(index) else {
+                            break;
+                        };
+                        let result =
+                            download_file(spec, &file_config(&config, spec), |path, config| {
+                                download_to_file_with_progress(spec.total_size, path, config, None)
+                            });
+                        results.lock().unwrap_or_else(PoisonError::into_inner)[index] = result;
+                    }
+                });
+            }
+        });
+        results.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // The config every file starts from, with one attempt counter for all of them unless the
+    // caller already set their own
+    fn shared_config(&self) -> DownloadConfig {
+        let mut config = self.config.clone();
+        config
+            .attempt_counter
+            .get_or_insert_with(|| Arc::new(AtomicU32::new(0)));
+        config
+    }
+}
+
+fn file_config(config: &DownloadConfig, spec: &FileSpec) -> DownloadConfig {
+    DownloadConfig {
+        path: spec.url_path.clone(),
+        ..config.clone()
+    }
+}
+
+// Runs download for one file and checks its hash if there's one to check against
+fn download_file<F>(
+    spec: &FileSpec,
+    config: &DownloadConfig,
+    download: F,
+) -> Result<DownloadStats, DownloadError>
+where
+    F: FnOnce(&Path, &DownloadConfig) -> Result<DownloadStats, DownloadError>,
+{
+    let mut stats = download(&spec.output_path, config)?;
+    match &spec.expected_hash {
+        Some(expected) if config.compute_hash => {
+            if !expected.eq_ignore_ascii_case(&stats.hash) {
+                return Err(DownloadError::HashMismatch {
+                    algorithm: config.hash_algorithm,
+                    expected: expected.to_ascii_lowercase(),
+                    actual: stats.hash,
+                });
+            }
+            stats.hash_verified = true;
+            Ok(stats)
+        }
+        _ => Ok(stats),
+    }
+}
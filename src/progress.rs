@@ -0,0 +1,240 @@
+use std::{
+    io::{self, Write},
+    time::Instant,
+};
+
+use crate::config::DownloadConfig;
+use crate::error::DownloadError;
+use crate::stats::rate;
+
+// How much each chunk's speed counts towards the estimate. 0.25 weighs it about like an average
+// over the last 8 chunks would, without having to keep them around
+const ALPHA: f64 = 0.25;
+
+// Gets told how far along a download is after every chunk, so library users can draw their own
+// progress bar instead of us printing to stdout
+pub trait ProgressObserver {
+    fn on_progress(&mut self, downloaded: u64, total: u64);
+
+    // One line descriptions of what's happening ie "Attempting to download 450 bytes...".
+    // Ignored unless you want them
+    fn on_status(&mut self, _message: &str) {}
+
+    // A chunk failed with error and is about to be tried again. The warning still gets logged
+    // either way, this is for counting them
+    fn on_retry(&mut self, _error: &DownloadError) {}
+
+    // How fast the last chunk's response came off the connection, not counting the connect or
+    // sending the request. Comes before the on_progress for that chunk, only from the single
+    // connection downloads and not for pipelined chunks
+    fn on_throughput(&mut self, _bytes_per_sec: f64) {}
+}
+
+// An exponential moving average of the download speed, fed how far along things are after each
+// chunk
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BandwidthEstimate {
+    bytes_per_sec: Option<f64>,
+    last: Option<(Instant, u64)>,
+}
+
+impl BandwidthEstimate {
+    // The speed since the last update counts for ALPHA of the new estimate. The first update only
+    // gives it something to measure from. Going backwards means it's a new download, which starts
+    // over
+    pub fn update(&mut self, downloaded: u64) -> Option<f64> {
+        self.update_at(Instant::now(), downloaded)
+    }
+
+    fn update_at(&mut self, now: Instant, downloaded: u64) -> Option<f64> {
+        match self.last {
+            Some((_, before)) if downloaded < before => *self = Self::default(),
+            Some((then, before)) => {
+                let secs = now.duration_since(then).as_secs_f64();
+                // Two updates in the same instant don't say anything about the speed
+                if secs <= 0.0 {
+                    return self.bytes_per_sec;
+                }
+                self.add_sample((downloaded - before) as f64 / secs);
+            }
+            None => {}
+        }
+        self.last = Some((now, downloaded));
+        self.bytes_per_sec
+    }
+
+    // A speed measured some other way, ie one chunk's on_throughput, counting for ALPHA of the
+    // new estimate the same as update's own
+    pub fn add_sample(&mut self, bytes_per_sec: f64) -> f64 {
+        let estimate = match self.bytes_per_sec {
+            Some(ema) => ALPHA * bytes_per_sec + (1.0 - ALPHA) * ema,
+            None => bytes_per_sec,
+        };
+        self.bytes_per_sec = Some(estimate);
+        estimate
+    }
+
+    #[must_use]
+    pub fn bytes_per_sec(&self) -> Option<f64> {
+        self.bytes_per_sec
+    }
+
+    // How long the rest would take at the current estimate, None until there is one or while
+    // it's stalled
+    #[must_use]
+    pub fn eta_secs(&self, remaining: u64) -> Option<u64> {
+        self.bytes_per_sec
+            .filter(|&speed| speed > 0.0)
+            .map(|speed| (remaining as f64 / speed).ceil() as u64)
+    }
+}
+
+// What the binary uses, the "Downloaded: 42.00% (x/y) bytes  ~2.1 MiB/s  ETA 00:05" line that
+// rewrites itself. The speed is the chunks' on_throughput averaged when the download gives it,
+// otherwise worked out from how far along it is and then it shows up from the second chunk on
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PrintProgressObserver {
+    bandwidth: BandwidthEstimate,
+    // Whether on_throughput has been heard from, after that on_progress leaves the speed alone
+    measured: bool,
+}
+
+impl ProgressObserver for PrintProgressObserver {
+    fn on_progress(&mut self, downloaded: u64, total: u64) {
+        let percentage = (downloaded as f64 / total as f64) * 100.0;
+        print!("\rDownloaded: {percentage:.2}% ({downloaded}/{total}) bytes");
+        let speed = if self.measured {
+            self.bandwidth.bytes_per_sec()
+        } else {
+            self.bandwidth.update(downloaded)
+        };
+        if let Some(speed) = speed {
+            print!("  ~{}", rate(speed));
+            if let Some(eta) = self.bandwidth.eta_secs(total.saturating_sub(downloaded)) {
+                print!("  ETA {}", minutes_seconds(eta));
+            }
+        }
+        // Clears whatever's left of a longer line from before
+        print!("    ");
+        if downloaded >= total {
+            println!("\nDownload complete.");
+        }
+        // Ensure progress is displayed immediately, not worth failing a download over though
+        let _ = io::stdout().flush();
+    }
+
+    fn on_status(&mut self, message: &str) {
+        println!("{message}");
+    }
+
+    fn on_throughput(&mut self, bytes_per_sec: f64) {
+        self.measured = true;
+        self.bandwidth.add_sample(bytes_per_sec);
+    }
+}
+
+// mm:ss, minutes keep going past 59 rather than turning into hours
+fn minutes_seconds(secs: u64) -> String {
+    format!("{:02}:{:02}", secs / 60, secs % 60)
+}
+
+// What the download functions that don't take an observer use. PrintProgressObserver unless
+// config.quiet, just its status lines if the progress is going to config.progress_fd instead
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DefaultObserver {
+    progress: bool,
+    printer: PrintProgressObserver,
+}
+
+pub(crate) fn default_observer(config: &DownloadConfig) -> Option<DefaultObserver> {
+    (!config.quiet).then_some(DefaultObserver {
+        progress: !has_progress_fd(config),
+        printer: PrintProgressObserver::default(),
+    })
+}
+
+impl ProgressObserver for DefaultObserver {
+    fn on_progress(&mut self, downloaded: u64, total: u64) {
+        if self.progress {
+            self.printer.on_progress(downloaded, total);
+        }
+    }
+
+    fn on_status(&mut self, message: &str) {
+        self.printer.on_status(message);
+    }
+
+    fn on_throughput(&mut self, bytes_per_sec: f64) {
+        self.printer.on_throughput(bytes_per_sec);
+    }
+}
+
+// Whether config.progress_fd is set, never where there's no such thing
+#[cfg(unix)]
+#[must_use]
+pub fn has_progress_fd(config: &DownloadConfig) -> bool {
+    config.progress_fd.is_some()
+}
+
+#[cfg(not(unix))]
+#[must_use]
+pub fn has_progress_fd(_config: &DownloadConfig) -> bool {
+    false
+}
+
+// Writes <downloaded>\n<total>\n to config.progress_fd if there is one, the same numbers the
+// observer gets. A reader that's gone away isn't worth failing the download over
+#[cfg(unix)]
+pub(crate) fn report_fd(config: &DownloadConfig, downloaded: u64, total: u64) {
+    if let Some(out) = &config.progress_fd {
+        let _ = (&**out).write_all(format!("{downloaded}\n{total}\n").as_bytes());
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn report_fd(_config: &DownloadConfig, _downloaded: u64, _total: u64) {}
+
+// Closures work as observers too, handy for tests and quick logging
+impl<F: FnMut(u64, u64)> ProgressObserver for F {
+    fn on_progress(&mut self, downloaded: u64, total: u64) {
+        self(downloaded, total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bandwidth_is_a_moving_average() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut bandwidth = BandwidthEstimate::default();
+        assert_eq!(bandwidth.update_at(start, 0), None);
+        assert_eq!(bandwidth.eta_secs(100), None);
+
+        // 1000 B/s to begin with, then a chunk at 5000 B/s only moves it a quarter of the way
+        assert_eq!(bandwidth.update_at(at(1000), 1000), Some(1000.0));
+        assert_eq!(bandwidth.update_at(at(2000), 6000), Some(2000.0));
+        assert_eq!(bandwidth.eta_secs(3000), Some(2));
+        assert_eq!(bandwidth.eta_secs(3001), Some(2));
+        assert_eq!(bandwidth.eta_secs(4001), Some(3));
+
+        // Same instant doesn't change anything, going backwards starts over
+        assert_eq!(bandwidth.update_at(at(2000), 7000), Some(2000.0));
+        assert_eq!(bandwidth.update_at(at(3000), 10), None);
+        assert_eq!(bandwidth.bytes_per_sec(), None);
+
+        // Measured speeds go into the same average
+        assert_eq!(bandwidth.add_sample(4000.0), 4000.0);
+        assert_eq!(bandwidth.add_sample(8000.0), 5000.0);
+    }
+
+    #[test]
+    fn eta_format() {
+        assert_eq!(minutes_seconds(0), "00:00");
+        assert_eq!(minutes_seconds(65), "01:05");
+        assert_eq!(minutes_seconds(6000), "100:00");
+    }
+}
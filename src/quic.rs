@@ -0,0 +1,174 @@
+// Transport::Quic. quinn is async so everything here blocks on a single threaded tokio runtime
+// that belongs to the QUIC connection, the rest of the client just sees another Read + Write. The
+// streams carry the same HTTP/1.1 bytes a TCP connection would, this isn't HTTP/3, so it needs a
+// server that speaks HTTP/1.1 over QUIC (ALPN http/1.1)
+use std::{
+    future::Future,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Endpoint, RecvStream, SendStream};
+use tokio::{runtime::Runtime, time::timeout};
+
+use crate::config::DownloadConfig;
+use crate::connection::{bind_failed, candidates, log_fallback, server_host, tls_config};
+use crate::error::{ConnectError, DownloadError};
+
+pub(crate) const ALPN: &[u8] = b"http/1.1";
+
+// How long dropping a session waits for the server to hear it's being closed
+const CLOSE_WAIT: Duration = Duration::from_millis(100);
+
+// One QUIC connection to the server. Connection keeps it around between requests so only the
+// first one pays for the handshake, anything after just opens a new stream on it
+#[derive(Debug)]
+pub(crate) struct QuicSession {
+    runtime: Arc<Runtime>,
+    endpoint: Endpoint,
+    connection: quinn::Connection,
+}
+
+impl QuicSession {
+    // Tries each of the server's addresses like a TCP connect does, the first handshake to finish
+    // wins
+    pub(crate) fn connect(
+        config: &DownloadConfig,
+        preferred: Option<SocketAddr>,
+    ) -> Result<(Self, SocketAddr), DownloadError> {
+        let candidates = candidates(config, preferred)?;
+        let runtime = Arc::new(
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?,
+        );
+        let client_config = client_config(config)?;
+        let host = server_host(&config.server_addr);
+
+        let mut failures = Vec::new();
+        for &addr in &candidates {
+            match handshake(&runtime, &client_config, addr, host, config) {
+                Ok((endpoint, connection)) => {
+                    log_fallback(addr, failures.len());
+                    let session = Self {
+                        runtime,
+                        endpoint,
+                        connection,
+                    };
+                    return Ok((session, addr));
+                }
+                Err(e) => failures.push((addr, e)),
+            }
+        }
+        // Same shape as connection::connect_failed, but the errors aren't io::Errors
+        if failures.len() == 1 {
+            return Err(failures.remove(0).1);
+        }
+        Err(DownloadError::Network(Box::new(ConnectError {
+            server: config.server_addr.clone(),
+            failures: failures
+                .into_iter()
+                .map(|(addr, e)| (addr, io::Error::other(e)))
+                .collect(),
+        })))
+    }
+
+    // False once the connection's gone for good, ie the server closed it or it sat idle too long
+    pub(crate) fn is_alive(&self) -> bool {
+        self.connection.close_reason().is_none()
+    }
+
+    pub(crate) fn open_stream(&self, config: &DownloadConfig) -> Result<QuicStream, DownloadError> {
+        let (send, recv) = block_on(
+            &self.runtime,
+            config.connect_timeout,
+            self.connection.open_bi(),
+        )
+        .map_err(|e| DownloadError::from(e).with_timeout(config.connect_timeout))??;
+        Ok(QuicStream {
+            runtime: Arc::clone(&self.runtime),
+            send,
+            recv,
+            read_timeout: config.read_timeout,
+            write_timeout: config.connect_timeout,
+        })
+    }
+}
+
+impl Drop for QuicSession {
+    // Otherwise the server only finds out once its idle timeout runs out
+    fn drop(&mut self) {
+        self.connection.close(0u32.into(), b"done");
+        let _ = block_on(&self.runtime, CLOSE_WAIT, self.endpoint.wait_idle());
+    }
+}
+
+// One bidirectional stream, a request goes out on send and its response comes back on recv. A
+// response without a Content-Length ends when the server finishes its side
+#[derive(Debug)]
+pub struct QuicStream {
+    runtime: Arc<Runtime>,
+    send: SendStream,
+    recv: RecvStream,
+    read_timeout: Duration,
+    write_timeout: Duration,
+}
+
+impl Read for QuicStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = block_on(&self.runtime, self.read_timeout, self.recv.read(buf))?;
+        Ok(read?.unwrap_or(0))
+    }
+}
+
+impl Write for QuicStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = block_on(&self.runtime, self.write_timeout, self.send.write(buf))?;
+        Ok(written?)
+    }
+
+    // quinn sends whatever's written as soon as it can, there's nothing held back to flush
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The TLS config a TCP connection would use with the ALPN QUIC insists on
+fn client_config(config: &DownloadConfig) -> Result<ClientConfig, DownloadError> {
+    let mut tls = (*tls_config(config)?).clone();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(tls).map_err(|e| DownloadError::Tls(e.to_string()))?;
+    Ok(ClientConfig::new(Arc::new(crypto)))
+}
+
+fn handshake(
+    runtime: &Runtime,
+    client_config: &ClientConfig,
+    addr: SocketAddr,
+    host: &str,
+    config: &DownloadConfig,
+) -> Result<(Endpoint, quinn::Connection), DownloadError> {
+    let bind: SocketAddr = match config.bind_addr {
+        Some(bind) => bind,
+        None if addr.is_ipv4() => ([0, 0, 0, 0], 0).into(),
+        None => ([0u16; 8], 0).into(),
+    };
+    // quinn finds the runtime to drive the socket on through the current context
+    let _context = runtime.enter();
+    let endpoint = Endpoint::client(bind).map_err(|e| bind_failed(bind, e))?;
+    let connecting = endpoint.connect_with(client_config.clone(), addr, host)?;
+    let connection = block_on(runtime, config.connect_timeout, connecting)
+        .map_err(|e| DownloadError::from(e).with_timeout(config.connect_timeout))??;
+    Ok((endpoint, connection))
+}
+
+// Runs future to completion unless it takes longer than limit, which comes back as a TimedOut
+// io::Error so it turns into DownloadError::Timeout like a socket timeout does
+fn block_on<F: Future>(runtime: &Runtime, limit: Duration, future: F) -> io::Result<F::Output> {
+    // The timer has to be made inside the runtime, not just polled there
+    runtime
+        .block_on(async { timeout(limit, future).await })
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "QUIC operation timed out"))
+}
@@ -0,0 +1,216 @@
+// Following 301/302/307/308 for DownloadConfig::follow_redirects. The download works out where
+// the file really is once before it starts (see client::resolve_redirects) rather than on every
+// chunk, so a server that redirects each range request somewhere different isn't supported
+use std::collections::HashSet;
+
+use crate::config::{parse_url, validate_path, DownloadConfig, ServerUrl};
+use crate::error::DownloadError;
+use crate::headers::ResponseHeaders;
+
+// Where Location says to go from the URL a config points at. Either a whole URL, one starting
+// // that keeps the scheme, an absolute path on the same server, or a path relative to the
+// directory the current one is in. The fragment is dropped like parse_url does
+pub fn resolve_location(current: &ServerUrl, location: &str) -> Result<ServerUrl, DownloadError> {
+    let location = location.trim();
+    let location = location
+        .split_once('#')
+        .map_or(location, |(before, _)| before);
+    let scheme = if current.use_tls { "https" } else { "http" };
+    let bad_location = |e: DownloadError| {
+        DownloadError::Parse(format!("Can't follow redirect to {location}: {e}").into())
+    };
+
+    // Anything with a scheme is a whole URL, parse_url turns away the ones that aren't http(s)
+    if location
+        .split_once("://")
+        .is_some_and(|(scheme, _)| !scheme.is_empty() && !scheme.contains(['/', '?']))
+    {
+        return parse_url(location).map_err(bad_location);
+    }
+    if location.starts_with("//") {
+        return parse_url(&format!("{scheme}:{location}")).map_err(bad_location);
+    }
+    let path = if location.starts_with('/') {
+        location.to_owned()
+    } else {
+        let (segments, _query) = current.path.split_once('?').unwrap_or((&current.path, ""));
+        let dir = segments.rfind('/').map_or("/", |slash| &segments[..=slash]);
+        format!("{dir}{location}")
+    };
+    validate_path(&path).map_err(bad_location)?;
+    Ok(ServerUrl {
+        server_addr: current.server_addr.clone(),
+        path,
+        use_tls: current.use_tls,
+    })
+}
+
+// Every URL one download has been sent to, to catch a loop before max_redirects would
+pub(crate) struct RedirectTracker {
+    seen: HashSet<String>,
+    followed: u8,
+}
+
+impl RedirectTracker {
+    pub(crate) fn new(config: &DownloadConfig) -> Self {
+        Self {
+            seen: HashSet::from([url_key(&config_url(config))]),
+            followed: 0,
+        }
+    }
+
+    // Points config wherever headers redirect to and returns true, or false if headers aren't a
+    // redirect and config is where the file is
+    pub(crate) fn follow(
+        &mut self,
+        config: &mut DownloadConfig,
+        headers: &ResponseHeaders,
+    ) -> Result<bool, DownloadError> {
+        if !matches!(headers.status_code, 301 | 302 | 307 | 308) {
+            return Ok(false);
+        }
+        let location = headers.get("location").ok_or_else(|| {
+            DownloadError::Parse(
+                format!(
+                    "Server returned {} {} without a Location header",
+                    headers.status_code, headers.reason
+                )
+                .into(),
+            )
+        })?;
+        if self.followed >= config.max_redirects {
+            return Err(DownloadError::Network(
+                format!("Gave up after {} redirects", config.max_redirects).into(),
+            ));
+        }
+        let next = resolve_location(&config_url(config), location)?;
+        if !self.seen.insert(url_key(&next)) {
+            return Err(DownloadError::Network("Redirect loop detected".into()));
+        }
+        self.followed += 1;
+        config.server_addr = next.server_addr;
+        config.path = next.path;
+        config.use_tls = next.use_tls;
+        Ok(true)
+    }
+}
+
+fn config_url(config: &DownloadConfig) -> ServerUrl {
+    ServerUrl {
+        server_addr: config.server_addr.clone(),
+        path: config.path.clone(),
+        use_tls: config.use_tls,
+    }
+}
+
+// Host names aren't case sensitive, paths are
+fn url_key(url: &ServerUrl) -> String {
+    let scheme = if url.use_tls { "https" } else { "http" };
+    format!(
+        "{scheme}://{}{}",
+        url.server_addr.to_ascii_lowercase(),
+        url.path
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn url(server_addr: &str, path: &str) -> ServerUrl {
+        ServerUrl {
+            server_addr: server_addr.into(),
+            path: path.into(),
+            use_tls: false,
+        }
+    }
+
+    fn redirect(code: u16, location: &str) -> ResponseHeaders {
+        ResponseHeaders {
+            version: "HTTP/1.1".into(),
+            status_code: code,
+            reason: "Found".into(),
+            content_length: Some(0),
+            content_range: None,
+            headers: HashMap::from([("location".into(), location.into())]),
+        }
+    }
+
+    #[test]
+    fn resolves_every_kind_of_location() {
+        let current = url("files.example.com:80", "/v1/data.bin?sig=abc");
+        assert_eq!(
+            resolve_location(&current, "https://cdn.example.com/data.bin").unwrap(),
+            ServerUrl {
+                server_addr: "cdn.example.com:443".into(),
+                path: "/data.bin".into(),
+                use_tls: true,
+            }
+        );
+        assert_eq!(
+            resolve_location(&current, "//cdn.example.com:8080/x").unwrap(),
+            url("cdn.example.com:8080", "/x")
+        );
+        assert_eq!(
+            resolve_location(&current, "/v2/data.bin#top").unwrap(),
+            url("files.example.com:80", "/v2/data.bin")
+        );
+        assert_eq!(
+            resolve_location(&current, "data-2.bin?sig=def").unwrap(),
+            url("files.example.com:80", "/v1/data-2.bin?sig=def")
+        );
+        for bad in ["../etc/passwd", "ftp://example.com/x", "/has space"] {
+            assert!(
+                matches!(
+                    resolve_location(&current, bad),
+                    Err(DownloadError::Parse(_))
+                ),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn follows_until_it_loops_or_runs_out() {
+        let mut config = DownloadConfig {
+            server_addr: "a.example.com:80".into(),
+            path: "/start".into(),
+            max_redirects: 2,
+            ..Default::default()
+        };
+        let mut tracker = RedirectTracker::new(&config);
+        assert!(tracker
+            .follow(&mut config, &redirect(301, "/next"))
+            .unwrap());
+        assert_eq!(config.path, "/next");
+        match tracker.follow(&mut config, &redirect(302, "http://A.example.com/start")) {
+            Err(DownloadError::Network(msg)) => {
+                assert_eq!(msg.to_string(), "Redirect loop detected");
+            }
+            other => panic!("Expected a loop, got {other:?}"),
+        }
+        assert!(tracker
+            .follow(&mut config, &redirect(307, "/other"))
+            .unwrap());
+        assert!(tracker
+            .follow(&mut config, &redirect(308, "/more"))
+            .is_err());
+
+        // Not a redirect at all
+        let mut tracker = RedirectTracker::new(&config);
+        assert!(!tracker
+            .follow(&mut config, &redirect(206, "/ignored"))
+            .unwrap());
+        assert!(!tracker
+            .follow(&mut config, &redirect(304, "/ignored"))
+            .unwrap());
+        let mut missing = redirect(302, "");
+        missing.headers.clear();
+        assert!(matches!(
+            tracker.follow(&mut config, &missing),
+            Err(DownloadError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,125 @@
+// An HTTP/1.1 request head put together a header at a time, so every line gets its CRLF and
+// there's always the blank line at the end
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest<'a> {
+    pub method: &'a str,
+    // What goes between the method and HTTP/1.1, the whole URL when it's going to a proxy
+    pub path: &'a str,
+    pub host: &'a str,
+    // After Host, in the order they were added
+    pub headers: Vec<(String, String)>,
+}
+
+impl<'a> HttpRequest<'a> {
+    #[must_use]
+    pub fn new(method: &'a str, path: &'a str, host: &'a str) -> Self {
+        Self {
+            method,
+            path,
+            host,
+            headers: Vec::new(),
+        }
+    }
+
+    // The value gets trimmed, and a CR or LF in it dropped since that would start a header of
+    // its own (or end the request early)
+    pub fn header(&mut self, name: &str, value: &str) -> &mut Self {
+        let value = strip_line_breaks(value).trim().to_owned();
+        self.headers.push((name.to_owned(), value));
+        self
+    }
+
+    // Range: bytes=start-end, both inclusive
+    pub fn range(&mut self, start: u64, end: u64) -> &mut Self {
+        self.header("Range", &format!("bytes={start}-{end}"))
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+// What to_bytes sends, as text. Line breaks get taken out of everything, not just the header
+// values, so nothing anyone passes in can split a line
+impl fmt::Display for HttpRequest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let clean = strip_line_breaks;
+        write!(
+            f,
+            "{} {} HTTP/1.1\r\nHost: {}\r\n",
+            clean(self.method),
+            clean(self.path),
+            clean(self.host)
+        )?;
+        for (name, value) in &self.headers {
+            write!(f, "{}: {}\r\n", clean(name), clean(value))?;
+        }
+        f.write_str("\r\n")
+    }
+}
+
+fn strip_line_breaks(s: &str) -> String {
+    s.chars().filter(|c| !matches!(c, '\r' | '\n')).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn ranged_get() {
+        let mut request = HttpRequest::new("GET", "/data.bin", "127.0.0.1:8080");
+        request.range(0, 32767).header("Connection", "keep-alive");
+        assert_eq!(
+            request.to_bytes(),
+            b"GET /data.bin HTTP/1.1\r\n\
+              Host: 127.0.0.1:8080\r\n\
+              Range: bytes=0-32767\r\n\
+              Connection: keep-alive\r\n\
+              \r\n"
+        );
+        assert_eq!(
+            HttpRequest::new("HEAD", "/", "x").to_string(),
+            "HEAD / HTTP/1.1\r\nHost: x\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn values_cant_add_headers() {
+        let mut request = HttpRequest::new("GET", "/", "x");
+        request.header("Authorization", " Bearer x\r\nX-Injected: yes ");
+        assert_eq!(
+            request.to_string(),
+            "GET / HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer xX-Injected: yes\r\n\r\n"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn every_line_ends_in_crlf(
+            method in ".*",
+            path in ".*",
+            host in ".*",
+            headers in proptest::collection::vec((".*", ".*"), 0..5),
+        ) {
+            let mut request = HttpRequest::new(&method, &path, &host);
+            for (name, value) in &headers {
+                request.header(name, value);
+            }
+            let bytes = request.to_bytes();
+            prop_assert!(bytes.ends_with(b"\r\n\r\n"));
+            for (i, &byte) in bytes.iter().enumerate() {
+                if byte == b'\n' {
+                    prop_assert!(i > 0 && bytes[i - 1] == b'\r', "bare LF at {}", i);
+                }
+            }
+            // One line for the request, one for Host and one for each header
+            prop_assert_eq!(bytes.windows(2).filter(|w| w == b"\r\n").count(), headers.len() + 3);
+        }
+    }
+}
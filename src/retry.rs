@@ -0,0 +1,135 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use crate::config::DownloadConfig;
+use crate::error::DownloadError;
+
+// How much jitter to apply either way, 0.25 means the delay ends up somewhere in 75%-125%
+const JITTER_FRACTION: f64 = 0.25;
+
+// Exponential backoff: base * 2^(attempt-1) capped at retry_max_delay, attempt starts at 1.
+// Jitter is applied after the cap but we clamp again so the cap is never exceeded
+#[must_use]
+pub fn backoff_delay(attempt: u32, config: &DownloadConfig) -> Duration {
+    let exponent = attempt.saturating_sub(1);
+    let multiplier = 2u32.saturating_pow(exponent);
+    let delay = config
+        .retry_base_delay
+        .saturating_mul(multiplier)
+        .min(config.retry_max_delay);
+
+    if !config.retry_jitter {
+        return delay;
+    }
+
+    // Random number in [-1, 1] scaled down to the jitter fraction
+    let jitter = (random_unit() * 2.0 - 1.0) * JITTER_FRACTION;
+    delay.mul_f64(1.0 + jitter).min(config.retry_max_delay)
+}
+
+// How long to wait before the attempt after the one that failed with failure. A 429 that said
+// how long with Retry-After gets exactly that, capped at retry_after_max, anything else gets the
+// usual backoff_delay
+#[must_use]
+pub fn retry_delay(attempt: u32, failure: &DownloadError, config: &DownloadConfig) -> Duration {
+    match failure {
+        DownloadError::RateLimited {
+            retry_after: Some(after),
+        } => (*after).min(config.retry_after_max),
+        _ => backoff_delay(attempt, config),
+    }
+}
+
+// Random float in [0, 1). std doesn't have an RNG but RandomState is seeded randomly per instance
+// which is plenty good enough for jitter, not for anything cryptographic though
+fn random_unit() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    // Use the top 53 bits so every value is exactly representable as an f64
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(jitter: bool) -> DownloadConfig {
+        DownloadConfig {
+            retry_base_delay: Duration::from_millis(100),
+            retry_max_delay: Duration::from_secs(5),
+            retry_jitter: jitter,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn retry_after_beats_backoff() {
+        let config = DownloadConfig {
+            retry_after_max: Duration::from_secs(3),
+            ..config(false)
+        };
+        let limited = |secs| DownloadError::RateLimited {
+            retry_after: Some(Duration::from_secs(secs)),
+        };
+        assert_eq!(retry_delay(1, &limited(2), &config), Duration::from_secs(2));
+        assert_eq!(
+            retry_delay(1, &limited(3600), &config),
+            Duration::from_secs(3)
+        );
+        let no_header = DownloadError::RateLimited { retry_after: None };
+        assert_eq!(
+            retry_delay(3, &no_header, &config),
+            Duration::from_millis(400)
+        );
+        let other = DownloadError::Network("gone".into());
+        assert_eq!(retry_delay(1, &other, &config), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn doubles_without_jitter() {
+        let config = config(false);
+        let delays: Vec<_> = (1..=4).map(|a| backoff_delay(a, &config)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800].map(Duration::from_millis).to_vec()
+        );
+    }
+
+    #[test]
+    fn never_exceeds_cap() {
+        for jitter in [false, true] {
+            let config = config(jitter);
+            for attempt in 1..=100 {
+                assert!(backoff_delay(attempt, &config) <= config.retry_max_delay);
+            }
+        }
+        // Huge attempt numbers shouldn't overflow either
+        assert_eq!(
+            backoff_delay(u32::MAX, &config(false)),
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let config = config(true);
+        for _ in 0..1000 {
+            let delay = backoff_delay(3, &config);
+            assert!(delay >= Duration::from_millis(300) && delay <= Duration::from_millis(500));
+        }
+    }
+
+    #[test]
+    fn jittered_delays_non_decreasing_on_average() {
+        let config = config(true);
+        let average = |attempt| {
+            let total: Duration = (0..200).map(|_| backoff_delay(attempt, &config)).sum();
+            total / 200
+        };
+        // Once we hit the cap the averages are just noise so only look at the growing part
+        let averages: Vec<_> = (1..=6).map(average).collect();
+        assert!(averages.windows(2).all(|w| w[0] <= w[1]), "{averages:?}");
+    }
+}
@@ -1,25 +1,413 @@
-use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::error::DownloadError;
+
+// Deserializes through FromStr so a config file gets the same leniency as --hash-algo
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", try_from = "String")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = DownloadError;
+
+    // Be lenient so sha-256, SHA256 etc. all work
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(DownloadError::Args(format!(
+                "Unknown hash algorithm: {s}. Expected one of sha256, sha512, blake3"
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for HashAlgorithm {
+    type Error = DownloadError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl HashAlgorithm {
+    // Guess from how long a hex digest is. blake3 is 64 as well but sha256 is far more likely
+    #[must_use]
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            64 => Some(HashAlgorithm::Sha256),
+            128 => Some(HashAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+}
+
+// What sha256sum and friends write out, "<hash>  <file name>" a line (or "<hash> *<file name>"
+// for binary mode). Only the first line that isn't blank or a # comment counts. Gives back the
+// lowercased hash and the file name if there was one
+pub fn read_checksum_file(path: &Path) -> Result<(String, Option<String>), DownloadError> {
+    parse_checksum_file(&std::fs::read_to_string(path)?)
+}
+
+pub fn parse_checksum_file(text: &str) -> Result<(String, Option<String>), DownloadError> {
+    let line = text
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or_else(|| DownloadError::Parse("Checksum file has no hash in it".into()))?;
+    let (hash, name) = match line.split_once(char::is_whitespace) {
+        Some((hash, name)) => (hash, Some(name.trim_start())),
+        None => (line, None),
+    };
+    if hash.is_empty() || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(DownloadError::Parse(
+            format!("Checksum file doesn't start with a hex hash: {line}").into(),
+        ));
+    }
+    let name = name
+        .map(|name| name.strip_prefix('*').unwrap_or(name))
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned);
+    Ok((hash.to_ascii_lowercase(), name))
+}
 
 // Separating this function into a new file allows us to choose if we want to implement SHA256
 // ourselves as an exercise though not recommended. Well better separation of concerns
 #[must_use]
 #[inline]
 pub fn calculate_sha256(data: &[u8]) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    let result = hasher.finalize();
+    calculate_hash(data, HashAlgorithm::Sha256)
+}
+
+// calculate_sha256 of just bytes start..=end of data, ie one range of a manifest checked against
+// the whole file. A range that's backwards or runs off the end of data is a Logic error rather
+// than quietly hashing something else
+pub fn calculate_sha256_partial(data: &[u8], range: (u64, u64)) -> Result<String, DownloadError> {
+    let (start, end) = range;
+    if start > end || end >= data.len() as u64 {
+        return Err(DownloadError::Logic(format!(
+            "Can't hash bytes {start}-{end} of {} bytes of data",
+            data.len()
+        )));
+    }
+    // Both fit in a usize now they're under data.len()
+    Ok(calculate_sha256(&data[start as usize..=end as usize]))
+}
+
+// calculate_sha256 for a file on disk, only 64 KiB of it is in memory at a time
+pub fn calculate_sha256_file(path: &Path) -> Result<String, io::Error> {
+    calculate_hash_file(path, HashAlgorithm::Sha256)
+}
+
+pub fn calculate_hash_file(path: &Path, algo: HashAlgorithm) -> Result<String, io::Error> {
+    checksum_stream(File::open(path)?, algo, 64 * 1024)
+}
+
+// Hash whatever comes out of reader until EOF, buf_size bytes at a time, so a socket or pipe
+// never has to be buffered whole. A buf_size of 0 is treated as 1
+pub fn checksum_stream<R: Read>(
+    mut reader: R,
+    algo: HashAlgorithm,
+    buf_size: usize,
+) -> Result<String, io::Error> {
+    let mut hasher = IncrementalHasher::new(algo);
+    let mut buf = vec![0u8; buf_size.max(1)];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(hasher.finalize())
+}
 
-    // Convert bytes to hex string manually. result is a 32 byte array
+// Lowercase hex digest of data using whichever algorithm was asked for
+#[must_use]
+pub fn calculate_hash(data: &[u8], algo: HashAlgorithm) -> String {
+    // Convert bytes to hex string manually. result is a 32 or 64 byte array
     // I'm surprised that it works but ig the sha2 crate writers implemented LowerHex trait already
-    format!("{:x}", result)
+    match algo {
+        HashAlgorithm::Sha256 => format!("{:x}", Sha256::digest(data)),
+        HashAlgorithm::Sha512 => format!("{:x}", Sha512::digest(data)),
+        // blake3 has its own Hash type that already displays as hex
+        HashAlgorithm::Blake3 => blake3::hash(data).to_hex().to_string(),
+    }
+}
+
+// Feed data in as it arrives rather than needing it all in one buffer, finalize gives the exact
+// same digest calculate_hash would on the concatenated data
+pub struct IncrementalHasher {
+    inner: HasherInner,
+}
+
+enum HasherInner {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    // blake3's hasher is a couple KB so keep it off the stack
+    Blake3(Box<blake3::Hasher>),
+    // Ignores everything and finalizes to an empty string, for DownloadConfig::compute_hash
+    Off,
+}
+
+impl IncrementalHasher {
+    #[must_use]
+    pub fn new(algo: HashAlgorithm) -> Self {
+        let inner = match algo {
+            HashAlgorithm::Sha256 => HasherInner::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => HasherInner::Sha512(Sha512::new()),
+            HashAlgorithm::Blake3 => HasherInner::Blake3(Box::default()),
+        };
+        Self { inner }
+    }
+
+    pub(crate) fn off() -> Self {
+        Self {
+            inner: HasherInner::Off,
+        }
+    }
+
+    // None if it's not hashing at all
+    #[must_use]
+    pub fn algorithm(&self) -> Option<HashAlgorithm> {
+        match self.inner {
+            HasherInner::Sha256(_) => Some(HashAlgorithm::Sha256),
+            HasherInner::Sha512(_) => Some(HashAlgorithm::Sha512),
+            HasherInner::Blake3(_) => Some(HashAlgorithm::Blake3),
+            HasherInner::Off => None,
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        match &mut self.inner {
+            HasherInner::Sha256(h) => h.update(chunk),
+            HasherInner::Sha512(h) => h.update(chunk),
+            HasherInner::Blake3(h) => {
+                h.update(chunk);
+            }
+            HasherInner::Off => {}
+        }
+    }
+
+    #[must_use]
+    pub fn finalize(self) -> String {
+        match self.inner {
+            HasherInner::Sha256(h) => format!("{:x}", h.finalize()),
+            HasherInner::Sha512(h) => format!("{:x}", h.finalize()),
+            HasherInner::Blake3(h) => h.finalize().to_hex().to_string(),
+            HasherInner::Off => String::new(),
+        }
+    }
+}
+
+// So anything Read can go straight in with io::copy, ie a file that's already on disk
+impl io::Write for IncrementalHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Hashes whatever it passes on to inner, so what ends up in the file (or buffer) and what got
+// hashed can't drift apart. Only the bytes inner takes are hashed, a short write hashes the part
+// that was written and the caller's retry of the rest hashes that
+pub struct DownloadWriter<W: Write> {
+    inner: W,
+    hasher: IncrementalHasher,
+}
+
+impl<W: Write> DownloadWriter<W> {
+    // hasher can already have something in it, ie a file's existing bytes when resuming
+    #[must_use]
+    pub fn new(inner: W, hasher: IncrementalHasher) -> Self {
+        Self { inner, hasher }
+    }
+
+    #[must_use]
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    // Doesn't flush inner, anything buffered there has to be flushed first or it's lost unless
+    // dropping it writes it out (a BufWriter does)
+    #[must_use]
+    pub fn into_hash(self) -> String {
+        self.hasher.finalize()
+    }
+
+    #[must_use]
+    pub fn into_parts(self) -> (W, IncrementalHasher) {
+        (self.inner, self.hasher)
+    }
+}
+
+impl<W: Write> Write for DownloadWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 // Verify that our sha function is correct, could be helpful if I decided to
 // implement SHA256 myself
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+    use ring::digest;
+
     use super::*;
 
+    // ring's SHA-256 as lowercase hex, a second opinion that shares no code with sha2
+    fn ring_sha256(data: &[u8]) -> String {
+        digest::digest(&digest::SHA256, data)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn sha256_matches_ring(data in proptest::collection::vec(any::<u8>(), 0..10_000)) {
+            prop_assert_eq!(calculate_sha256(&data), ring_sha256(&data));
+        }
+
+        // All one byte, ie all zeros or all 0xff, right across the 64 byte block boundaries
+        #[test]
+        fn sha256_matches_ring_on_repeated_bytes(byte in any::<u8>(), len in 0usize..300) {
+            let data = vec![byte; len];
+            prop_assert_eq!(calculate_sha256(&data), ring_sha256(&data));
+        }
+    }
+
+    // Takes at most 7 bytes a call, like a pipe or socket might
+    struct Dribble(Vec<u8>);
+
+    impl Write for Dribble {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(7);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn download_writer_hashes_what_got_written() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut writer = DownloadWriter::new(
+            Dribble(Vec::new()),
+            IncrementalHasher::new(HashAlgorithm::Sha256),
+        );
+        writer.write_all(&data[..100]).unwrap();
+        writer.write_all(&data[100..]).unwrap();
+        let (inner, hasher) = writer.into_parts();
+        assert_eq!(inner.0, data);
+        assert_eq!(hasher.finalize(), calculate_sha256(&data));
+
+        // A full buffer takes part of a write and the hash only has that part
+        let mut buf = [0u8; 10];
+        let mut writer =
+            DownloadWriter::new(&mut buf[..], IncrementalHasher::new(HashAlgorithm::Sha256));
+        assert_eq!(writer.write(&data[..15]).unwrap(), 10);
+        assert_eq!(writer.into_hash(), calculate_sha256(&data[..10]));
+    }
+
+    proptest! {
+        #[test]
+        fn checksum_stream_matches_calculate_hash(
+            data in proptest::collection::vec(any::<u8>(), 0..10_000),
+            buf_size in 0usize..5000,
+        ) {
+            for algo in [HashAlgorithm::Sha256, HashAlgorithm::Sha512, HashAlgorithm::Blake3] {
+                let streamed = checksum_stream(data.as_slice(), algo, buf_size).unwrap();
+                prop_assert_eq!(streamed, calculate_hash(&data, algo));
+            }
+        }
+    }
+
+    #[test]
+    fn partial_hashes_just_the_range() {
+        let data = b"hello world";
+        assert_eq!(
+            calculate_sha256_partial(data, (6, 10)).unwrap(),
+            calculate_sha256(b"world")
+        );
+        assert_eq!(
+            calculate_sha256_partial(data, (0, 0)).unwrap(),
+            calculate_sha256(b"h")
+        );
+        assert_eq!(
+            calculate_sha256_partial(data, (0, 10)).unwrap(),
+            calculate_sha256(data)
+        );
+        for range in [(0, 11), (5, 4), (11, 11), (0, u64::MAX)] {
+            assert!(
+                matches!(
+                    calculate_sha256_partial(data, range),
+                    Err(DownloadError::Logic(_))
+                ),
+                "{range:?}"
+            );
+        }
+        assert!(calculate_sha256_partial(b"", (0, 0)).is_err());
+    }
+
+    #[test]
+    fn sha256_empty() {
+        let empty = calculate_sha256(&[]);
+        assert_eq!(
+            empty,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(empty, ring_sha256(b""));
+    }
+
+    #[test]
+    fn sha256_long_input() {
+        let data = vec![0xa5u8; 3 * 1024 * 1024 + 17];
+        assert_eq!(calculate_sha256(&data), ring_sha256(&data));
+    }
+
     #[test]
     fn sha_hello() {
         test_helper(
@@ -52,6 +440,118 @@ mod tests {
         )
     }
 
+    #[test]
+    fn sha512_hello() {
+        assert_eq!(
+            calculate_hash(b"hello", HashAlgorithm::Sha512),
+            "9b71d224bd62f3785d96d46ad3ea3d73319bfbc2890caadae2dff72519673ca7\
+             2323c3d99ba5c11d7c7acc6e14b8c5da0c4663475c2e5c3adef46f73bcdec043"
+        );
+    }
+
+    #[test]
+    fn blake3_empty_and_abc() {
+        // Test vectors from the BLAKE3 reference implementation
+        assert_eq!(
+            calculate_hash(b"", HashAlgorithm::Blake3),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            calculate_hash(b"abc", HashAlgorithm::Blake3),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    #[test]
+    fn parse_algorithm_names() {
+        assert_eq!(
+            "SHA-256".parse::<HashAlgorithm>().unwrap(),
+            HashAlgorithm::Sha256
+        );
+        assert_eq!(
+            "sha512".parse::<HashAlgorithm>().unwrap(),
+            HashAlgorithm::Sha512
+        );
+        assert_eq!(
+            "Blake3".parse::<HashAlgorithm>().unwrap(),
+            HashAlgorithm::Blake3
+        );
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn parse_checksum_files() {
+        let hash = calculate_sha256(b"hello");
+        assert_eq!(
+            parse_checksum_file(&format!(
+                "# from sha256sum\n\n{}  data.bin\n",
+                hash.to_uppercase()
+            ))
+            .unwrap(),
+            (hash.clone(), Some("data.bin".to_owned()))
+        );
+        assert_eq!(
+            parse_checksum_file(&format!("{hash} *my data.bin\nffff  other.bin")).unwrap(),
+            (hash.clone(), Some("my data.bin".to_owned()))
+        );
+        assert_eq!(parse_checksum_file(&hash).unwrap(), (hash.clone(), None));
+
+        for bad in ["", "# nothing\n\n", "not-hex  data.bin"] {
+            assert!(
+                matches!(parse_checksum_file(bad), Err(DownloadError::Parse(_))),
+                "{bad}"
+            );
+        }
+
+        assert_eq!(HashAlgorithm::from_hex_len(64), Some(HashAlgorithm::Sha256));
+        assert_eq!(
+            HashAlgorithm::from_hex_len(128),
+            Some(HashAlgorithm::Sha512)
+        );
+        assert_eq!(HashAlgorithm::from_hex_len(40), None);
+    }
+
+    #[test]
+    fn incremental_matches_one_shot() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        for algo in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512,
+            HashAlgorithm::Blake3,
+        ] {
+            let mut hasher = IncrementalHasher::new(algo);
+            // Uneven chunks to make sure block boundaries don't matter
+            for chunk in data.chunks(333) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), calculate_hash(&data, algo));
+
+            let mut hasher = IncrementalHasher::new(algo);
+            io::copy(&mut &data[..], &mut hasher).unwrap();
+            assert_eq!(hasher.finalize(), calculate_hash(&data, algo));
+        }
+    }
+
+    #[test]
+    fn file_matches_in_memory() {
+        // Bigger than the read buffer and not a multiple of it
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+        let path =
+            std::env::temp_dir().join(format!("glitchy-http-sha-{}.bin", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+
+        let from_file = calculate_sha256_file(&path);
+        let blake3 = calculate_hash_file(&path, HashAlgorithm::Blake3);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(from_file.unwrap(), calculate_sha256(&data));
+        assert_eq!(
+            blake3.unwrap(),
+            calculate_hash(&data, HashAlgorithm::Blake3)
+        );
+
+        assert!(calculate_sha256_file(Path::new("/definitely/not/here")).is_err());
+    }
+
     fn test_helper(data: &str, expected_sha: &str) {
         let data = data.as_bytes();
         let output = calculate_sha256(data);
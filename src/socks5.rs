@@ -0,0 +1,314 @@
+// The client side of a SOCKS5 proxy (RFC 1928), for going through Tor or an ssh -D tunnel. Only
+// CONNECT, with no auth or username/password (RFC 1929). Once connect says yes the stream is a
+// plain connection to server_addr and HTTP or TLS goes over it like normal
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, TcpStream},
+};
+
+use crate::config::DownloadConfig;
+use crate::error::DownloadError;
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+// The version of the username/password sub-negotiation, not SOCKS
+const USER_PASS_VERSION: u8 = 0x01;
+const CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+// Greets the proxy, logs in if it wants socks5_auth and asks it to connect to server_addr. The
+// hostname goes to the proxy as is, so it does the DNS lookup (which is the point with Tor)
+pub(crate) fn connect(
+    stream: &mut TcpStream,
+    config: &DownloadConfig,
+) -> Result<(), DownloadError> {
+    let methods: &[u8] = if config.socks5_auth.is_some() {
+        &[NO_AUTH, USER_PASS]
+    } else {
+        &[NO_AUTH]
+    };
+    let mut greeting = vec![VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    send(stream, &greeting, config)?;
+
+    let [version, method] = receive(stream, config)?;
+    if version != VERSION {
+        return Err(DownloadError::ProxyError(format!(
+            "SOCKS5 proxy answered with version {version}"
+        )));
+    }
+    match (method, &config.socks5_auth) {
+        (NO_AUTH, _) => {}
+        (USER_PASS, Some((user, password))) => log_in(stream, user, password, config)?,
+        (NO_ACCEPTABLE_METHOD, _) => {
+            return Err(DownloadError::ProxyError(
+                "SOCKS5 proxy wouldn't accept any of the auth methods offered".into(),
+            ))
+        }
+        (method, _) => {
+            return Err(DownloadError::ProxyError(format!(
+                "SOCKS5 proxy picked auth method {method:#04x}, which wasn't offered"
+            )))
+        }
+    }
+
+    send(stream, &connect_request(&config.server_addr)?, config)?;
+    let [version, reply, _reserved, atyp] = receive(stream, config)?;
+    if version != VERSION {
+        return Err(DownloadError::ProxyError(format!(
+            "SOCKS5 proxy answered with version {version}"
+        )));
+    }
+    if reply != 0x00 {
+        return Err(DownloadError::ProxyError(format!(
+            "SOCKS5 proxy couldn't connect to {}: {}",
+            config.server_addr,
+            reply_message(reply)
+        )));
+    }
+    // The address the proxy connected from, nothing needs it but it has to be read past
+    let bound_len = match atyp {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => usize::from(receive::<1>(stream, config)?[0]),
+        _ => {
+            return Err(DownloadError::ProxyError(format!(
+                "SOCKS5 proxy answered with unknown address type {atyp}"
+            )))
+        }
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    read(stream, &mut bound, config)
+}
+
+// RFC 1929, both have to fit in a length byte
+fn log_in(
+    stream: &mut TcpStream,
+    user: &str,
+    password: &str,
+    config: &DownloadConfig,
+) -> Result<(), DownloadError> {
+    let (Ok(user_len), Ok(password_len)) = (u8::try_from(user.len()), u8::try_from(password.len()))
+    else {
+        return Err(DownloadError::Args(
+            "SOCKS5 username and password can't be longer than 255 bytes".into(),
+        ));
+    };
+    let mut request = vec![USER_PASS_VERSION, user_len];
+    request.extend_from_slice(user.as_bytes());
+    request.push(password_len);
+    request.extend_from_slice(password.as_bytes());
+    send(stream, &request, config)?;
+
+    let [_version, status] = receive(stream, config)?;
+    if status != 0x00 {
+        return Err(DownloadError::Auth(format!(
+            "SOCKS5 proxy didn't accept the username and password for {user}"
+        )));
+    }
+    Ok(())
+}
+
+// CONNECT to server_addr, an IP as its 4 or 16 bytes and anything else as a hostname
+pub(crate) fn connect_request(server_addr: &str) -> Result<Vec<u8>, DownloadError> {
+    let invalid = || DownloadError::Args(format!("Can't send {server_addr} to a SOCKS5 proxy"));
+    let (host, port) = server_addr.rsplit_once(':').ok_or_else(invalid)?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let mut request = vec![VERSION, CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .ok()
+                .filter(|&len| len > 0)
+                .ok_or_else(invalid)?;
+            request.push(ATYP_DOMAIN);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+// What RFC 1928 section 6 says each REP means
+fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        0x01 => "general failure",
+        0x02 => "not allowed by its rules",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn send(
+    stream: &mut TcpStream,
+    bytes: &[u8],
+    config: &DownloadConfig,
+) -> Result<(), DownloadError> {
+    stream
+        .write_all(bytes)
+        .map_err(|e| DownloadError::from(e).with_timeout(config.connect_timeout))
+}
+
+fn receive<const N: usize>(
+    stream: &mut TcpStream,
+    config: &DownloadConfig,
+) -> Result<[u8; N], DownloadError> {
+    let mut buf = [0u8; N];
+    read(stream, &mut buf, config)?;
+    Ok(buf)
+}
+
+fn read(
+    stream: &mut TcpStream,
+    buf: &mut [u8],
+    config: &DownloadConfig,
+) -> Result<(), DownloadError> {
+    stream.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => DownloadError::ProxyError(
+            "SOCKS5 proxy closed the connection partway through the handshake".into(),
+        ),
+        _ => DownloadError::from(e).with_timeout(config.read_timeout),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+    use crate::connection::Connection;
+
+    #[test]
+    fn connect_requests() {
+        assert_eq!(
+            connect_request("127.0.0.1:8080").unwrap(),
+            [5, 1, 0, 1, 127, 0, 0, 1, 0x1f, 0x90]
+        );
+        let mut v6 = vec![5, 1, 0, 4];
+        v6.extend_from_slice(&[0; 15]);
+        v6.extend_from_slice(&[1, 1, 0xbb]);
+        assert_eq!(connect_request("[::1]:443").unwrap(), v6);
+        assert_eq!(
+            connect_request("example.com:80").unwrap(),
+            b"\x05\x01\x00\x03\x0bexample.com\x00\x50"
+        );
+        for bad in ["example.com", "example.com:http", ":80"] {
+            assert!(
+                matches!(connect_request(bad), Err(DownloadError::Args(_))),
+                "{bad}"
+            );
+        }
+    }
+
+    // A SOCKS5 proxy that wants user/pass, checks every message the client sends and then answers
+    // the HTTP request itself. Hands back the credentials it was given
+    fn fake_proxy(accept: bool) -> (std::net::SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 4];
+            socket.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 2, NO_AUTH, USER_PASS]);
+            socket.write_all(&[5, USER_PASS]).unwrap();
+
+            let mut login = [0u8; 13];
+            socket.read_exact(&mut login).unwrap();
+            socket.write_all(&[1, u8::from(!accept)]).unwrap();
+            if !accept {
+                return login.to_vec();
+            }
+
+            let mut request = [0u8; 20];
+            socket.read_exact(&mut request).unwrap();
+            assert_eq!(&request, b"\x05\x01\x00\x03\x0dfiles.invalid\x00\x50");
+            socket
+                .write_all(&[5, 0, 0, ATYP_DOMAIN, 1, b'x', 0, 0])
+                .unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            login.to_vec()
+        });
+        (addr, proxy)
+    }
+
+    fn config(proxy: std::net::SocketAddr) -> DownloadConfig {
+        DownloadConfig {
+            server_addr: "files.invalid:80".into(),
+            socks5_proxy: Some(proxy),
+            socks5_auth: Some(("user".into(), "secret".into())),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn logs_in_and_connects_by_hostname() {
+        let (proxy, handle) = fake_proxy(true);
+        let mut conn = Connection::new();
+        let stream = conn.stream(&config(proxy)).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert_eq!(handle.join().unwrap(), b"\x01\x04user\x06secret");
+    }
+
+    #[test]
+    fn wrong_password_is_an_auth_error() {
+        let (proxy, handle) = fake_proxy(false);
+        let result = Connection::new().stream(&config(proxy)).map(|_| ());
+        assert!(matches!(result, Err(DownloadError::Auth(_))), "{result:?}");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn refused_connect_is_a_proxy_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            socket.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, NO_AUTH]);
+            socket.write_all(&[5, NO_AUTH]).unwrap();
+            let mut request = [0u8; 10];
+            socket.read_exact(&mut request).unwrap();
+            socket
+                .write_all(&[5, 0x05, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .unwrap();
+        });
+
+        let config = DownloadConfig {
+            server_addr: "10.0.0.1:80".into(),
+            socks5_proxy: Some(proxy),
+            ..Default::default()
+        };
+        match Connection::new().stream(&config).map(|_| ()) {
+            Err(DownloadError::ProxyError(msg)) => {
+                assert!(msg.contains("connection refused"), "{msg}");
+            }
+            other => panic!("Expected ProxyError, got {other:?}"),
+        }
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,483 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Read},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::sha::{HashAlgorithm, IncrementalHasher};
+
+// What a download actually took, handed back alongside the data so you can see how much extra
+// work the retrying did
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DownloadStats {
+    // Hex digest of the data using config.hash_algorithm
+    pub hash: String,
+    // What hash is in, None when DownloadConfig::compute_hash was off and hash is empty
+    pub hash_algorithm: Option<HashAlgorithm>,
+    // Whether hash has been checked against an expected one and matched. The download functions
+    // never have one to check against so it's only ever set by whoever does the checking, ie
+    // MultiFileDownloader
+    pub hash_verified: bool,
+    pub total_duration: Duration,
+    // Every chunk that got requested at least once
+    pub chunks_attempted: u32,
+    // Requests on top of the first one for each chunk
+    pub total_retries: u32,
+    // Body bytes received, including ones thrown away from bad responses. So it can be more than
+    // the total size
+    pub bytes_transferred: u64,
+    // Attempts that failed because of the connection itself (IO/network) rather than the response
+    pub connection_errors: u32,
+    // How long each chunk took from its first request to arriving in full, retries and all. 0
+    // when nothing got downloaded
+    pub slowest_chunk_ms: u64,
+    pub fastest_chunk_ms: u64,
+    pub average_chunk_ms: f64,
+}
+
+impl DownloadStats {
+    // Nothing but the finished hash and its algorithm
+    pub(crate) fn hashed(hasher: IncrementalHasher) -> Self {
+        Self {
+            hash_algorithm: hasher.algorithm(),
+            hash: hasher.finalize(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn finish_hash(&mut self, hasher: IncrementalHasher) {
+        self.hash_algorithm = hasher.algorithm();
+        self.hash = hasher.finalize();
+    }
+
+    // Adds the counters from other onto these, for putting the parallel threads back together.
+    // hash and total_duration are left alone since they're about the whole download. The chunk
+    // timings get combined, the average weighted by how many chunks each side had
+    pub(crate) fn merge(&mut self, other: &DownloadStats) {
+        if other.chunks_attempted > 0 {
+            let (ours, theirs) = (
+                f64::from(self.chunks_attempted),
+                f64::from(other.chunks_attempted),
+            );
+            self.fastest_chunk_ms = if self.chunks_attempted == 0 {
+                other.fastest_chunk_ms
+            } else {
+                self.fastest_chunk_ms.min(other.fastest_chunk_ms)
+            };
+            self.slowest_chunk_ms = self.slowest_chunk_ms.max(other.slowest_chunk_ms);
+            self.average_chunk_ms =
+                (self.average_chunk_ms * ours + other.average_chunk_ms * theirs) / (ours + theirs);
+        }
+
+        self.chunks_attempted += other.chunks_attempted;
+        self.total_retries += other.total_retries;
+        self.bytes_transferred += other.bytes_transferred;
+        self.connection_errors += other.connection_errors;
+    }
+
+    // A chunk that's just finished. Only successful chunks get here and a failed one ends the
+    // download, so every chunk counted in chunks_attempted so far has been recorded
+    pub(crate) fn record_chunk(&mut self, took: Duration) {
+        let ms = took.as_millis() as u64;
+        let recorded = f64::from(self.chunks_attempted.saturating_sub(1));
+        self.fastest_chunk_ms = if recorded == 0.0 {
+            ms
+        } else {
+            self.fastest_chunk_ms.min(ms)
+        };
+        self.slowest_chunk_ms = self.slowest_chunk_ms.max(ms);
+        self.average_chunk_ms =
+            (self.average_chunk_ms * recorded + took.as_secs_f64() * 1000.0) / (recorded + 1.0);
+    }
+}
+
+// Download complete: 1,048,576 bytes in 2.3 s (442 KiB/s), 32 chunks, 2 retries, SHA-256 verified
+// then the chunk timings on a second line if there were any. The bytes and the speed are what
+// came over the wire, so retries count
+impl fmt::Display for DownloadStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.total_duration.as_secs_f64();
+        write!(
+            f,
+            "Download complete: {} bytes in {secs:.1} s",
+            thousands(self.bytes_transferred)
+        )?;
+        if secs > 0.0 {
+            write!(f, " ({})", rate(self.bytes_transferred as f64 / secs))?;
+        }
+        write!(
+            f,
+            ", {} chunks, {} retries",
+            self.chunks_attempted, self.total_retries
+        )?;
+        if self.connection_errors > 0 {
+            write!(f, ", {} connection errors", self.connection_errors)?;
+        }
+        match self.hash_algorithm {
+            Some(algorithm) if self.hash_verified => write!(f, ", {} verified", name(algorithm))?,
+            Some(algorithm) => write!(f, ", {} not checked", name(algorithm))?,
+            None => write!(f, ", not hashed")?,
+        }
+        if self.chunks_attempted > 0 {
+            write!(
+                f,
+                "\n Chunks took {}ms at the fastest, {}ms at the slowest, {:.1}ms on average",
+                self.fastest_chunk_ms, self.slowest_chunk_ms, self.average_chunk_ms
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// How the algorithm's written when it's for people, HashAlgorithm's Display is the CLI spelling
+fn name(algorithm: HashAlgorithm) -> &'static str {
+    match algorithm {
+        HashAlgorithm::Sha256 => "SHA-256",
+        HashAlgorithm::Sha512 => "SHA-512",
+        HashAlgorithm::Blake3 => "BLAKE3",
+    }
+}
+
+// 1048576 -> 1,048,576
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+    out
+}
+
+// Bytes per second in whichever binary unit keeps the number small, a decimal place under 10
+pub(crate) fn rate(bytes_per_sec: f64) -> String {
+    const UNITS: [&str; 5] = ["B/s", "KiB/s", "MiB/s", "GiB/s", "TiB/s"];
+    let mut value = bytes_per_sec;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value < 10.0 && unit > 0 {
+        format!("{value:.1} {}", UNITS[unit])
+    } else {
+        format!("{value:.0} {}", UNITS[unit])
+    }
+}
+
+// Counts the bytes read through it, so the speed is what came off the connection since it was
+// made rather than a whole request timed, which has the connect and the request going out in it.
+// The client makes one once the response head has been read so the speed is just the body's.
+// Wrapping a BufRead keeps it one, bytes count as they're consumed. Nothing actually gets held
+// back, it's named for what it's measuring against
+#[derive(Debug)]
+pub struct ThrottledReader<R> {
+    inner: R,
+    pub bytes_read: u64,
+    pub start_time: Instant,
+}
+
+impl<R> ThrottledReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            bytes_read: 0,
+            start_time: Instant::now(),
+        }
+    }
+
+    // 0 until any time has passed
+    #[must_use]
+    pub fn bytes_per_second(&self) -> f64 {
+        let secs = self.start_time.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            self.bytes_read as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes_read += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for ThrottledReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.bytes_read += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+// How much a read waits to have before it goes ahead, so a slow limit isn't a sleep and a
+// syscall for every few bytes
+const RATE_LIMIT_SLICE: Duration = Duration::from_millis(20);
+
+// Holds reads to bytes_per_sec as they happen, rather than sleeping off the difference once the
+// whole chunk is in like throttle_delay. A token bucket that starts empty and fills at
+// bytes_per_sec, up to a second's worth. Each read waits until there's a slice's worth in it (or
+// as much as was asked for if that's less) and then reads no more than there is. As a BufRead
+// it's fill_buf that waits and only shows what can be had, consume spends it. 0 is no limit
+#[derive(Debug)]
+pub struct RateLimitedReader<R> {
+    inner: R,
+    bytes_per_sec: u64,
+    token_bucket: f64,
+    last_refill: Instant,
+}
+
+impl<R> RateLimitedReader<R> {
+    pub fn new(inner: R, bytes_per_sec: u64) -> Self {
+        Self {
+            inner,
+            bytes_per_sec,
+            token_bucket: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let rate = self.bytes_per_sec as f64;
+        let earned = now.duration_since(self.last_refill).as_secs_f64() * rate;
+        self.token_bucket = (self.token_bucket + earned).min(rate);
+        self.last_refill = now;
+    }
+
+    // Sleeps until there's at least min(wanted, a slice) in the bucket and returns how many whole
+    // bytes' worth there are. wanted has to be at least 1, and then so is what comes back
+    fn wait_for(&mut self, wanted: usize) -> usize {
+        let rate = self.bytes_per_sec as f64;
+        let needed = (wanted as f64).min((rate * RATE_LIMIT_SLICE.as_secs_f64()).max(1.0));
+        loop {
+            self.refill();
+            if self.token_bucket >= needed {
+                return self.token_bucket as usize;
+            }
+            thread::sleep(Duration::from_secs_f64((needed - self.token_bucket) / rate));
+        }
+    }
+}
+
+impl<R: Read> Read for RateLimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.bytes_per_sec == 0 || buf.is_empty() {
+            return self.inner.read(buf);
+        }
+        let allowed = self.wait_for(buf.len()).min(buf.len());
+        let read = self.inner.read(&mut buf[..allowed])?;
+        self.token_bucket -= read as f64;
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for RateLimitedReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.bytes_per_sec == 0 {
+            return self.inner.fill_buf();
+        }
+        // Asked twice, once to see how much there is (and block for it if there's nothing) and
+        // once to hand it back after waiting, which doesn't read anything
+        let available = self.inner.fill_buf()?.len();
+        if available == 0 {
+            return Ok(&[]);
+        }
+        let allowed = self.wait_for(available);
+        let buf = self.inner.fill_buf()?;
+        Ok(&buf[..allowed.min(buf.len())])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.bytes_per_sec > 0 {
+            self.token_bucket -= amt as f64;
+        }
+        self.inner.consume(amt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_adds_counters() {
+        let mut total = DownloadStats {
+            hash: "abc".into(),
+            total_duration: Duration::from_secs(2),
+            chunks_attempted: 3,
+            total_retries: 1,
+            bytes_transferred: 300,
+            connection_errors: 0,
+            ..Default::default()
+        };
+        total.merge(&DownloadStats {
+            hash: "other".into(),
+            total_duration: Duration::from_secs(9),
+            chunks_attempted: 2,
+            total_retries: 4,
+            bytes_transferred: 250,
+            connection_errors: 2,
+            ..Default::default()
+        });
+        assert_eq!(
+            total,
+            DownloadStats {
+                hash: "abc".into(),
+                total_duration: Duration::from_secs(2),
+                chunks_attempted: 5,
+                total_retries: 5,
+                bytes_transferred: 550,
+                connection_errors: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn throttled_reader_counts_bytes() {
+        let mut reader = ThrottledReader::new(&b"HTTP/1.1 200 OK\r\n\r\nbody"[..]);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(reader.bytes_read, 17);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(reader.bytes_read, 23);
+        assert_eq!(rest, b"\r\nbody");
+
+        let reader = ThrottledReader {
+            start_time: Instant::now() - Duration::from_secs(2),
+            ..reader
+        };
+        let speed = reader.bytes_per_second();
+        assert!(speed > 10.0 && speed <= 11.5, "{speed}");
+        assert_eq!(ThrottledReader::new(io::empty()).bytes_per_second(), 0.0);
+    }
+
+    #[test]
+    fn rate_limited_reader_takes_as_long_as_it_should() {
+        // 3000 bytes at 10000 a second is 300ms whichever way it's read
+        let data = vec![7u8; 3000];
+        let started = Instant::now();
+        let mut read = Vec::new();
+        RateLimitedReader::new(data.as_slice(), 10_000)
+            .read_to_end(&mut read)
+            .unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(read, data);
+        assert!(elapsed >= Duration::from_millis(300), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+
+        let started = Instant::now();
+        let mut reader = RateLimitedReader::new(data.as_slice(), 10_000);
+        let mut total = 0;
+        loop {
+            let got = reader.fill_buf().unwrap().len();
+            if got == 0 {
+                break;
+            }
+            // Only what's been earned, a slice (200 bytes here) or a bit more after oversleeping
+            assert!(got < 1000, "{got}");
+            reader.consume(got);
+            total += got;
+        }
+        let elapsed = started.elapsed();
+        assert_eq!(total, data.len());
+        assert!(elapsed >= Duration::from_millis(300), "{elapsed:?}");
+
+        // No limit doesn't wait at all
+        let started = Instant::now();
+        let mut read = Vec::new();
+        RateLimitedReader::new(data.as_slice(), 0)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn chunk_timings() {
+        // Each chunk gets counted as attempted before it's recorded, like download_chunk does
+        let timed = |millis: &[u64]| {
+            let mut stats = DownloadStats::default();
+            for &ms in millis {
+                stats.chunks_attempted += 1;
+                stats.record_chunk(Duration::from_millis(ms));
+            }
+            stats
+        };
+        let stats = timed(&[30, 10, 20]);
+        assert_eq!(stats.fastest_chunk_ms, 10);
+        assert_eq!(stats.slowest_chunk_ms, 30);
+        assert!((stats.average_chunk_ms - 20.0).abs() < 1e-9);
+
+        let mut total = DownloadStats::default();
+        total.merge(&stats);
+        total.merge(&DownloadStats::default());
+        total.merge(&timed(&[50]));
+        assert_eq!(total.chunks_attempted, 4);
+        assert_eq!(total.fastest_chunk_ms, 10);
+        assert_eq!(total.slowest_chunk_ms, 50);
+        assert!((total.average_chunk_ms - 27.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn displays_a_summary() {
+        let stats = DownloadStats {
+            hash_algorithm: Some(HashAlgorithm::Sha256),
+            hash_verified: true,
+            total_duration: Duration::from_millis(2300),
+            chunks_attempted: 32,
+            total_retries: 2,
+            bytes_transferred: 1_048_576,
+            fastest_chunk_ms: 40,
+            slowest_chunk_ms: 250,
+            average_chunk_ms: 71.25,
+            ..Default::default()
+        };
+        assert_eq!(
+            stats.to_string(),
+            "Download complete: 1,048,576 bytes in 2.3 s (445 KiB/s), 32 chunks, 2 retries, \
+             SHA-256 verified\n Chunks took 40ms at the fastest, 250ms at the slowest, 71.2ms on \
+             average"
+        );
+
+        let quick = DownloadStats {
+            hash_algorithm: Some(HashAlgorithm::Blake3),
+            total_duration: Duration::from_secs(1),
+            bytes_transferred: 5 * 1024 * 1024 + 512 * 1024,
+            connection_errors: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            quick.to_string(),
+            "Download complete: 5,767,168 bytes in 1.0 s (5.5 MiB/s), 0 chunks, 0 retries, 1 \
+             connection errors, BLAKE3 not checked"
+        );
+        assert_eq!(
+            DownloadStats::default().to_string(),
+            "Download complete: 0 bytes in 0.0 s, 0 chunks, 0 retries, not hashed"
+        );
+        assert_eq!(thousands(999), "999");
+        assert_eq!(thousands(1000), "1,000");
+        assert_eq!(rate(100.0), "100 B/s");
+    }
+}
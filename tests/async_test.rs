@@ -0,0 +1,193 @@
+// The async client against common::MockTcpServer, only built with --features async
+#![cfg(feature = "async")]
+
+mod common;
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use glitchy_http::async_client::download_full_data_async_with_progress;
+use glitchy_http::config::DownloadConfig;
+use glitchy_http::error::DownloadError;
+use glitchy_http::sha::calculate_hash;
+
+use common::{test_data, FaultConfig, MockTcpServer};
+use tokio_util::sync::CancellationToken;
+
+fn test_config(addr: SocketAddr) -> DownloadConfig {
+    DownloadConfig {
+        server_addr: addr.to_string(),
+        chunk_size: 4096,
+        max_retries: 3,
+        retry_base_delay: Duration::from_millis(1),
+        retry_max_delay: Duration::from_millis(5),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn downloads_whole_file() {
+    let data = test_data(50_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = test_config(server.addr);
+
+    let (downloaded, stats) = download_full_data_async_with_progress(50_000, &config, None)
+        .await
+        .unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    assert_eq!(stats.chunks_attempted, 13);
+    assert_eq!(stats.total_retries, 0);
+}
+
+#[tokio::test]
+async fn retries_the_same_way_as_sync() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            drop_at_offset: Some(13_000),
+            fail_request: Some(1),
+            ..Default::default()
+        },
+    );
+    let config = test_config(server.addr);
+
+    // Has to be Send to go on tokio::spawn
+    let task =
+        tokio::spawn(
+            async move { download_full_data_async_with_progress(20_000, &config, None).await },
+        );
+    let (downloaded, stats) = task.await.unwrap().unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.total_retries, 2);
+}
+
+#[tokio::test]
+async fn keepalive_limits_match_sync() {
+    let data = test_data(20 * 4096);
+    let server = MockTcpServer::new(data.clone());
+    let config = DownloadConfig {
+        keepalive_max_requests: 5,
+        ..test_config(server.addr)
+    };
+
+    let (downloaded, _) = download_full_data_async_with_progress(data.len() as u64, &config, None)
+        .await
+        .unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(server.connection_count(), 4);
+}
+
+#[tokio::test]
+async fn not_found_is_not_retried() {
+    let server = MockTcpServer::with_faults(
+        test_data(100),
+        FaultConfig {
+            always_status: Some(404),
+            ..Default::default()
+        },
+    );
+    let config = test_config(server.addr);
+
+    match download_full_data_async_with_progress(100, &config, None).await {
+        Err(DownloadError::ServerError { status: 404, .. }) => {}
+        other => panic!("Expected a 404, got {other:?}"),
+    }
+    assert_eq!(server.request_count(), 1);
+}
+
+#[tokio::test]
+async fn slow_server_times_out() {
+    let server = MockTcpServer::with_faults(
+        test_data(100),
+        FaultConfig {
+            delay: Duration::from_millis(500),
+            ..Default::default()
+        },
+    );
+    let config = DownloadConfig {
+        read_timeout: Duration::from_millis(50),
+        max_retries: 2,
+        ..test_config(server.addr)
+    };
+
+    match download_full_data_async_with_progress(100, &config, None).await {
+        Err(DownloadError::RetriesExhausted {
+            attempts,
+            last_error,
+        }) => {
+            assert_eq!(attempts, 2);
+            assert!(
+                matches!(*last_error, DownloadError::Timeout { .. }),
+                "{last_error:?}"
+            );
+        }
+        other => panic!("Expected the retries to run out, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn chunked_responses_get_decoded() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            chunked: Some(1000),
+            ..Default::default()
+        },
+    );
+    let config = test_config(server.addr);
+
+    let (downloaded, stats) = download_full_data_async_with_progress(20_000, &config, None)
+        .await
+        .unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.total_retries, 0);
+    assert_eq!(server.request_count(), 5);
+}
+
+#[tokio::test]
+async fn cancel_token_stops_it() {
+    let server = MockTcpServer::new(test_data(20_000));
+    let config = DownloadConfig {
+        cancel_token: Some(std::sync::Arc::new(true.into())),
+        ..test_config(server.addr)
+    };
+
+    match download_full_data_async_with_progress(20_000, &config, None).await {
+        Err(DownloadError::Cancelled) => {}
+        other => panic!("Expected Cancelled, got {other:?}"),
+    }
+    assert_eq!(server.request_count(), 0);
+}
+
+#[tokio::test]
+async fn cancellation_token_stops_it_mid_chunk() {
+    let server = MockTcpServer::with_faults(
+        test_data(20_000),
+        FaultConfig {
+            delay: Duration::from_secs(2),
+            ..Default::default()
+        },
+    );
+    let token = CancellationToken::new();
+    let config = DownloadConfig {
+        async_cancel_token: Some(token.clone()),
+        ..test_config(server.addr)
+    };
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        token.cancel();
+    });
+
+    // The first response is still 2s off, it doesn't get waited for
+    let started = Instant::now();
+    match download_full_data_async_with_progress(20_000, &config, None).await {
+        Err(DownloadError::Cancelled) => {}
+        other => panic!("Expected Cancelled, got {other:?}"),
+    }
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
@@ -0,0 +1,340 @@
+// A tiny HTTP/1.1 server running on a background thread for the integration tests. Unlike the
+// python server this one gets Range right (inclusive ends, Content-Range, keep-alive) unless
+// fault_injection tells it to misbehave. Several ranges in one request get a multipart/byteranges
+// response. Not every test file uses every part of it
+#![allow(dead_code)]
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+// Ways the server can go wrong, the default is none of them
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    // Hangs up once a body gets to this offset into the data, having already promised the whole
+    // range in Content-Length. Only the first response that gets there, after that it's fine
+    pub drop_at_offset: Option<u64>,
+    // Request number, counting from 0, that gets a 500 instead of the data
+    pub fail_request: Option<usize>,
+    // Every request gets this status and an empty body, ie 404
+    pub always_status: Option<u16>,
+    // Request number whose body comes back the right length but with every byte flipped
+    pub corrupt_request: Option<usize>,
+    // Request number that gets the same length from the start of the data instead of where it
+    // asked, with a Content-Range that owns up to it
+    pub wrong_range_request: Option<usize>,
+    // Request number that gets a 429 with this Retry-After value
+    pub rate_limit_request: Option<(usize, &'static str)>,
+    // Waits this long before answering every request
+    pub delay: Duration,
+    // Sends bodies with Transfer-Encoding: chunked in pieces of this size instead of Content-Length
+    pub chunked: Option<usize>,
+    // gzips every body that was asked for with Accept-Encoding, each response on its own
+    pub gzip: bool,
+    // Requests for the first path get a 302 to the second, which goes in Location as is
+    pub redirects: Vec<(&'static str, String)>,
+    // Sent as the ETag of every range response, and a request with it in If-None-Match gets a
+    // 304 instead
+    pub etag: Option<&'static str>,
+}
+
+pub struct MockTcpServer {
+    pub addr: SocketAddr,
+    // Number of requests that have come in so far
+    pub requests: Arc<AtomicUsize>,
+    // And connections that have been accepted
+    pub connections: Arc<AtomicUsize>,
+    // Read for every request so changes apply to the next one, ie set_faults straight after new
+    pub fault_injection: Arc<Mutex<FaultConfig>>,
+}
+
+// What every connection thread shares
+struct State {
+    data: Vec<u8>,
+    requests: Arc<AtomicUsize>,
+    fault_injection: Arc<Mutex<FaultConfig>>,
+    dropped: AtomicBool,
+}
+
+impl MockTcpServer {
+    // Binds to a random free port and serves data until the test process exits
+    pub fn new(data: Vec<u8>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let fault_injection = Arc::new(Mutex::new(FaultConfig::default()));
+
+        let state = Arc::new(State {
+            data,
+            requests: Arc::clone(&requests),
+            fault_injection: Arc::clone(&fault_injection),
+            dropped: AtomicBool::new(false),
+        });
+        let accepted = Arc::clone(&connections);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let state = Arc::clone(&state);
+                // One thread per connection so a keep-alive client doesn't block anyone else
+                thread::spawn(move || handle_connection(stream, &state));
+            }
+        });
+
+        Self {
+            addr,
+            requests,
+            connections,
+            fault_injection,
+        }
+    }
+
+    pub fn with_faults(data: Vec<u8>, faults: FaultConfig) -> Self {
+        let server = Self::new(data);
+        server.set_faults(faults);
+        server
+    }
+
+    pub fn set_faults(&self, faults: FaultConfig) {
+        *self
+            .fault_injection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = faults;
+    }
+
+    pub fn request_count(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: &State) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    let data = state.data.as_slice();
+
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut range = None;
+        let mut multi_range = Vec::new();
+        let mut accepts_gzip = false;
+        let mut if_none_match = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("range") {
+                    range = parse_range(value.trim(), data.len());
+                    if let Some(specs) = value.trim().strip_prefix("bytes=") {
+                        multi_range = specs
+                            .split(',')
+                            .filter_map(|spec| parse_range(&format!("bytes={spec}"), data.len()))
+                            .collect();
+                    }
+                }
+                if name.eq_ignore_ascii_case("accept-encoding") {
+                    accepts_gzip = value.contains("gzip");
+                }
+                if name.eq_ignore_ascii_case("if-none-match") {
+                    if_none_match = Some(value.trim().to_owned());
+                }
+            }
+        }
+        let nth = state.requests.fetch_add(1, Ordering::SeqCst);
+        let faults = state
+            .fault_injection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let path = request_line.split(' ').nth(1).unwrap_or_default();
+        if let Some((_, location)) = faults.redirects.iter().find(|(from, _)| *from == path) {
+            let response =
+                format!("HTTP/1.1 302 Found\r\nLocation: {location}\r\nContent-Length: 0\r\n\r\n");
+            let _ = stream.write_all(response.as_bytes());
+            continue;
+        }
+
+        thread::sleep(faults.delay);
+        let status = match (faults.always_status, faults.fail_request) {
+            (Some(code), _) => Some(code),
+            (None, Some(n)) if n == nth => Some(500),
+            _ => None,
+        };
+        // The reason again as the body, like plenty of real servers do
+        if let Some(code) = status {
+            let body = format!("{}\n", reason(code));
+            let response = format!(
+                "HTTP/1.1 {code} {}\r\nContent-Length: {}\r\n\r\n{body}",
+                reason(code),
+                body.len()
+            );
+            let _ = stream.write_all(response.as_bytes());
+            continue;
+        }
+        if let Some((_, retry_after)) = faults.rate_limit_request.filter(|&(n, _)| n == nth) {
+            let response = format!(
+                "HTTP/1.1 429 Too Many Requests\r\nRetry-After: {retry_after}\r\n\
+                 Content-Length: 0\r\n\r\n"
+            );
+            let _ = stream.write_all(response.as_bytes());
+            continue;
+        }
+
+        if faults.etag.is_some() && if_none_match.as_deref() == faults.etag {
+            let response = "HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\n\r\n";
+            if stream.write_all(response.as_bytes()).is_err() {
+                return;
+            }
+            continue;
+        }
+
+        if multi_range.len() > 1 {
+            let mut body = Vec::new();
+            for &(start, end) in &multi_range {
+                body.extend_from_slice(
+                    format!(
+                        "\r\n--BOUNDARY\r\nContent-Type: application/octet-stream\r\n\
+                         Content-Range: bytes {start}-{end}/{}\r\n\r\n",
+                        data.len()
+                    )
+                    .as_bytes(),
+                );
+                body.extend_from_slice(&data[start..=end]);
+            }
+            body.extend_from_slice(b"\r\n--BOUNDARY--\r\n");
+            let mut response = format!(
+                "HTTP/1.1 206 Partial Content\r\n\
+                 Content-Type: multipart/byteranges; boundary=BOUNDARY\r\n\
+                 Content-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&body);
+            if stream.write_all(&response).is_err() {
+                return;
+            }
+            continue;
+        }
+
+        let corrupted: Vec<u8>;
+        let range = match range {
+            Some((start, end)) if faults.wrong_range_request == Some(nth) => Some((0, end - start)),
+            range => range,
+        };
+        let (status, start, mut body, mut content_range) = match range {
+            Some((start, end)) => (
+                "206 Partial Content",
+                start,
+                &data[start..=end],
+                format!("Content-Range: bytes {start}-{end}/{}\r\n", data.len()),
+            ),
+            None => ("200 OK", 0, data, String::new()),
+        };
+        if let Some(etag) = faults.etag {
+            content_range.push_str(&format!("ETag: {etag}\r\n"));
+        }
+        if faults.corrupt_request == Some(nth) {
+            corrupted = body.iter().map(|b| !b).collect();
+            body = &corrupted;
+        }
+        if faults.gzip && accepts_gzip && !request_line.starts_with("HEAD") {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body).unwrap();
+            let compressed = encoder.finish().unwrap();
+            let mut response = format!(
+                "HTTP/1.1 {status}\r\n{content_range}Content-Encoding: gzip\r\n\
+                 Content-Length: {}\r\n\r\n",
+                compressed.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&compressed);
+            if stream.write_all(&response).is_err() {
+                return;
+            }
+            continue;
+        }
+        if let Some(piece) = faults.chunked.filter(|_| !request_line.starts_with("HEAD")) {
+            let mut response =
+                format!("HTTP/1.1 {status}\r\n{content_range}Transfer-Encoding: chunked\r\n\r\n")
+                    .into_bytes();
+            for part in body.chunks(piece.max(1)) {
+                response.extend_from_slice(format!("{:x}\r\n", part.len()).as_bytes());
+                response.extend_from_slice(part);
+                response.extend_from_slice(b"\r\n");
+            }
+            response.extend_from_slice(b"0\r\n\r\n");
+            if stream.write_all(&response).is_err() {
+                return;
+            }
+            continue;
+        }
+        let mut response = format!(
+            "HTTP/1.1 {status}\r\n{content_range}Content-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        if !request_line.starts_with("HEAD") {
+            let cut = faults
+                .drop_at_offset
+                .and_then(|offset| usize::try_from(offset).ok())
+                .filter(|&offset| (start..start + body.len()).contains(&offset))
+                .filter(|_| !state.dropped.swap(true, Ordering::SeqCst));
+            if let Some(offset) = cut {
+                response.extend_from_slice(&body[..offset - start]);
+                let _ = stream.write_all(&response);
+                return;
+            }
+            response.extend_from_slice(body);
+        }
+        // All in one write, head then body separately runs into Nagle + delayed ACKs and every
+        // request stalls for 40ms
+        if stream.write_all(&response).is_err() {
+            return;
+        }
+    }
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        401 => "Unauthorized",
+        404 => "Not Found",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+// bytes=<start>-<end> with end clamped to the data like a real server would
+fn parse_range(value: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let start: usize = start.parse().ok()?;
+    let end: usize = end.parse::<usize>().ok()?.min(len - 1);
+    (start <= end).then_some((start, end))
+}
+
+// Not all zeros so a chunk landing in the wrong place would show up in the hash
+pub fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
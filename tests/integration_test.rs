@@ -0,0 +1,1315 @@
+// End to end tests against common::MockTcpServer, which gets Range right unless told to misbehave
+mod common;
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use glitchy_http::client::{
+    discover_total_size, download_full_data_with_progress, download_plan_with_progress,
+    download_streaming, download_to_file_with_progress, fetch_multiple_ranges,
+    parallel_download_full_data_with_progress, plan_download, resolve_redirects, test_connection,
+    DownloadPlan,
+};
+use glitchy_http::config::DownloadConfig;
+use glitchy_http::error::DownloadError;
+use glitchy_http::multi::{FileSpec, MultiFileDownloader};
+use glitchy_http::progress::ProgressObserver;
+use glitchy_http::sha::{calculate_hash, calculate_sha256};
+
+use common::{test_data, FaultConfig, MockTcpServer};
+
+// Small chunks so even a small file takes a few of them, and retries that don't hang about
+fn test_config(addr: SocketAddr) -> DownloadConfig {
+    DownloadConfig {
+        server_addr: addr.to_string(),
+        chunk_size: 4096,
+        max_retries: 3,
+        retry_base_delay: Duration::from_millis(1),
+        retry_max_delay: Duration::from_millis(5),
+        ..Default::default()
+    }
+}
+
+// What every failed attempt said, progress is ignored
+#[derive(Default)]
+struct RetryMessages(Vec<String>);
+
+impl ProgressObserver for RetryMessages {
+    fn on_progress(&mut self, _downloaded: u64, _total: u64) {}
+    fn on_retry(&mut self, error: &DownloadError) {
+        self.0.push(error.to_string());
+    }
+}
+
+#[test]
+fn downloads_whole_file() {
+    let data = test_data(50_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = test_config(server.addr);
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    assert_eq!(stats.hash_algorithm, Some(config.hash_algorithm));
+    // Nothing to check it against
+    assert!(!stats.hash_verified);
+    assert_eq!(stats.chunks_attempted, 13);
+    assert_eq!(stats.total_retries, 0);
+}
+
+#[test]
+fn downloads_to_file_and_discovers_size() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = test_config(server.addr);
+
+    let total_size = discover_total_size(&config).unwrap();
+    assert_eq!(total_size, data.len() as u64);
+
+    let path = std::env::temp_dir().join(format!("glitchy-http-test-{}.bin", server.addr.port()));
+    let stats = download_to_file_with_progress(total_size, &path, &config, None).unwrap();
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(written, data);
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+}
+
+#[test]
+fn unwritable_output_is_a_filesystem_error() {
+    let data = test_data(10_000);
+    let server = MockTcpServer::new(data.clone());
+    let path = std::env::temp_dir()
+        .join(format!("glitchy-http-missing-{}", server.addr.port()))
+        .join("out.bin");
+
+    match download_to_file_with_progress(data.len() as u64, &path, &test_config(server.addr), None)
+    {
+        Err(DownloadError::FilesystemError {
+            path: failed,
+            inner,
+        }) => {
+            assert_eq!(failed, path);
+            assert_eq!(inner.kind(), std::io::ErrorKind::NotFound);
+        }
+        other => panic!("Expected FilesystemError, got {other:?}"),
+    }
+    // Nothing got asked for since the file couldn't be opened
+    assert_eq!(server.request_count(), 0);
+}
+
+#[test]
+fn write_buffer_size_does_not_change_the_file() {
+    let data = test_data(30_000);
+    let server = MockTcpServer::new(data.clone());
+
+    // Smaller than a chunk, a few chunks, and no buffering at all
+    for write_buffer_size in [1000, 3 * 4096, 0] {
+        let config = DownloadConfig {
+            write_buffer_size,
+            ..test_config(server.addr)
+        };
+        let path = std::env::temp_dir().join(format!(
+            "glitchy-http-test-{}-{write_buffer_size}.bin",
+            server.addr.port()
+        ));
+        download_to_file_with_progress(data.len() as u64, &path, &config, None).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, data, "write_buffer_size {write_buffer_size}");
+    }
+}
+
+#[test]
+fn plan_skips_ranges_already_in_the_file() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::new(data.clone());
+    let path = std::env::temp_dir().join(format!("glitchy-http-plan-{}.bin", server.addr.port()));
+    let _ = std::fs::remove_file(&path);
+    // Where from and the chunk size come out of the plan, not the config
+    let config = DownloadConfig {
+        server_addr: "127.0.0.1:1".into(),
+        chunk_size: 1,
+        ..test_config(server.addr)
+    };
+    let full = plan_download(data.len() as u64, &test_config(server.addr));
+
+    // Just two of the chunks, the rest of the file stays zeroed
+    let some = DownloadPlan {
+        ranges: vec![full.ranges[4], full.ranges[1]],
+        created_at: SystemTime::UNIX_EPOCH,
+        ..full.clone()
+    };
+    let stats = download_plan_with_progress(&some, &path, &config, None).unwrap();
+    let written = std::fs::read(&path).unwrap();
+    assert_eq!(written.len(), data.len());
+    assert_eq!(written[4096..8192], data[4096..8192]);
+    assert_eq!(written[16_384..], data[16_384..]);
+    assert!(written[..4096].iter().all(|&b| b == 0));
+    assert!(written[8192..16_384].iter().all(|&b| b == 0));
+    assert_eq!(stats.hash, calculate_hash(&written, config.hash_algorithm));
+    assert_eq!(server.request_count(), 2);
+
+    // The whole plan only asks for what's missing
+    let stats = download_plan_with_progress(&full, &path, &config, None).unwrap();
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(written, data);
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    assert_eq!(stats.chunks_attempted, 3);
+    assert_eq!(server.request_count(), 5);
+
+    let past_the_end = DownloadPlan {
+        ranges: vec![(0, data.len() as u64)],
+        ..full
+    };
+    assert!(matches!(
+        download_plan_with_progress(&past_the_end, &path, &config, None),
+        Err(DownloadError::Args(_))
+    ));
+}
+
+#[test]
+fn unchanged_files_come_from_the_etag_cache() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            etag: Some("\"v1\""),
+            ..Default::default()
+        },
+    );
+    let dir = std::env::temp_dir().join(format!("glitchy-http-etags-{}", server.addr.port()));
+    let config = DownloadConfig {
+        etag_cache_dir: Some(dir.clone()),
+        ..test_config(server.addr)
+    };
+    let chunks = data.len().div_ceil(4096);
+
+    // Nothing cached, so one request to find out the ETag and then the download
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert!(stats.bytes_transferred >= data.len() as u64);
+    assert_eq!(server.request_count(), 1 + chunks);
+
+    // Same ETag, so the one conditional request is all it takes
+    let (cached, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(cached, data);
+    assert_eq!(stats.bytes_transferred, 0);
+    assert_eq!(stats.chunks_attempted, 0);
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    assert_eq!(server.request_count(), 2 + chunks);
+
+    // A new ETag on the server means downloading it again
+    server.set_faults(FaultConfig {
+        etag: Some("\"v2\""),
+        ..Default::default()
+    });
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert!(stats.bytes_transferred > 0);
+    assert_eq!(server.request_count(), 3 + 2 * chunks);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn prefetch_downloads_every_chunk_once() {
+    let data = test_data(50_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = DownloadConfig {
+        prefetch: true,
+        ..test_config(server.addr)
+    };
+    let chunks = data.len().div_ceil(4096);
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    assert_eq!(stats.chunks_attempted as usize, chunks);
+    assert_eq!(stats.total_retries, 0);
+    assert_eq!(server.request_count(), chunks);
+    // The first chunk on the main connection, everything after it on the prefetch one
+    assert_eq!(server.connection_count(), 2);
+}
+
+#[test]
+fn streaming_yields_every_chunk_in_order() {
+    let data = test_data(30_000);
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            fail_request: Some(2),
+            ..Default::default()
+        },
+    );
+    let config = test_config(server.addr);
+
+    let mut stream = download_streaming(data.len() as u64, &config);
+    let mut offset = 0;
+    for chunk in stream.by_ref() {
+        let chunk = chunk.unwrap();
+        assert_eq!(chunk, data[offset..offset + chunk.len()]);
+        offset += chunk.len();
+    }
+    assert_eq!(offset, data.len());
+    let stats = stream.stats().unwrap();
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    assert_eq!(stats.total_retries, 1);
+
+    // Collected it's download_full_data
+    let collected = download_streaming(data.len() as u64, &config)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap()
+        .concat();
+    assert_eq!(collected, data);
+}
+
+#[test]
+fn streaming_ends_with_the_error() {
+    let server = MockTcpServer::with_faults(
+        test_data(10_000),
+        FaultConfig {
+            always_status: Some(404),
+            ..Default::default()
+        },
+    );
+    let mut stream = download_streaming(10_000, &test_config(server.addr));
+    assert!(matches!(
+        stream.next(),
+        Some(Err(DownloadError::ServerError { status: 404, .. }))
+    ));
+    assert!(stream.next().is_none());
+    assert!(stream.stats().is_none());
+}
+
+#[test]
+fn printing_headers_leaves_the_data_alone() {
+    let data = test_data(30_000);
+    for pipeline_depth in [1, 4] {
+        let server = MockTcpServer::with_faults(
+            data.clone(),
+            FaultConfig {
+                fail_request: Some(0),
+                ..Default::default()
+            },
+        );
+        let config = DownloadConfig {
+            debug_headers: true,
+            pipeline_depth,
+            ..test_config(server.addr)
+        };
+        let (downloaded, stats) =
+            download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+        assert_eq!(downloaded, data);
+        assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    }
+}
+
+#[test]
+fn failed_prefetches_get_retried() {
+    let data = test_data(30_000);
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            fail_request: Some(2),
+            ..Default::default()
+        },
+    );
+    let config = DownloadConfig {
+        prefetch: true,
+        ..test_config(server.addr)
+    };
+    let chunks = data.len().div_ceil(4096);
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.chunks_attempted as usize, chunks);
+    assert_eq!(stats.total_retries, 1);
+    assert_eq!(server.request_count(), chunks + 1);
+}
+
+// Every chunk's measured speed
+#[derive(Default)]
+struct Throughputs(Vec<f64>);
+
+impl ProgressObserver for Throughputs {
+    fn on_progress(&mut self, _downloaded: u64, _total: u64) {}
+    fn on_throughput(&mut self, bytes_per_sec: f64) {
+        self.0.push(bytes_per_sec);
+    }
+}
+
+#[test]
+fn every_chunk_reports_its_throughput() {
+    let data = test_data(50_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = test_config(server.addr);
+
+    let mut throughputs = Throughputs::default();
+    download_full_data_with_progress(data.len() as u64, &config, Some(&mut throughputs)).unwrap();
+    // Only the ones too quick to time get left out
+    assert!(!throughputs.0.is_empty());
+    assert!(throughputs.0.len() <= data.len().div_ceil(4096));
+    assert!(throughputs.0.iter().all(|&speed| speed > 0.0));
+}
+
+#[test]
+fn no_hash_still_downloads_everything() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = DownloadConfig {
+        compute_hash: false,
+        ..test_config(server.addr)
+    };
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.hash, "");
+    assert_eq!(stats.hash_algorithm, None);
+
+    let path = std::env::temp_dir().join(format!("glitchy-http-nohash-{}.bin", server.addr.port()));
+    let stats = download_to_file_with_progress(data.len() as u64, &path, &config, None).unwrap();
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(written, data);
+    assert_eq!(stats.hash, "");
+}
+
+#[test]
+fn stops_once_the_server_sends_too_much() {
+    let data = test_data(50_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = DownloadConfig {
+        max_total_bytes: Some(20_000),
+        ..test_config(server.addr)
+    };
+    let too_much = |e: &DownloadError| matches!(e, DownloadError::Logic(msg) if msg == "Response exceeds expected size");
+
+    let err = download_full_data_with_progress(data.len() as u64, &config, None).unwrap_err();
+    assert!(too_much(&err), "{err:?}");
+    let config = DownloadConfig {
+        concurrency: 4,
+        ..config
+    };
+    let err =
+        parallel_download_full_data_with_progress(data.len() as u64, &config, None).unwrap_err();
+    assert!(too_much(&err), "{err:?}");
+
+    // The file never gets past the limit either
+    let path = std::env::temp_dir().join(format!("glitchy-http-limit-{}.bin", server.addr.port()));
+    let err = download_to_file_with_progress(data.len() as u64, &path, &config, None).unwrap_err();
+    let written = std::fs::metadata(&path).unwrap().len();
+    std::fs::remove_file(&path).unwrap();
+    match err {
+        DownloadError::PartialDownload { cause, .. } => assert!(too_much(&cause), "{cause:?}"),
+        other => panic!("Expected PartialDownload, got {other:?}"),
+    }
+    assert!(written <= 20_000);
+
+    // Twice the size like the CLI's default is plenty. Exactly the size wouldn't be, every chunk
+    // asks for a byte more than it needs for the python server
+    let config = DownloadConfig {
+        max_total_bytes: Some(2 * data.len() as u64),
+        ..test_config(server.addr)
+    };
+    let (downloaded, _) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+}
+
+#[cfg(unix)]
+#[test]
+fn progress_goes_to_the_fd() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::new(data.clone());
+    let path = std::env::temp_dir().join(format!("glitchy-http-fd-{}.txt", server.addr.port()));
+    let out = std::fs::File::create(&path).unwrap();
+    let config = DownloadConfig {
+        progress_fd: Some(Arc::new(out)),
+        ..test_config(server.addr)
+    };
+
+    let (downloaded, _) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    drop(config);
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(downloaded, data);
+    // Two lines per chunk, the last one saying it's all there
+    let lines: Vec<u64> = written.lines().map(|line| line.parse().unwrap()).collect();
+    assert_eq!(lines.len(), 2 * data.len().div_ceil(4096));
+    assert!(lines.chunks(2).all(|pair| pair[1] == data.len() as u64));
+    assert_eq!(lines[lines.len() - 2], data.len() as u64);
+}
+
+#[test]
+fn not_found_is_not_retried() {
+    let server = MockTcpServer::with_faults(
+        test_data(100),
+        FaultConfig {
+            always_status: Some(404),
+            ..Default::default()
+        },
+    );
+    let config = test_config(server.addr);
+
+    match download_full_data_with_progress(100, &config, None) {
+        Err(DownloadError::ServerError { status: 404, .. }) => {}
+        other => panic!("Expected a 404, got {other:?}"),
+    }
+    assert_eq!(server.request_count(), 1);
+}
+
+#[test]
+fn error_responses_keep_their_body() {
+    let server = MockTcpServer::with_faults(
+        test_data(100),
+        FaultConfig {
+            always_status: Some(500),
+            ..Default::default()
+        },
+    );
+    let config = test_config(server.addr);
+
+    match download_full_data_with_progress(100, &config, None) {
+        Err(DownloadError::RetriesExhausted { last_error, .. }) => match *last_error {
+            DownloadError::ServerError { status, ref body } => {
+                assert_eq!(status, 500);
+                assert_eq!(body, b"Internal Server Error\n");
+                assert_eq!(
+                    last_error.to_string(),
+                    "Server Error: server returned 500: Internal Server Error"
+                );
+            }
+            other => panic!("Expected a ServerError, got {other:?}"),
+        },
+        other => panic!("Expected RetriesExhausted, got {other:?}"),
+    }
+}
+
+#[test]
+fn truncated_body_gets_retried() {
+    let data = test_data(10_000);
+    // Halfway through the first chunk
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            drop_at_offset: Some(2048),
+            ..Default::default()
+        },
+    );
+    let config = test_config(server.addr);
+
+    // Caught against Content-Length as soon as the connection closes, before anything looks at
+    // the chunk's length. 4097 because of the extra byte download_chunk asks for
+    let mut retries = RetryMessages::default();
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, Some(&mut retries)).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.total_retries, 1);
+    assert_eq!(
+        retries.0,
+        ["Response Parse Error: Response body truncated: got 2048 bytes, expected 4097"]
+    );
+}
+
+#[test]
+fn slow_server_times_out() {
+    let server = MockTcpServer::with_faults(
+        test_data(100),
+        FaultConfig {
+            delay: Duration::from_millis(500),
+            ..Default::default()
+        },
+    );
+    let config = DownloadConfig {
+        read_timeout: Duration::from_millis(50),
+        max_retries: 2,
+        ..test_config(server.addr)
+    };
+
+    match download_full_data_with_progress(100, &config, None) {
+        Err(DownloadError::RetriesExhausted {
+            attempts,
+            last_error,
+        }) => {
+            assert_eq!(attempts, 2);
+            assert!(
+                matches!(*last_error, DownloadError::Timeout { .. }),
+                "{last_error:?}"
+            );
+        }
+        other => panic!("Expected the retries to run out, got {other:?}"),
+    }
+    assert_eq!(server.request_count(), 2);
+}
+
+#[test]
+fn timeouts_say_how_long_they_waited() {
+    // Every retry it hears about, the overall error only says the retries ran out
+    struct Retries(Vec<String>);
+    impl ProgressObserver for Retries {
+        fn on_progress(&mut self, _downloaded: u64, _total: u64) {}
+        fn on_retry(&mut self, error: &DownloadError) {
+            assert!(
+                matches!(error, DownloadError::Timeout { timeout } if *timeout == Duration::from_millis(50)),
+                "{error:?}"
+            );
+            self.0.push(error.to_string());
+        }
+    }
+
+    let server = MockTcpServer::with_faults(
+        test_data(100),
+        FaultConfig {
+            delay: Duration::from_millis(500),
+            ..Default::default()
+        },
+    );
+    let config = DownloadConfig {
+        read_timeout: Duration::from_millis(50),
+        max_retries: 2,
+        ..test_config(server.addr)
+    };
+
+    let mut retries = Retries(Vec::new());
+    assert!(download_full_data_with_progress(100, &config, Some(&mut retries)).is_err());
+    assert_eq!(retries.0, ["Connection timed out after 0.05s"]);
+}
+
+#[test]
+fn nothing_listening_is_connection_refused() {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = DownloadConfig {
+        max_retries: 2,
+        ..test_config(addr)
+    };
+
+    let mut retries = RetryMessages::default();
+    assert!(download_full_data_with_progress(100, &config, Some(&mut retries)).is_err());
+    assert_eq!(
+        retries.0,
+        [format!("Server at {addr} refused connection, check that it's running and listening on that port")]
+    );
+}
+
+#[test]
+fn redirects_get_followed() {
+    let data = test_data(10_000);
+    let server = MockTcpServer::new(data.clone());
+    // An absolute URL then a relative path
+    server.set_faults(FaultConfig {
+        redirects: vec![
+            ("/old", format!("http://{}/new/", server.addr)),
+            ("/new/", "data.bin".into()),
+            ("/loop", "/loop".into()),
+        ],
+        ..Default::default()
+    });
+    let config = DownloadConfig {
+        path: "/old".into(),
+        follow_redirects: true,
+        ..test_config(server.addr)
+    };
+
+    assert_eq!(discover_total_size(&config).unwrap(), data.len() as u64);
+    let (downloaded, _) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+
+    let resolved = resolve_redirects(&config).unwrap();
+    assert_eq!(resolved.path, "/new/data.bin");
+    assert!(!resolved.follow_redirects);
+
+    let looping = DownloadConfig {
+        path: "/loop".into(),
+        ..config.clone()
+    };
+    match download_full_data_with_progress(100, &looping, None) {
+        Err(DownloadError::Network(msg)) => assert_eq!(msg.to_string(), "Redirect loop detected"),
+        other => panic!("Expected a redirect loop, got {other:?}"),
+    }
+    // Without follow_redirects a 302 is just another status to retry
+    let off = DownloadConfig {
+        follow_redirects: false,
+        ..config
+    };
+    assert!(download_full_data_with_progress(100, &off, None).is_err());
+}
+
+#[test]
+fn fetches_several_ranges_at_once() {
+    let data = test_data(10_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = test_config(server.addr);
+    let ranges = [(0, 99), (5000, 5000), (9900, 9999)];
+
+    assert_eq!(
+        fetch_multiple_ranges(&ranges, &config).unwrap(),
+        parts_of(&data, &ranges)
+    );
+    assert_eq!(server.request_count(), 1);
+
+    // One range comes back as a plain 206, and a 500 gets retried
+    assert_eq!(
+        fetch_multiple_ranges(&[(10, 19)], &config).unwrap(),
+        [(10, data[10..20].to_vec())]
+    );
+    server.set_faults(FaultConfig {
+        fail_request: Some(2),
+        ..Default::default()
+    });
+    assert_eq!(
+        fetch_multiple_ranges(&ranges, &config).unwrap(),
+        parts_of(&data, &ranges)
+    );
+    assert_eq!(server.request_count(), 4);
+
+    assert!(matches!(
+        fetch_multiple_ranges(&[(0, 99), (20_000, 20_100)], &config),
+        Err(DownloadError::RetriesExhausted { .. })
+    ));
+    assert!(matches!(
+        fetch_multiple_ranges(&[], &config),
+        Err(DownloadError::Args(_))
+    ));
+}
+
+fn parts_of(data: &[u8], ranges: &[(u64, u64)]) -> Vec<(u64, Vec<u8>)> {
+    ranges
+        .iter()
+        .map(|&(start, end)| (start, data[start as usize..=end as usize].to_vec()))
+        .collect()
+}
+
+#[test]
+fn server_error_gets_retried() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::new(data.clone());
+    // Second chunk's first attempt
+    server.set_faults(FaultConfig {
+        fail_request: Some(1),
+        ..Default::default()
+    });
+    let config = test_config(server.addr);
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.total_retries, 1);
+    assert_eq!(server.request_count(), 6);
+}
+
+#[test]
+fn global_attempt_limit_stops_every_chunk() {
+    let server = MockTcpServer::with_faults(
+        test_data(20_000),
+        FaultConfig {
+            always_status: Some(503),
+            ..Default::default()
+        },
+    );
+    let config = DownloadConfig {
+        max_retries: 10,
+        max_total_attempts: Some(4),
+        ..test_config(server.addr)
+    };
+
+    match download_full_data_with_progress(20_000, &config, None) {
+        Err(DownloadError::Network(msg)) => {
+            assert_eq!(
+                msg.to_string(),
+                "Global attempt limit reached, 4 requests made"
+            );
+        }
+        other => panic!("Expected the attempt limit, got {other:?}"),
+    }
+    assert_eq!(server.request_count(), 4);
+}
+
+#[test]
+fn pipelined_downloads_match() {
+    let data = test_data(50_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = DownloadConfig {
+        pipeline_depth: 4,
+        ..test_config(server.addr)
+    };
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    assert_eq!(stats.chunks_attempted, 13);
+    assert_eq!(stats.total_retries, 0);
+    assert_eq!(server.request_count(), 13);
+}
+
+#[test]
+fn pipelined_failures_get_asked_for_again() {
+    let data = test_data(50_000);
+    // The second request of the first burst, the two after it get thrown away with the
+    // connection and asked for again as well
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            fail_request: Some(1),
+            ..Default::default()
+        },
+    );
+    let config = DownloadConfig {
+        pipeline_depth: 4,
+        ..test_config(server.addr)
+    };
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.chunks_attempted, 13);
+    assert_eq!(stats.total_retries, 3);
+    assert_eq!(server.request_count(), 16);
+
+    // Cut off halfway through the third chunk
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            drop_at_offset: Some(10_000),
+            ..Default::default()
+        },
+    );
+    let config = DownloadConfig {
+        pipeline_depth: 4,
+        ..test_config(server.addr)
+    };
+    let path =
+        std::env::temp_dir().join(format!("glitchy-http-pipeline-{}.bin", server.addr.port()));
+    let result = download_to_file_with_progress(data.len() as u64, &path, &config, None);
+    let written = std::fs::read(&path);
+    let _ = std::fs::remove_file(&path);
+    result.unwrap();
+    assert_eq!(written.unwrap(), data);
+}
+
+#[test]
+fn keepalive_max_requests_opens_fresh_connections() {
+    let data = test_data(20 * 4096);
+    // Pipelined bursts get cut down to what the connection has left rather than past it
+    for pipeline_depth in [1, 4] {
+        let server = MockTcpServer::new(data.clone());
+        let config = DownloadConfig {
+            keepalive_max_requests: 5,
+            pipeline_depth,
+            ..test_config(server.addr)
+        };
+
+        let (downloaded, stats) =
+            download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+        assert_eq!(downloaded, data);
+        assert_eq!(stats.total_retries, 0, "pipeline_depth {pipeline_depth}");
+        assert_eq!(server.request_count(), 20);
+        assert_eq!(
+            server.connection_count(),
+            4,
+            "pipeline_depth {pipeline_depth}"
+        );
+    }
+}
+
+#[test]
+fn idle_keepalive_connections_get_replaced() {
+    let data = test_data(5 * 4096);
+    // A 500 leaves the connection open, then the retry waits longer than it's allowed to sit
+    // idle for
+    let faults = FaultConfig {
+        fail_request: Some(1),
+        ..Default::default()
+    };
+    for (keepalive_idle_timeout, connections) in
+        [(Duration::from_secs(30), 1), (Duration::from_millis(20), 2)]
+    {
+        let server = MockTcpServer::with_faults(data.clone(), faults.clone());
+        let config = DownloadConfig {
+            keepalive_idle_timeout,
+            retry_base_delay: Duration::from_millis(100),
+            retry_max_delay: Duration::from_millis(100),
+            retry_jitter: false,
+            ..test_config(server.addr)
+        };
+
+        let (downloaded, _) =
+            download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+        assert_eq!(downloaded, data);
+        assert_eq!(server.request_count(), 6);
+        assert_eq!(server.connection_count(), connections);
+    }
+}
+
+#[test]
+fn drop_partway_through_a_later_chunk() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            drop_at_offset: Some(13_000),
+            ..Default::default()
+        },
+    );
+    let config = test_config(server.addr);
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.total_retries, 1);
+    // The body coming up short is the response's fault, not the connection's
+    assert_eq!(stats.connection_errors, 0);
+}
+
+#[test]
+fn bandwidth_limit_slows_the_download_down() {
+    let data = test_data(40_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = DownloadConfig {
+        bandwidth_limit_bytes_per_sec: Some(100_000),
+        ..test_config(server.addr)
+    };
+
+    let started = Instant::now();
+    let (downloaded, _) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    let elapsed = started.elapsed();
+    assert_eq!(downloaded, data);
+    // 40k at 100k/s is 0.4s, give or take a bit of rounding in the sleeps
+    assert!(elapsed >= Duration::from_millis(390), "{elapsed:?}");
+}
+
+#[test]
+fn chunked_responses_get_decoded() {
+    let data = test_data(20_000);
+    // Pieces that don't line up with the 4096 byte ranges, over one keep-alive connection
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            chunked: Some(1000),
+            ..Default::default()
+        },
+    );
+    let config = test_config(server.addr);
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.total_retries, 0);
+    assert_eq!(server.request_count(), 5);
+}
+
+#[test]
+fn gzip_responses_get_decompressed() {
+    // Compresses really well, so the bytes over the wire are well under the file size
+    let data: Vec<u8> = b"glitchy ".iter().copied().cycle().take(20_000).collect();
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            gzip: true,
+            ..Default::default()
+        },
+    );
+    let config = DownloadConfig {
+        accept_encoding: true,
+        ..test_config(server.addr)
+    };
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.total_retries, 0);
+
+    // Without asking it never gets compressed in the first place
+    let (downloaded, _) =
+        download_full_data_with_progress(data.len() as u64, &test_config(server.addr), None)
+            .unwrap();
+    assert_eq!(downloaded, data);
+}
+
+#[test]
+fn wrong_range_is_retried() {
+    let data = test_data(20_000);
+    // Second chunk comes back as the first chunk's bytes, Content-Range and all
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            wrong_range_request: Some(1),
+            ..Default::default()
+        },
+    );
+
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &test_config(server.addr), None)
+            .unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.total_retries, 1);
+}
+
+#[test]
+fn chunk_manifest_catches_corrupt_chunks() {
+    let data = test_data(20_000);
+    // Third chunk comes back the right length but wrong
+    let faults = FaultConfig {
+        corrupt_request: Some(2),
+        ..Default::default()
+    };
+    let server = MockTcpServer::with_faults(data.clone(), faults.clone());
+
+    let manifest: String = data
+        .chunks(4096)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let start = i * 4096;
+            let end = start + chunk.len() - 1;
+            format!("{start}-{end} {}\n", calculate_sha256(chunk))
+        })
+        .collect();
+    let path = std::env::temp_dir().join(format!("glitchy-http-manifest-{}", server.addr.port()));
+    std::fs::write(&path, manifest).unwrap();
+    let config = DownloadConfig {
+        chunk_manifest: Some(path.clone()),
+        ..test_config(server.addr)
+    };
+
+    let result = download_full_data_with_progress(data.len() as u64, &config, None);
+    std::fs::remove_file(&path).unwrap();
+    let (downloaded, stats) = result.unwrap();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.total_retries, 1);
+
+    // Without it the length is all that gets checked so the bad chunk goes straight through
+    let server = MockTcpServer::with_faults(data.clone(), faults);
+    let (downloaded, _) =
+        download_full_data_with_progress(data.len() as u64, &test_config(server.addr), None)
+            .unwrap();
+    assert_ne!(downloaded, data);
+}
+
+#[test]
+fn cancelling_stops_before_the_next_chunk() {
+    let data = test_data(40_000);
+    let server = MockTcpServer::new(data.clone());
+    let token = Arc::new(AtomicBool::new(false));
+    let config = DownloadConfig {
+        cancel_token: Some(Arc::clone(&token)),
+        ..test_config(server.addr)
+    };
+    let path = std::env::temp_dir().join(format!("glitchy-http-cancel-{}.bin", server.addr.port()));
+
+    let download = {
+        let path = path.clone();
+        thread::spawn(move || {
+            let mut chunks = 0;
+            let mut cancel_after_three = |_: u64, _: u64| {
+                chunks += 1;
+                if chunks == 3 {
+                    token.store(true, Ordering::Relaxed);
+                }
+            };
+            download_to_file_with_progress(
+                data.len() as u64,
+                &path,
+                &config,
+                Some(&mut cancel_after_three),
+            )
+        })
+    };
+
+    match download.join().unwrap() {
+        Err(DownloadError::PartialDownload { written, cause, .. }) => {
+            assert!(matches!(*cause, DownloadError::Cancelled), "{cause:?}");
+            assert_eq!(written, 3 * 4096);
+        }
+        other => panic!("Expected a cancelled download, got {other:?}"),
+    }
+    // What made it is still there to resume from
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(written, &test_data(40_000)[..3 * 4096]);
+    assert_eq!(server.request_count(), 3);
+}
+
+#[test]
+fn plain_http_goes_through_the_proxy() {
+    let data = test_data(20_000);
+    // The mock server doesn't care what's in the request line so it can stand in for a proxy.
+    // The server itself doesn't resolve, so the download only works if nothing tries to
+    let proxy = MockTcpServer::new(data.clone());
+    let config = DownloadConfig {
+        server_addr: "files.example.invalid:80".into(),
+        proxy: Some(proxy.addr),
+        ..test_config(proxy.addr)
+    };
+
+    assert_eq!(discover_total_size(&config).unwrap(), data.len() as u64);
+    let (downloaded, _) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    assert_eq!(downloaded, data);
+}
+
+#[test]
+fn rate_limit_waits_for_retry_after() {
+    let data = test_data(10_000);
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            rate_limit_request: Some((0, "5")),
+            ..Default::default()
+        },
+    );
+    // Way longer than the 1ms backoff, and the cap keeps the test from taking 5 seconds
+    let config = DownloadConfig {
+        retry_after_max: Duration::from_millis(200),
+        ..test_config(server.addr)
+    };
+
+    let started = Instant::now();
+    let (downloaded, stats) =
+        download_full_data_with_progress(data.len() as u64, &config, None).unwrap();
+    let elapsed = started.elapsed();
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.total_retries, 1);
+    assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn downloads_into_a_mapping_and_resumes() {
+    use glitchy_http::client::download_to_mmap_with_progress;
+
+    let data = test_data(30_000);
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            always_status: Some(503),
+            ..Default::default()
+        },
+    );
+    let config = DownloadConfig {
+        max_retries: 1,
+        ..test_config(server.addr)
+    };
+    let path = std::env::temp_dir().join(format!("glitchy-http-mmap-{}.bin", server.addr.port()));
+    // An earlier run got the first chunk
+    std::fs::write(&path, &data[..4096]).unwrap();
+
+    // Every request fails so it gives up straight away, and the file goes back to what it was
+    match download_to_mmap_with_progress(data.len() as u64, &path, &config, None) {
+        Err(DownloadError::PartialDownload { written: 4096, .. }) => {}
+        other => panic!("Expected a partial download, got {other:?}"),
+    }
+    assert_eq!(std::fs::read(&path).unwrap(), &data[..4096]);
+
+    server.set_faults(FaultConfig::default());
+    let stats = download_to_mmap_with_progress(data.len() as u64, &path, &config, None).unwrap();
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(written, data);
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    assert_eq!(stats.chunks_attempted, 7);
+}
+
+#[test]
+fn append_fetches_only_the_rest() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = DownloadConfig {
+        append: true,
+        ..test_config(server.addr)
+    };
+    let path = std::env::temp_dir().join(format!("glitchy-http-append-{}.bin", server.addr.port()));
+    // The first part came from somewhere else and doesn't end on a chunk
+    std::fs::write(&path, &data[..9000]).unwrap();
+
+    let stats = download_to_file_with_progress(data.len() as u64, &path, &config, None).unwrap();
+    let written = std::fs::read(&path).unwrap();
+    assert_eq!(written, data);
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    assert_eq!(server.request_count(), 3);
+
+    // Already whole, so there's nothing to append and the file is left as it is
+    match download_to_file_with_progress(data.len() as u64, &path, &config, None) {
+        Err(DownloadError::Args(msg)) => assert!(msg.contains("nothing to append"), "{msg}"),
+        other => panic!("Expected an Args error, got {other:?}"),
+    }
+    let after = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(after, data);
+    assert_eq!(server.request_count(), 3);
+}
+
+#[test]
+fn test_connection_times_one_request() {
+    let server = MockTcpServer::new(test_data(10_000));
+    let rtt = test_connection(&test_config(server.addr)).unwrap();
+    assert!(rtt < Duration::from_secs(5), "{rtt:?}");
+    assert_eq!(server.request_count(), 1);
+
+    let broken = MockTcpServer::with_faults(
+        test_data(10_000),
+        FaultConfig {
+            always_status: Some(404),
+            ..Default::default()
+        },
+    );
+    match test_connection(&test_config(broken.addr)) {
+        Err(DownloadError::ServerError { status: 404, .. }) => {}
+        other => panic!("Expected a 404, got {other:?}"),
+    }
+}
+
+#[test]
+fn chunk_map_resumes_from_what_was_written() {
+    use glitchy_http::chunk_map::{chunk_map_path, ChunkMap};
+
+    let data = test_data(30_000);
+    let server = MockTcpServer::with_faults(
+        data.clone(),
+        FaultConfig {
+            always_status: Some(503),
+            ..Default::default()
+        },
+    );
+    let config = DownloadConfig {
+        max_retries: 1,
+        chunk_map: true,
+        ..test_config(server.addr)
+    };
+    let path =
+        std::env::temp_dir().join(format!("glitchy-http-chunkmap-{}.bin", server.addr.port()));
+    let map_path = chunk_map_path(&path);
+    // An earlier run got the first chunk down but the file is a chunk longer than that, the way
+    // it can be after a crash
+    let mut junk = data[..4096].to_vec();
+    junk.extend_from_slice(&[0xff; 4096]);
+    std::fs::write(&path, &junk).unwrap();
+    let mut map = ChunkMap::new(data.len() as u64);
+    map.mark_written(0, 4095);
+    map.save_to_file(&map_path).unwrap();
+
+    match download_to_file_with_progress(data.len() as u64, &path, &config, None) {
+        Err(DownloadError::PartialDownload { written: 4096, .. }) => {}
+        other => panic!("Expected a partial download, got {other:?}"),
+    }
+    assert_eq!(std::fs::read(&path).unwrap(), &data[..4096]);
+    assert_eq!(ChunkMap::load_from_file(&map_path).unwrap(), map);
+
+    server.set_faults(FaultConfig::default());
+    let stats = download_to_file_with_progress(data.len() as u64, &path, &config, None).unwrap();
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(written, data);
+    assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+    assert_eq!(stats.chunks_attempted, 7);
+    assert!(!map_path.exists());
+}
+
+// Each file is the first size bytes of what the mock serves, it doesn't look at the path
+fn multi_file_specs(addr: SocketAddr, data: &[u8], sizes: &[usize]) -> Vec<FileSpec> {
+    sizes
+        .iter()
+        .enumerate()
+        .map(|(i, &size)| FileSpec {
+            url_path: format!("/part{i}"),
+            total_size: size as u64,
+            expected_hash: Some(calculate_sha256(&data[..size])),
+            output_path: std::env::temp_dir()
+                .join(format!("glitchy-http-multi-{}-{i}.bin", addr.port())),
+        })
+        .collect()
+}
+
+#[test]
+fn multi_file_downloads_each_file() {
+    let data = test_data(20_000);
+    let server = MockTcpServer::new(data.clone());
+    let config = DownloadConfig {
+        quiet: true,
+        concurrency: 2,
+        ..test_config(server.addr)
+    };
+    let mut specs = multi_file_specs(server.addr, &data, &[20_000, 5000, 100]);
+    specs[2].expected_hash = Some(calculate_sha256(b"something else"));
+    let mut downloader = MultiFileDownloader::new(config);
+    for spec in specs.clone() {
+        downloader.add_file(spec);
+    }
+
+    for parallel in [false, true] {
+        let results = if parallel {
+            downloader.download_all_parallel()
+        } else {
+            downloader.download_all()
+        };
+        assert_eq!(results.len(), 3);
+        for (spec, result) in specs.iter().zip(&results).take(2) {
+            let written = std::fs::read(&spec.output_path).unwrap();
+            std::fs::remove_file(&spec.output_path).unwrap();
+            assert_eq!(written, data[..spec.total_size as usize]);
+            let stats = result.as_ref().unwrap();
+            assert_eq!(stats.hash, *spec.expected_hash.as_ref().unwrap());
+            assert!(stats.hash_verified);
+        }
+        std::fs::remove_file(&specs[2].output_path).unwrap();
+        assert!(
+            matches!(results[2], Err(DownloadError::HashMismatch { .. })),
+            "{:?}",
+            results[2]
+        );
+    }
+}
+
+#[test]
+fn multi_file_shares_the_attempt_budget() {
+    let data = test_data(8192);
+    let server = MockTcpServer::new(data.clone());
+    // Two chunks a file so the second file runs out halfway through
+    let config = DownloadConfig {
+        quiet: true,
+        max_total_attempts: Some(3),
+        ..test_config(server.addr)
+    };
+    let specs = multi_file_specs(server.addr, &data, &[8192, 8192]);
+    let mut downloader = MultiFileDownloader::new(config);
+    for spec in specs.clone() {
+        downloader.add_file(spec);
+    }
+
+    let results = downloader.download_all();
+    for spec in &specs {
+        std::fs::remove_file(&spec.output_path).unwrap();
+    }
+    assert!(results[0].is_ok(), "{:?}", results[0]);
+    match &results[1] {
+        Err(DownloadError::PartialDownload { written, cause, .. }) => {
+            assert_eq!(*written, 4096);
+            assert!(
+                cause.to_string().contains("Global attempt limit reached"),
+                "{cause}"
+            );
+        }
+        other => panic!("Expected the budget to run out, got {other:?}"),
+    }
+    assert_eq!(server.request_count(), 3);
+}
@@ -0,0 +1,176 @@
+// Transport::Quic against a small quinn server that answers HTTP/1.1 range requests on each
+// stream, only built with --features quic
+#![cfg(feature = "quic")]
+
+mod common;
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use glitchy_http::client::download_full_data;
+use glitchy_http::config::{DownloadConfig, Transport};
+use glitchy_http::sha::calculate_hash;
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+use common::test_data;
+
+#[derive(Default)]
+struct Counts {
+    connections: AtomicUsize,
+    streams: AtomicUsize,
+}
+
+// Starts the server on a thread of its own and hands back where it's listening
+fn quic_server(data: Vec<u8>) -> (SocketAddr, Arc<Counts>) {
+    let certs = CertificateDer::pem_slice_iter(include_bytes!("fixtures/localhost_cert.pem"))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_slice(include_bytes!("fixtures/localhost_key.pem")).unwrap();
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .unwrap();
+    tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let server_config =
+        quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls).unwrap()));
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let endpoint = {
+        let _context = runtime.enter();
+        quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap()
+    };
+    let addr = endpoint.local_addr().unwrap();
+    let counts = Arc::new(Counts::default());
+    let data = Arc::new(data);
+
+    let server_counts = Arc::clone(&counts);
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let Ok(connection) = incoming.await else {
+                    continue;
+                };
+                server_counts.connections.fetch_add(1, Ordering::SeqCst);
+                let counts = Arc::clone(&server_counts);
+                let data = Arc::clone(&data);
+                tokio::spawn(async move {
+                    while let Ok((send, recv)) = connection.accept_bi().await {
+                        counts.streams.fetch_add(1, Ordering::SeqCst);
+                        tokio::spawn(serve_stream(send, recv, Arc::clone(&data)));
+                    }
+                });
+            }
+        });
+    });
+    (addr, counts)
+}
+
+// Keeps answering requests on the stream until the client finishes its side or asks to close
+async fn serve_stream(
+    mut send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+    data: Arc<Vec<u8>>,
+) {
+    let mut pending = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let Some(end) = pending.windows(4).position(|w| w == b"\r\n\r\n") else {
+            match recv.read(&mut buf).await {
+                Ok(Some(read)) => pending.extend_from_slice(&buf[..read]),
+                _ => break,
+            }
+            continue;
+        };
+        let head = String::from_utf8_lossy(&pending[..end]).to_ascii_lowercase();
+        pending.drain(..end + 4);
+
+        let range = head
+            .lines()
+            .find_map(|line| line.strip_prefix("range: bytes="))
+            .and_then(|range| range.split_once('-'))
+            .map(|(start, end)| {
+                (
+                    start.parse::<usize>().unwrap(),
+                    end.parse::<usize>().unwrap(),
+                )
+            })
+            .unwrap();
+        let (start, end) = (range.0, range.1.min(data.len() - 1));
+        let close = head.contains("connection: close");
+        let response = format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{end}/{}\r\n\
+             Content-Length: {}\r\nConnection: {}\r\n\r\n",
+            data.len(),
+            end - start + 1,
+            if close { "close" } else { "keep-alive" }
+        );
+        if send.write_all(response.as_bytes()).await.is_err()
+            || send.write_all(&data[start..=end]).await.is_err()
+            || close
+        {
+            break;
+        }
+    }
+    let _ = send.finish();
+}
+
+fn quic_config(addr: SocketAddr) -> DownloadConfig {
+    DownloadConfig {
+        server_addr: addr.to_string(),
+        transport: Transport::Quic,
+        verify_tls: false,
+        chunk_size: 4096,
+        connect_timeout: Duration::from_secs(5),
+        read_timeout: Duration::from_secs(5),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn downloads_over_one_quic_connection() {
+    let data = test_data(50_000);
+    let chunks = data.len().div_ceil(4096);
+    for keep_alive in [true, false] {
+        let (addr, counts) = quic_server(data.clone());
+        let config = DownloadConfig {
+            keep_alive,
+            ..quic_config(addr)
+        };
+
+        let (downloaded, stats) = download_full_data(data.len() as u64, &config).unwrap();
+        assert_eq!(downloaded, data, "keep_alive {keep_alive}");
+        assert_eq!(stats.hash, calculate_hash(&data, config.hash_algorithm));
+        // The handshake only happens once, without keep-alive every chunk gets a stream of its
+        // own on that same connection
+        assert_eq!(counts.connections.load(Ordering::SeqCst), 1);
+        let streams = if keep_alive { 1 } else { chunks };
+        assert_eq!(counts.streams.load(Ordering::SeqCst), streams);
+    }
+}
+
+#[test]
+fn quic_refuses_a_proxy() {
+    let config = DownloadConfig {
+        proxy: Some("127.0.0.1:3128".parse().unwrap()),
+        ..quic_config("127.0.0.1:1".parse().unwrap())
+    };
+    assert!(matches!(
+        download_full_data(10, &config),
+        Err(glitchy_http::error::DownloadError::Args(_))
+    ));
+}